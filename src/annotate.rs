@@ -0,0 +1,589 @@
+//! Annotations drawn on top of a selection, and rasterizing them into the overlay or into the
+//! saved image.
+//!
+//! Shapes are kept in the global logical coordinate space and mapped onto the pixels of a
+//! [`Target`] with a [`View`], so the same annotation looks alike on the overlay of any output
+//! and in the saved image at any scale. Every shape is turned into a coverage mask first and
+//! blended once, so overlapping parts of a stroke don't get darker.
+
+use image::{ColorType, DynamicImage, Rgba};
+
+use crate::{
+    config::Color,
+    font::{self, GLYPH_HEIGHT, GLYPH_WIDTH, LINE_HEIGHT},
+    pixel_format::Rgba16Image,
+    redact::{RedactStyle, Redaction},
+    types::Rect,
+};
+
+/// A point in the global logical coordinate space.
+pub(crate) type Point = (f64, f64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Tool {
+    /// Drags a new selection.
+    Select,
+    Rectangle,
+    Arrow,
+    Freehand,
+    Highlighter,
+    /// Numbered step markers, counting up from 1.
+    Marker,
+    Text,
+    Redact(RedactStyle),
+}
+
+impl Tool {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Tool::Select => "Select",
+            Tool::Rectangle => "Rectangle",
+            Tool::Arrow => "Arrow",
+            Tool::Freehand => "Pen",
+            Tool::Highlighter => "Highlighter",
+            Tool::Marker => "Marker",
+            Tool::Text => "Text",
+            Tool::Redact(style) => style.name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Shape {
+    Rectangle {
+        from: Point,
+        to: Point,
+    },
+    Arrow {
+        from: Point,
+        to: Point,
+    },
+    Freehand(Vec<Point>),
+    Highlighter(Vec<Point>),
+    Marker {
+        center: Point,
+        number: u32,
+    },
+    /// A single line label with its top left corner at `position`.
+    Text {
+        position: Point,
+        text: String,
+    },
+    /// Hides the captured pixels under the rectangle, see [`crate::redact`].
+    Redact {
+        from: Point,
+        to: Point,
+        style: RedactStyle,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Annotation {
+    pub(crate) shape: Shape,
+    pub(crate) color: Color,
+    /// Stroke width in logical pixels, text uses it as the size of a font pixel.
+    pub(crate) width: f64,
+}
+
+/// The annotations of a selection with their undo history.
+#[derive(Debug, Default)]
+pub(crate) struct Annotations {
+    done: Vec<Annotation>,
+    undone: Vec<Annotation>,
+}
+
+impl Annotations {
+    pub(crate) fn push(&mut self, annotation: Annotation) {
+        self.done.push(annotation);
+        self.undone.clear();
+    }
+
+    /// Returns whether there was anything to undo.
+    pub(crate) fn undo(&mut self) -> bool {
+        let Some(annotation) = self.done.pop() else {
+            return false;
+        };
+        self.undone.push(annotation);
+        true
+    }
+
+    /// Returns whether there was anything to redo.
+    pub(crate) fn redo(&mut self) -> bool {
+        let Some(annotation) = self.undone.pop() else {
+            return false;
+        };
+        self.done.push(annotation);
+        true
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Annotation> {
+        self.done.iter()
+    }
+
+    /// The redactions among the annotations, they get applied to the capture instead of being
+    /// drawn on top of it.
+    pub(crate) fn redactions(&self) -> Vec<Redaction> {
+        self.done
+            .iter()
+            .filter_map(|annotation| match annotation.shape {
+                Shape::Redact { from, to, style } => Some(Redaction {
+                    rect: Rect::spanning(from, to),
+                    style,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of the next step marker.
+    pub(crate) fn next_marker(&self) -> u32 {
+        let markers = self
+            .done
+            .iter()
+            .filter(|annotation| matches!(annotation.shape, Shape::Marker { .. }))
+            .count();
+        markers as u32 + 1
+    }
+}
+
+/// Pixels annotations can be drawn onto.
+pub(crate) trait Target {
+    fn size(&self) -> (u32, u32);
+    /// Blends `color` over the pixel, of which `coverage` is covered by the shape.
+    fn blend(&mut self, x: u32, y: u32, color: Color, coverage: f32);
+}
+
+/// Maps the global logical coordinate space onto the pixels of a [`Target`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct View {
+    /// The point that ends up at the top left corner of the target.
+    pub(crate) origin: Point,
+    /// Target pixels per logical pixel.
+    pub(crate) scale: (f64, f64),
+}
+
+impl View {
+    fn map(&self, (x, y): Point) -> Point {
+        (
+            (x - self.origin.0) * self.scale.0,
+            (y - self.origin.1) * self.scale.1,
+        )
+    }
+
+    /// Maps a logical length, the average of both directions for the rare non uniform scales.
+    fn length(&self, length: f64) -> f64 {
+        length * (self.scale.0 + self.scale.1) / 2.0
+    }
+}
+
+/// Coverage of the target pixels within a rectangle, clipped to the target.
+struct Mask {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    coverage: Vec<f32>,
+}
+
+impl Mask {
+    /// A mask over the pixels touched by the target space rectangle `min` to `max`.
+    fn new(min: Point, max: Point, (width, height): (u32, u32)) -> Option<Mask> {
+        let clamp = |value: f64, limit: u32| value.clamp(0.0, limit as f64) as u32;
+        let (x, y) = (clamp(min.0.floor(), width), clamp(min.1.floor(), height));
+        let (right, bottom) = (clamp(max.0.ceil(), width), clamp(max.1.ceil(), height));
+        (right > x && bottom > y).then(|| Mask {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+            coverage: vec![0.0; ((right - x) * (bottom - y)) as usize],
+        })
+    }
+
+    /// Raises the coverage of the pixels within `min` to `max` to what `coverage` returns for
+    /// their center.
+    fn cover(&mut self, min: Point, max: Point, coverage: impl Fn(Point) -> f64) {
+        let clamp = |value: f64, start: u32, length: u32| {
+            (value.max(start as f64) as u32).clamp(start, start + length) - start
+        };
+        let (x_start, x_end) = (
+            clamp(min.0.floor(), self.x, self.width),
+            clamp(max.0.ceil(), self.x, self.width),
+        );
+        let (y_start, y_end) = (
+            clamp(min.1.floor(), self.y, self.height),
+            clamp(max.1.ceil(), self.y, self.height),
+        );
+        for y in y_start..y_end {
+            for x in x_start..x_end {
+                let center = ((self.x + x) as f64 + 0.5, (self.y + y) as f64 + 0.5);
+                let coverage = coverage(center).clamp(0.0, 1.0) as f32;
+                let pixel = &mut self.coverage[(y * self.width + x) as usize];
+                *pixel = pixel.max(coverage);
+            }
+        }
+    }
+
+    fn blend_into(self, target: &mut dyn Target, color: Color) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let coverage = self.coverage[(y * self.width + x) as usize];
+                if coverage > 0.0 {
+                    target.blend(self.x + x, self.y + y, color, coverage);
+                }
+            }
+        }
+    }
+}
+
+fn distance_to_segment(p: Point, a: Point, b: Point) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length).clamp(0.0, 1.0)
+    };
+    let (x, y) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - x).powi(2) + (p.1 - y).powi(2)).sqrt()
+}
+
+/// Bounds of `points` grown by `margin` on every side.
+fn bounds(points: &[Point], margin: f64) -> (Point, Point) {
+    let mut min = (f64::INFINITY, f64::INFINITY);
+    let mut max = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for &(x, y) in points {
+        min = (min.0.min(x), min.1.min(y));
+        max = (max.0.max(x), max.1.max(y));
+    }
+    (
+        (min.0 - margin, min.1 - margin),
+        (max.0 + margin, max.1 + margin),
+    )
+}
+
+/// A round capped line through `points`, in target pixels.
+fn stroke(mask: &mut Mask, points: &[Point], half_width: f64) {
+    let segments = points.windows(2).map(|pair| (pair[0], pair[1]));
+    // A single point still leaves a dot
+    let segments = segments.chain((points.len() == 1).then(|| (points[0], points[0])));
+    for (a, b) in segments {
+        let (min, max) = bounds(&[a, b], half_width + 1.0);
+        mask.cover(min, max, |p| {
+            half_width + 0.5 - distance_to_segment(p, a, b)
+        });
+    }
+}
+
+/// A filled convex polygon, in target pixels.
+fn fill_convex(mask: &mut Mask, points: &[Point]) {
+    let area = points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum::<f64>();
+    let orientation = area.signum();
+    let (min, max) = bounds(points, 1.0);
+    mask.cover(min, max, |p| {
+        // Distance to the closest edge, positive inside
+        let inside = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| {
+                let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+                let length = (dx * dx + dy * dy).sqrt().max(f64::EPSILON);
+                orientation * (dx * (p.1 - a.1) - dy * (p.0 - a.0)) / length
+            })
+            .fold(f64::INFINITY, f64::min);
+        inside + 0.5
+    });
+}
+
+fn fill_circle(mask: &mut Mask, center: Point, radius: f64) {
+    let (min, max) = bounds(&[center], radius + 1.0);
+    mask.cover(min, max, |p| {
+        radius + 0.5 - ((p.0 - center.0).powi(2) + (p.1 - center.1).powi(2)).sqrt()
+    });
+}
+
+/// Covers the axis aligned rectangle `min` to `max` by the area of every pixel inside of it.
+fn fill_box(mask: &mut Mask, min: Point, max: Point) {
+    let overlap = |center: f64, start: f64, end: f64| {
+        ((center + 0.5).min(end) - (center - 0.5).max(start)).max(0.0)
+    };
+    mask.cover(min, max, |p| {
+        overlap(p.0, min.0, max.0) * overlap(p.1, min.1, max.1)
+    });
+}
+
+/// Covers the glyphs of `text` with their top left corner at `position`, `pixel` being the size
+/// of a font pixel, all in target pixels. Lines break at `\n`.
+fn fill_text(mask: &mut Mask, position: Point, text: &str, pixel: f64) {
+    for (row, line) in text.split('\n').enumerate() {
+        let top = position.1 + (row as u32 * LINE_HEIGHT) as f64 * pixel;
+        fill_line(mask, (position.0, top), line, pixel);
+    }
+}
+
+fn fill_line(mask: &mut Mask, position: Point, text: &str, pixel: f64) {
+    for (index, c) in text.chars().enumerate() {
+        let left = position.0 + (index as u32 * (GLYPH_WIDTH + 1)) as f64 * pixel;
+        for y in 0..GLYPH_HEIGHT {
+            for x in 0..GLYPH_WIDTH {
+                if font::is_set(c, x, y) {
+                    let min = (left + x as f64 * pixel, position.1 + y as f64 * pixel);
+                    fill_box(mask, min, (min.0 + pixel, min.1 + pixel));
+                }
+            }
+        }
+    }
+}
+
+/// Size of `text` in logical pixels, for a font pixel of `pixel` logical pixels.
+pub(crate) fn text_size(text: &str, pixel: f64) -> (f64, f64) {
+    let (width, height) = font::text_size(text);
+    (width as f64 * pixel, height as f64 * pixel)
+}
+
+/// Black or white, whichever reads better on top of `color`.
+pub(crate) fn contrasting(color: Color) -> Color {
+    let [r, g, b, _] = color.0;
+    let luma = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
+    if luma > 150.0 {
+        Color([0, 0, 0, 255])
+    } else {
+        Color([255, 255, 255, 255])
+    }
+}
+
+/// Size of the checkered blocks standing in for pixelation, in logical pixels.
+const PREVIEW_BLOCK: i32 = 10;
+
+/// Radius of a step marker in logical pixels.
+fn marker_radius(width: f64) -> f64 {
+    width * 2.0 + 8.0
+}
+
+/// Draws `text` in logical coordinates, `pixel` being the size of a font pixel.
+pub(crate) fn draw_text(
+    target: &mut dyn Target,
+    view: View,
+    position: Point,
+    text: &str,
+    pixel: f64,
+    color: Color,
+) {
+    let (width, height) = text_size(text, pixel);
+    let (min, max) = (
+        view.map(position),
+        view.map((position.0 + width, position.1 + height)),
+    );
+    let Some(mut mask) = Mask::new(min, max, target.size()) else {
+        return;
+    };
+    fill_text(&mut mask, min, text, view.length(pixel));
+    mask.blend_into(target, color);
+}
+
+/// Fills the logical rectangle `min` to `max`.
+pub(crate) fn draw_box(target: &mut dyn Target, view: View, min: Point, max: Point, color: Color) {
+    let (min, max) = (view.map(min), view.map(max));
+    let Some(mut mask) = Mask::new(min, max, target.size()) else {
+        return;
+    };
+    fill_box(&mut mask, min, max);
+    mask.blend_into(target, color);
+}
+
+/// Draws a single annotation.
+pub(crate) fn draw(target: &mut dyn Target, view: View, annotation: &Annotation) {
+    let half_width = view.length(annotation.width) / 2.0;
+    let size = target.size();
+    let new_mask = |points: &[Point], margin: f64| {
+        let points = points
+            .iter()
+            .map(|&point| view.map(point))
+            .collect::<Vec<_>>();
+        let (min, max) = bounds(&points, margin + 1.0);
+        Mask::new(min, max, size).map(|mask| (mask, points))
+    };
+
+    let mut color = annotation.color;
+    let mask = match &annotation.shape {
+        Shape::Rectangle { from, to } => {
+            let corners = [*from, (to.0, from.1), *to, (from.0, to.1), *from];
+            new_mask(&corners, half_width).map(|(mut mask, corners)| {
+                stroke(&mut mask, &corners, half_width);
+                mask
+            })
+        }
+        Shape::Arrow { from, to } => {
+            let head = view.length(annotation.width * 3.0 + 8.0);
+            new_mask(&[*from, *to], head).map(|(mut mask, points)| {
+                let [from, to] = [points[0], points[1]];
+                let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+                let length = (dx * dx + dy * dy).sqrt().max(f64::EPSILON);
+                let (ux, uy) = (dx / length, dy / length);
+                // Stop the line inside of the head so its round cap doesn't poke out of the tip
+                let head = head.min(length);
+                let base = (to.0 - ux * head, to.1 - uy * head);
+                let neck = (to.0 - ux * head * 0.5, to.1 - uy * head * 0.5);
+                stroke(&mut mask, &[from, neck], half_width);
+                let spread = (-uy * head * 0.5, ux * head * 0.5);
+                fill_convex(
+                    &mut mask,
+                    &[
+                        to,
+                        (base.0 + spread.0, base.1 + spread.1),
+                        (base.0 - spread.0, base.1 - spread.1),
+                    ],
+                );
+                mask
+            })
+        }
+        Shape::Freehand(points) => new_mask(points, half_width).map(|(mut mask, points)| {
+            stroke(&mut mask, &points, half_width);
+            mask
+        }),
+        Shape::Highlighter(points) => {
+            // Wide and see-through like a marker pen
+            let half_width = half_width * 4.0;
+            color.0[3] = (color.0[3] as u32 * 2 / 5) as u8;
+            new_mask(points, half_width).map(|(mut mask, points)| {
+                stroke(&mut mask, &points, half_width);
+                mask
+            })
+        }
+        Shape::Marker { center, number } => {
+            let radius = marker_radius(annotation.width);
+            draw_marker(target, view, *center, radius, *number, color);
+            None
+        }
+        Shape::Text { position, text } => {
+            draw_text(target, view, *position, text, annotation.width, color);
+            None
+        }
+        Shape::Redact { from, to, style } => {
+            draw_redaction_preview(target, view, Rect::spanning(*from, *to), *style);
+            None
+        }
+    };
+    if let Some(mask) = mask {
+        mask.blend_into(target, color);
+    }
+}
+
+fn draw_marker(
+    target: &mut dyn Target,
+    view: View,
+    center: Point,
+    radius: f64,
+    number: u32,
+    color: Color,
+) {
+    let mapped = view.map(center);
+    let margin = view.length(radius) + 1.0;
+    let Some(mut mask) = Mask::new(
+        (mapped.0 - margin, mapped.1 - margin),
+        (mapped.0 + margin, mapped.1 + margin),
+        target.size(),
+    ) else {
+        return;
+    };
+    fill_circle(&mut mask, mapped, view.length(radius));
+    mask.blend_into(target, color);
+
+    let label = number.to_string();
+    let pixel = radius * 1.1 / GLYPH_HEIGHT as f64;
+    let (width, height) = text_size(&label, pixel);
+    let position = (center.0 - width / 2.0, center.1 - height / 2.0);
+    draw_text(target, view, position, &label, pixel, contrasting(color));
+}
+
+/// Stands in for a redaction on the overlay, which has no access to the pixels below it.
+fn draw_redaction_preview(target: &mut dyn Target, view: View, rect: Rect, style: RedactStyle) {
+    let (min, max) = (
+        (rect.x as f64, rect.y as f64),
+        ((rect.x + rect.width) as f64, (rect.y + rect.height) as f64),
+    );
+    match style {
+        RedactStyle::Black => draw_box(target, view, min, max, Color([0, 0, 0, 255])),
+        RedactStyle::Blur => draw_box(target, view, min, max, Color([128, 128, 128, 230])),
+        RedactStyle::Pixelate => {
+            let block = PREVIEW_BLOCK as usize;
+            for (row, y) in (rect.y..rect.y + rect.height).step_by(block).enumerate() {
+                for (column, x) in (rect.x..rect.x + rect.width).step_by(block).enumerate() {
+                    let gray = if (row + column) % 2 == 0 { 96 } else { 160 };
+                    let cell_max = (
+                        (x + PREVIEW_BLOCK).min(rect.x + rect.width) as f64,
+                        (y + PREVIEW_BLOCK).min(rect.y + rect.height) as f64,
+                    );
+                    let color = Color([gray, gray, gray, 230]);
+                    draw_box(target, view, (x as f64, y as f64), cell_max, color);
+                }
+            }
+        }
+    }
+}
+
+/// Draws every annotation, in order.
+pub(crate) fn draw_all<'a>(
+    target: &mut dyn Target,
+    view: View,
+    annotations: impl IntoIterator<Item = &'a Annotation>,
+) {
+    for annotation in annotations {
+        draw(target, view, annotation);
+    }
+}
+
+impl Target for Rgba16Image {
+    fn size(&self) -> (u32, u32) {
+        self.dimensions()
+    }
+
+    fn blend(&mut self, x: u32, y: u32, color: Color, coverage: f32) {
+        let [r, g, b, a] = color.0;
+        let alpha = a as f32 / 255.0 * coverage;
+        let Rgba(pixel) = self.get_pixel_mut(x, y);
+        let below = pixel[3] as f32 / 65535.0;
+        let out = alpha + below * (1.0 - alpha);
+        if out <= 0.0 {
+            return;
+        }
+        for (channel, value) in pixel.iter_mut().zip([r, g, b]) {
+            let value = value as f32 * 257.0 * alpha + *channel as f32 * below * (1.0 - alpha);
+            *channel = (value / out).round() as u16;
+        }
+        pixel[3] = (out * 65535.0).round() as u16;
+    }
+}
+
+/// Burns `annotations` into a capture of the logical rectangle starting at `origin` that is
+/// `scale` pixels per logical pixel, keeping the color type of the image.
+pub(crate) fn rasterize(
+    img: DynamicImage,
+    origin: Point,
+    scale: (f64, f64),
+    annotations: &Annotations,
+) -> DynamicImage {
+    // Redactions already got applied to the capture
+    let drawn = annotations
+        .iter()
+        .filter(|annotation| !matches!(annotation.shape, Shape::Redact { .. }))
+        .collect::<Vec<_>>();
+    if drawn.is_empty() {
+        return img;
+    }
+    let color = img.color();
+    let mut canvas = img.into_rgba16();
+    draw_all(&mut canvas, View { origin, scale }, drawn);
+
+    let canvas = DynamicImage::ImageRgba16(canvas);
+    match color {
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(canvas.into_rgb8()),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(canvas.into_rgba8()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(canvas.into_rgb16()),
+        _ => canvas,
+    }
+}
@@ -0,0 +1,247 @@
+//! Software drawing into premultiplied `Abgr8888` buffers.
+//!
+//! A [`Canvas`] borrows its pixels, which are either the mapped shm memory a surface shows or
+//! plain memory. The fills, borders and blits here work in buffer pixels. Lines, text and the
+//! other antialiased shapes come from [`crate::annotate`], which blends them on in logical
+//! coordinates through the [`Target`] implementation.
+
+use std::array;
+
+use image::RgbaImage;
+
+use crate::{annotate::Target, config::Color, types::Rect};
+
+/// Premultiplied `Abgr8888` pixels row after row.
+pub(crate) struct Canvas<'a> {
+    pixels: &'a mut [u32],
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Canvas<'a> {
+    pub(crate) fn new(pixels: &'a mut [u32], width: u32, height: u32) -> Canvas<'a> {
+        assert_eq!(
+            pixels.len(),
+            (width * height) as usize,
+            "buffer doesn't match its size"
+        );
+        Canvas {
+            pixels,
+            width,
+            height,
+        }
+    }
+
+    /// Draws into mapped shm memory, which has to start 4 byte aligned.
+    pub(crate) fn from_bytes(bytes: &'a mut [u8], width: u32, height: u32) -> Canvas<'a> {
+        Canvas::new(bytemuck::cast_slice_mut(bytes), width, height)
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width as i32,
+            height: self.height as i32,
+        }
+    }
+
+    /// Calls `paint` with every pixel of `rect` that lies on the canvas.
+    fn each_pixel(&mut self, rect: Rect, mut paint: impl FnMut((i32, i32), &mut u32)) {
+        let Some(rect) = rect.intersection(&self.bounds()) else {
+            return;
+        };
+        for y in rect.y..rect.y + rect.height {
+            let row = &mut self.pixels[(y as u32 * self.width) as usize..][..self.width as usize];
+            for x in rect.x..rect.x + rect.width {
+                paint((x, y), &mut row[x as usize]);
+            }
+        }
+    }
+
+    /// Replaces every pixel with `color`.
+    pub(crate) fn fill(&mut self, color: Color) {
+        self.pixels.fill(color.premultiplied(u8::MAX));
+    }
+
+    /// Replaces the pixels of `rect` with `color`, leaving them see-through for a transparent
+    /// one.
+    pub(crate) fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let pixel = color.premultiplied(u8::MAX);
+        self.each_pixel(rect, |_, below| *below = pixel);
+    }
+
+    /// Replaces a border `width` pixels wide along the inside of `rect` with `color`.
+    pub(crate) fn outline(&mut self, rect: Rect, width: i32, color: Color) {
+        let pixel = color.premultiplied(u8::MAX);
+        self.each_pixel(rect, |(x, y), below| {
+            let on_border = x - rect.x < width
+                || rect.x + rect.width - x <= width
+                || y - rect.y < width
+                || rect.y + rect.height - y <= width;
+            if on_border {
+                *below = pixel;
+            }
+        });
+    }
+
+    /// Copies `image` with its top left corner at `(left, top)`. Its alpha is ignored, captures are
+    /// opaque whatever their format says.
+    pub(crate) fn blit(&mut self, image: &RgbaImage, (left, top): (i32, i32)) {
+        let rect = Rect {
+            x: left,
+            y: top,
+            width: image.width() as i32,
+            height: image.height() as i32,
+        };
+        self.each_pixel(rect, |(x, y), below| {
+            let [r, g, b, _] = image.get_pixel((x - left) as u32, (y - top) as u32).0;
+            *below = u32::from_le_bytes([r, g, b, u8::MAX]);
+        });
+    }
+}
+
+/// `color` blended over the premultiplied pixel `below`, `coverage` of which it covers.
+fn over(below: u32, color: Color, coverage: f32) -> u32 {
+    let source = color
+        .premultiplied((coverage * 255.0).round() as u8)
+        .to_le_bytes();
+    // The alpha actually drawn, so the channels left of `below` add up with it to at most 255
+    let alpha = source[3] as f32 / 255.0;
+    let below = below.to_le_bytes();
+    u32::from_le_bytes(array::from_fn(|channel| {
+        let kept = below[channel] as f32 * (1.0 - alpha);
+        source[channel].saturating_add(kept.round() as u8)
+    }))
+}
+
+impl Target for Canvas<'_> {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn blend(&mut self, x: u32, y: u32, color: Color, coverage: f32) {
+        let pixel = &mut self.pixels[(y * self.width + x) as usize];
+        *pixel = over(*pixel, color, coverage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn pixel(bytes: [u8; 4]) -> u32 {
+        u32::from_le_bytes(bytes)
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    const RED: Color = Color([255, 0, 0, 255]);
+
+    #[test]
+    fn fill_premultiplies() {
+        let mut pixels = vec![0; 6];
+        Canvas::new(&mut pixels, 3, 2).fill(Color([255, 100, 0, 128]));
+        assert_eq!(pixels, [pixel([128, 50, 0, 128]); 6]);
+    }
+
+    #[test]
+    fn fill_rect_clips_at_the_edges() {
+        let mut pixels = vec![0; 12];
+        let mut canvas = Canvas::new(&mut pixels, 4, 3);
+        canvas.fill_rect(rect(-1, 2, 3, 5), RED);
+        canvas.fill_rect(rect(3, -4, 10, 5), RED);
+        canvas.fill_rect(rect(4, 0, 2, 2), RED);
+        let red = pixel([255, 0, 0, 255]);
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            0, 0, 0, red,
+            0, 0, 0, 0,
+            red, red, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn outline_is_width_pixels_wide_inside() {
+        let mut pixels = vec![0; 36];
+        Canvas::new(&mut pixels, 6, 6).outline(rect(0, 0, 6, 6), 2, RED);
+        let inside = |index: usize| (2..4).contains(&(index % 6)) && (2..4).contains(&(index / 6));
+        for (index, &color) in pixels.iter().enumerate() {
+            let expected = if inside(index) {
+                0
+            } else {
+                pixel([255, 0, 0, 255])
+            };
+            assert_eq!(color, expected, "pixel {index}");
+        }
+
+        // Only the part on the canvas gets drawn
+        let mut pixels = vec![0; 9];
+        Canvas::new(&mut pixels, 3, 3).outline(rect(-2, 1, 4, 4), 1, RED);
+        let red = pixel([255, 0, 0, 255]);
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            0, 0, 0,
+            red, red, 0,
+            0, red, 0,
+        ]);
+    }
+
+    #[test]
+    fn blit_with_negative_offsets() {
+        let image = RgbaImage::from_fn(3, 3, |x, y| Rgba([x as u8, y as u8, 7, 0]));
+        let mut pixels = vec![1; 9];
+        Canvas::new(&mut pixels, 3, 3).blit(&image, (-1, -2));
+        // The alpha of the image is ignored
+        let copied = |x, y| pixel([x, y, 7, 255]);
+        #[rustfmt::skip]
+        assert_eq!(pixels, [
+            copied(1, 2), copied(2, 2), 1,
+            1, 1, 1,
+            1, 1, 1,
+        ]);
+    }
+
+    #[test]
+    fn over_blends_premultiplied() {
+        let white = pixel([255, 255, 255, 255]);
+        // Fully covered by an opaque color, nothing of below is left
+        assert_eq!(over(white, RED, 1.0), pixel([255, 0, 0, 255]));
+        // Nothing covered or a transparent color keeps below
+        assert_eq!(over(white, RED, 0.0), white);
+        assert_eq!(over(white, Color([255, 0, 0, 0]), 1.0), white);
+
+        // Half covered at full alpha, the source is 128 and 127 of below is kept
+        assert_eq!(over(white, RED, 0.5), pixel([255, 127, 127, 255]));
+        assert_eq!(over(0, RED, 0.5), pixel([128, 0, 0, 128]));
+        // Half transparent over half transparent
+        let below = pixel([0, 0, 128, 128]);
+        assert_eq!(
+            over(below, Color([255, 0, 0, 128]), 1.0),
+            pixel([128, 0, 64, 192])
+        );
+    }
+
+    #[test]
+    fn over_stays_premultiplied() {
+        let belows = [0, pixel([255, 255, 255, 255]), pixel([10, 200, 30, 200])];
+        for below in belows {
+            for coverage in [0.1, 0.25, 0.5, 0.7, 0.99] {
+                for alpha in [1, 100, 128, 254, 255] {
+                    let [r, g, b, a] =
+                        over(below, Color([255, 255, 255, alpha]), coverage).to_le_bytes();
+                    assert!(r.max(g).max(b) <= a, "{below:x} {coverage} {alpha}");
+                }
+            }
+        }
+    }
+}
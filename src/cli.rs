@@ -0,0 +1,496 @@
+use std::{
+    convert::Infallible,
+    fmt::{self, Display},
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::{imageops::FilterType, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    daemon::{CaptureMode, Region},
+    image_format,
+    redact::RedactStyle,
+    types::Rect,
+    SaveOptions,
+};
+
+#[derive(Debug, Parser)]
+#[command(name = "screen_capturer", version, about)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+    #[command(flatten)]
+    pub(crate) log: LogArgs,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct LogArgs {
+    /// Only log errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub(crate) quiet: bool,
+    /// Log more, `-vv` also traces the Wayland events we receive.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub(crate) verbose: u8,
+    /// Write the log to a file instead of stderr.
+    #[arg(long, global = true)]
+    pub(crate) log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Take a screenshot of every output (the default).
+    Full(FullArgs),
+    /// Select a region on the overlay, annotate it and save it.
+    Region(RegionArgs),
+    /// Click a pixel on the overlay and print its color.
+    Pick(PickArgs),
+    /// Measure boxes and distances on the overlay and print them.
+    Measure(MeasureArgs),
+    /// Stream frames of one output to stdout or a named pipe.
+    Record(RecordArgs),
+    /// Keep the Wayland connection open and serve captures over a unix socket.
+    ///
+    /// Clients get 5 seconds to send their request. SIGINT and SIGTERM remove the socket before
+    /// exiting.
+    Daemon(DaemonArgs),
+    /// Ask a running daemon for a capture.
+    Client(ClientArgs),
+    /// Offers stdin on the clipboard until it gets replaced, used to copy captures and colors.
+    #[command(hide = true)]
+    ServeClipboard(ServeClipboardArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub(crate) struct FullArgs {
+    /// Only keep the output the cursor is on.
+    #[arg(long)]
+    pub(crate) single_monitor: bool,
+    #[command(flatten)]
+    pub(crate) save: SaveArgs,
+    /// Show the capture in a window to save, copy, annotate, retake or discard it.
+    #[arg(long)]
+    pub(crate) preview: bool,
+}
+
+/// How the captured image gets saved, shared by every command saving one.
+#[derive(Debug, Default, Args)]
+pub(crate) struct SaveArgs {
+    /// File to write, or `-` to write the encoded image to stdout.
+    #[arg(short, long)]
+    pub(crate) output: Option<OutputTarget>,
+    /// Image format, guessed from the output extension when omitted.
+    #[arg(long, value_enum)]
+    pub(crate) format: Option<EncodingFormat>,
+    /// Bits per channel of the saved image.
+    #[arg(long, value_enum, default_value_t = Depth::Auto)]
+    pub(crate) depth: Depth,
+    /// `physical`, `logical`, or a multiple of the logical size such as `2x`.
+    #[arg(long, default_value = "physical")]
+    pub(crate) scale: SaveScale,
+    /// Resampling filter used when scaling.
+    #[arg(long, value_enum, default_value_t = ResizeFilter::Lanczos3)]
+    pub(crate) filter: ResizeFilter,
+    /// `x,y,width,height` in global logical coordinates to hide, can be repeated.
+    #[arg(long, value_name = "X,Y,W,H")]
+    pub(crate) redact: Vec<Region>,
+    /// How the `--redact` rectangles get hidden.
+    #[arg(long, value_enum, default_value_t = RedactStyle::Pixelate)]
+    pub(crate) redact_style: RedactStyle,
+}
+
+impl SaveArgs {
+    /// Exits when the encoded image would end up on a terminal.
+    pub(crate) fn refuse_terminal(&self) {
+        if self.output == Some(OutputTarget::Stdout) && io::stdout().is_terminal() {
+            log::error!(
+                "Refusing to write image data to a terminal, redirect stdout or use -o <FILE>"
+            );
+            process::exit(1);
+        }
+    }
+
+    /// How the image gets encoded, exits when it would end up on a terminal.
+    pub(crate) fn options(&self) -> SaveOptions {
+        self.refuse_terminal();
+        let format = image_format(self.format, self.output.as_ref());
+        SaveOptions {
+            format,
+            high_depth: self.depth.keeps_high_depth(format),
+            scale: self.scale,
+            filter: self.filter,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct RegionArgs {
+    #[command(flatten)]
+    pub(crate) save: SaveArgs,
+    /// Show the capture in a window to save, copy, annotate, retake or discard it.
+    #[arg(long)]
+    pub(crate) preview: bool,
+    /// Capture the region selected last time without showing the overlay. It is shown again,
+    /// starting out with that region, when the outputs changed since.
+    #[arg(long)]
+    pub(crate) last_region: bool,
+    /// Capture `x,y widthxheight` in global logical coordinates without showing the overlay, the
+    /// format slurp prints. `-` reads it from stdin.
+    #[arg(short, long, value_name = "GEOMETRY", conflicts_with = "last_region")]
+    pub(crate) geometry: Option<Geometry>,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct PickArgs {
+    /// How the color gets printed.
+    #[arg(short, long, value_enum, default_value_t = ColorFormat::Hex)]
+    pub(crate) format: ColorFormat,
+    /// Print this instead, with `{r}` `{g}` `{b}` (0-255), `{hex}`, `{h}` (degrees), `{s}` and
+    /// `{l}` (percent) replaced by the color.
+    #[arg(short, long, conflicts_with = "format")]
+    pub(crate) template: Option<String>,
+    /// Also put the printed color on the clipboard.
+    #[arg(short, long)]
+    pub(crate) copy: bool,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct ServeClipboardArgs {
+    /// Mime type of the data, text also gets offered under the names X11 clients ask for.
+    #[arg(long, default_value = "text/plain;charset=utf-8")]
+    pub(crate) mime: String,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct MeasureArgs {
+    /// Don't snap the pointer to edges in the capture, `s` toggles it on the overlay.
+    #[arg(long)]
+    pub(crate) no_snap: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ColorFormat {
+    /// `#rrggbb`
+    Hex,
+    /// `rgb(r, g, b)`
+    Rgb,
+    /// `hsl(h, s%, l%)`
+    Hsl,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct RecordArgs {
+    /// Container written to the destination.
+    #[arg(long, value_enum, default_value_t = StreamFormat::Y4m)]
+    pub(crate) format: StreamFormat,
+    /// Target framerate, frames are duplicated or dropped to keep it constant.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) fps: u32,
+    /// Index of the output to record.
+    #[arg(long, default_value_t = 0)]
+    pub(crate) screen: usize,
+    /// Stop after writing this many frames.
+    #[arg(long)]
+    pub(crate) frames: Option<u64>,
+    /// `-` for stdout, or a path such as a FIFO created with `mkfifo`.
+    #[arg(short, long, default_value = "-")]
+    pub(crate) output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct DaemonArgs {
+    /// Defaults to `$XDG_RUNTIME_DIR/screen_capturer.sock`.
+    #[arg(long)]
+    pub(crate) socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct ClientArgs {
+    /// Defaults to `$XDG_RUNTIME_DIR/screen_capturer.sock`.
+    #[arg(long)]
+    pub(crate) socket: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = CaptureMode::Full)]
+    pub(crate) mode: CaptureMode,
+    /// Index of the output captured in `screen` mode.
+    #[arg(long, default_value_t = 0)]
+    pub(crate) screen: usize,
+    /// `x,y,width,height` to crop to in physical pixels of the output in `screen` mode, or to
+    /// capture in global logical coordinates in `area` mode.
+    #[arg(long)]
+    pub(crate) region: Option<Region>,
+    #[command(flatten)]
+    pub(crate) save: SaveArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StreamFormat {
+    /// YUV4MPEG2, 4:4:4 BT.601 limited range.
+    Y4m,
+    /// Raw RGBA frames after a single line JSON header.
+    Rawvideo,
+}
+
+/// The rectangle given to `region -g`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Geometry {
+    Rect(Rect),
+    /// Read it from stdin, as piped from slurp.
+    Stdin,
+}
+
+impl FromStr for Geometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "-" => Ok(Geometry::Stdin),
+            geometry => parse_geometry(geometry).map(Geometry::Rect),
+        }
+    }
+}
+
+/// Parses `x,y widthxheight`, surrounding whitespace such as the newline slurp ends with is
+/// ignored.
+pub(crate) fn parse_geometry(s: &str) -> Result<Rect, String> {
+    let invalid = || format!("expected x,y widthxheight, got {:?}", s.trim());
+    let (position, size) = s.trim().split_once(' ').ok_or_else(invalid)?;
+    let (x, y) = position.split_once(',').ok_or_else(invalid)?;
+    let (width, height) = size.trim_start().split_once('x').ok_or_else(invalid)?;
+    let [x, y, width, height] = [x, y, width, height].map(|value| value.trim().parse::<i32>());
+    let (Ok(x), Ok(y), Ok(width), Ok(height)) = (x, y, width, height) else {
+        return Err(invalid());
+    };
+    if width <= 0 || height <= 0 {
+        return Err("width and height have to be positive".to_string());
+    }
+    Ok(Rect {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Where an encoded image gets written to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub(crate) enum OutputTarget {
+    Path(PathBuf),
+    Stdout,
+}
+
+impl FromStr for OutputTarget {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "-" => OutputTarget::Stdout,
+            path => OutputTarget::Path(PathBuf::from(path)),
+        })
+    }
+}
+
+impl Display for OutputTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputTarget::Path(path) => path.display().fmt(f),
+            OutputTarget::Stdout => f.write_str("stdout"),
+        }
+    }
+}
+
+impl From<String> for OutputTarget {
+    fn from(target: String) -> Self {
+        let Ok(target) = target.parse();
+        target
+    }
+}
+
+impl From<OutputTarget> for String {
+    fn from(target: OutputTarget) -> Self {
+        match target {
+            OutputTarget::Path(path) => path.to_string_lossy().into_owned(),
+            OutputTarget::Stdout => "-".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EncodingFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Tiff,
+    Bmp,
+}
+
+impl EncodingFormat {
+    pub(crate) fn image_format(self) -> ImageFormat {
+        match self {
+            EncodingFormat::Png => ImageFormat::Png,
+            EncodingFormat::Jpeg => ImageFormat::Jpeg,
+            EncodingFormat::Webp => ImageFormat::WebP,
+            EncodingFormat::Tiff => ImageFormat::Tiff,
+            EncodingFormat::Bmp => ImageFormat::Bmp,
+        }
+    }
+}
+
+/// Bits per channel of the saved image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Depth {
+    /// 16 bits when the output has more than 8 and the format can store them (PNG, TIFF).
+    #[default]
+    Auto,
+    /// Always downconvert to 8 bits.
+    #[value(name = "8")]
+    #[serde(rename = "8")]
+    Eight,
+}
+
+impl Depth {
+    /// Whether captures deeper than 8 bits should be saved as 16 bit images in `format`.
+    pub(crate) fn keeps_high_depth(self, format: ImageFormat) -> bool {
+        self == Depth::Auto && matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+    }
+}
+
+/// Size of the saved image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum SaveScale {
+    /// One image pixel per captured pixel.
+    #[default]
+    Physical,
+    /// A multiple of the logical size, `logical` is 1.
+    Logical(f64),
+}
+
+impl FromStr for SaveScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "physical" => return Ok(SaveScale::Physical),
+            "logical" => return Ok(SaveScale::Logical(1.0)),
+            _ => {}
+        }
+        let factor = s.strip_suffix('x').unwrap_or(s);
+        match factor.parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(SaveScale::Logical(factor)),
+            _ => Err("expected physical, logical or a factor such as 2x".to_string()),
+        }
+    }
+}
+
+impl TryFrom<String> for SaveScale {
+    type Error = String;
+
+    fn try_from(scale: String) -> Result<Self, Self::Error> {
+        scale.parse()
+    }
+}
+
+impl From<SaveScale> for String {
+    fn from(scale: SaveScale) -> Self {
+        match scale {
+            SaveScale::Physical => "physical".to_string(),
+            SaveScale::Logical(factor) => format!("{factor}x"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub(crate) fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn parses_slurp_geometry() {
+        assert_eq!(parse_geometry("10,20 300x200"), Ok(rect(10, 20, 300, 200)));
+        // As read from stdin
+        assert_eq!(
+            parse_geometry("10,20 300x200\n"),
+            Ok(rect(10, 20, 300, 200))
+        );
+        assert_eq!(
+            parse_geometry("  10,20   300x200 \n"),
+            Ok(rect(10, 20, 300, 200))
+        );
+        // Outputs left of or above the origin
+        assert_eq!(
+            parse_geometry("-1920,-40 100x50"),
+            Ok(rect(-1920, -40, 100, 50))
+        );
+
+        assert_eq!("-".parse(), Ok(Geometry::Stdin));
+        assert_eq!("1,2 3x4".parse(), Ok(Geometry::Rect(rect(1, 2, 3, 4))));
+    }
+
+    #[test]
+    fn rejects_invalid_geometry() {
+        for invalid in [
+            "10,20 0x200",
+            "10,20 300x0",
+            "10,20 -300x200",
+            "10,20 300x-200",
+        ] {
+            assert_eq!(
+                parse_geometry(invalid),
+                Err("width and height have to be positive".to_string()),
+                "{invalid:?}"
+            );
+        }
+        for junk in [
+            "",
+            "\n",
+            "10,20",
+            "300x200",
+            "10 20 300x200",
+            "10,20 300,200",
+            "a,b 300x200",
+            "10,20 300x200 extra",
+            "10,20,300,200",
+            "99999999999,0 1x1",
+        ] {
+            assert!(parse_geometry(junk).is_err(), "{junk:?}");
+        }
+    }
+}
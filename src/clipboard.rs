@@ -0,0 +1,103 @@
+//! Puts text and images on the clipboard with `wlr-data-control`.
+//!
+//! Wayland clipboards hold no data: the client that set the selection hands it out on every
+//! paste, until another client takes the selection over. [`copy`] therefore leaves a copy of this
+//! program running in the background, started with the hidden `serve-clipboard` command.
+
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::process::CommandExt,
+    process::{self, Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
+
+use crate::{
+    cli::ServeClipboardArgs,
+    wayland::{
+        self,
+        types::{ClipboardSource, Delegate},
+    },
+};
+
+/// Names of plain text, from the mime type to the ones X11 clients ask for.
+const TEXT_MIMES: [&str; 5] = [
+    "text/plain;charset=utf-8",
+    "text/plain",
+    "UTF8_STRING",
+    "STRING",
+    "TEXT",
+];
+
+/// Printed by `serve-clipboard` once it owns the selection.
+const READY: &str = "ready";
+
+/// Puts `data` of type `mime` on the clipboard of the first seat, returns once it is there.
+pub(crate) fn copy(data: &[u8], mime: &str) -> io::Result<()> {
+    let mut child = Command::new(env::current_exe()?)
+        .args(["serve-clipboard", "--mime", mime])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        // Out of the process group of the terminal, so Ctrl-C there doesn't empty the clipboard
+        .process_group(0)
+        .spawn()?;
+    // Closing stdin tells the child it got everything
+    child.stdin.take().unwrap().write_all(data)?;
+
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line)?;
+    if line.trim_end() != READY {
+        child.wait()?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the compositor doesn't support wlr-data-control",
+        ));
+    }
+    Ok(())
+}
+
+/// The `serve-clipboard` command: offers stdin until the selection gets replaced.
+pub(crate) fn serve(args: &ServeClipboardArgs) {
+    let mut data = vec![];
+    io::stdin().read_to_end(&mut data).unwrap();
+
+    let mut vars = wayland::init();
+    let Ok(manager) = vars
+        .globals
+        .bind::<ZwlrDataControlManagerV1, _, _>(&vars.qh, 1..=2, ())
+    else {
+        log::error!("The compositor doesn't support wlr-data-control");
+        process::exit(1);
+    };
+    let Ok(seat) = vars.globals.bind::<WlSeat, _, _>(&vars.qh, 1..=7, ()) else {
+        log::error!("No seat to put the clipboard on");
+        process::exit(1);
+    };
+
+    let state = Arc::new(Mutex::new(ClipboardSource {
+        data,
+        cancelled: false,
+    }));
+    let source = manager.create_data_source(&vars.qh, state.clone());
+    if args.mime.starts_with("text/plain") {
+        for mime in TEXT_MIMES {
+            source.offer(mime.to_string());
+        }
+    } else {
+        source.offer(args.mime.clone());
+    }
+    let device = manager.get_data_device(&seat, &vars.qh, ());
+    device.set_selection(Some(&source));
+    vars.event_queue.roundtrip(&mut Delegate).unwrap();
+    println!("{READY}");
+
+    while !state.lock().unwrap().cancelled {
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+    }
+    log::debug!("The clipboard got taken over");
+    source.destroy();
+    device.destroy();
+}
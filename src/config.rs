@@ -0,0 +1,221 @@
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use serde::Deserialize;
+
+/// Settings read from `$XDG_CONFIG_HOME/screen_capturer/config.toml`, every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) theme: Theme,
+    pub(crate) editor: EditorConfig,
+}
+
+/// Look of the overlay shown while capturing.
+///
+/// ```toml
+/// [theme]
+/// tint = "#050505"
+/// alpha = 80
+/// border_width = 2
+/// border_color = "#ffffffcc"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Theme {
+    /// Color everything outside of the selection gets dimmed with.
+    pub(crate) tint: Color,
+    /// Opacity of the tint, 0 leaves the screen untouched.
+    pub(crate) alpha: u8,
+    /// Width of the border around the selection in physical pixels, 0 disables it.
+    pub(crate) border_width: u32,
+    pub(crate) border_color: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            tint: Color([5, 5, 5, 255]),
+            alpha: 80,
+            border_width: 2,
+            border_color: Color([255, 255, 255, 204]),
+        }
+    }
+}
+
+/// Choices offered by the annotation tools.
+///
+/// ```toml
+/// [editor]
+/// colors = ["#e53935", "#fdd835", "#43a047", "#1e88e5", "#ffffff", "#000000"]
+/// width = 3
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EditorConfig {
+    /// Palette cycled through with `c`, the first one is picked at the start.
+    pub(crate) colors: Vec<Color>,
+    /// Initial stroke width in logical pixels.
+    pub(crate) width: u32,
+}
+
+impl Default for EditorConfig {
+    fn default() -> Self {
+        EditorConfig {
+            colors: vec![
+                Color([229, 57, 53, 255]),
+                Color([253, 216, 53, 255]),
+                Color([67, 160, 71, 255]),
+                Color([30, 136, 229, 255]),
+                Color([255, 255, 255, 255]),
+                Color([0, 0, 0, 255]),
+            ],
+            width: 3,
+        }
+    }
+}
+
+/// A straight alpha RGBA color, written as `#rrggbb` or `#rrggbbaa`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Color(pub(crate) [u8; 4]);
+
+impl Color {
+    /// The color as an `Abgr8888` pixel, which Wayland expects premultiplied.
+    pub(crate) fn premultiplied(self, alpha: u8) -> u32 {
+        let [r, g, b, a] = self.0;
+        let alpha = a as u32 * alpha as u32 / 255;
+        let [r, g, b] = [r, g, b].map(|channel| (channel as u32 * alpha / 255) as u8);
+        u32::from_le_bytes([r, g, b, alpha as u8])
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.strip_prefix('#').unwrap_or(&value);
+        let invalid = || format!("invalid color {value:?}, expected #rrggbb or #rrggbbaa");
+        // Digits only, `from_str_radix` would take a sign as well
+        if !matches!(hex.len(), 6 | 8) || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let mut color = [255; 4];
+        for (channel, digits) in color.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).unwrap();
+            *channel = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Color(color))
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    Some(
+        dirs::config_dir()?
+            .join("screen_capturer")
+            .join("config.toml"),
+    )
+}
+
+/// Reads the config file, falling back to the defaults when it is missing or broken.
+pub(crate) fn load() -> Config {
+    let Some(path) = config_path() else {
+        return Config::default();
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Config::default(),
+        Err(err) => {
+            log::warn!("Failed to read {}: {err}", path.display());
+            return Config::default();
+        }
+    };
+
+    log::debug!("Loading config from {}", path.display());
+    toml::from_str(&contents).unwrap_or_else(|err| {
+        log::warn!("Ignoring invalid config {}: {err}", path.display());
+        Config::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(value: &str) -> Result<Color, String> {
+        Color::try_from(value.to_string())
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(color("#e53935"), Ok(Color([229, 57, 53, 255])));
+        assert_eq!(color("#FFFFFFCC"), Ok(Color([255, 255, 255, 204])));
+        // The `#` is optional
+        assert_eq!(color("00000000"), Ok(Color([0, 0, 0, 0])));
+
+        for invalid in [
+            "",
+            "#",
+            "#fff",
+            "#fffffff",
+            "#fffffffff",
+            "#ggggggg",
+            "#+f+f+f",
+            "#-f+f+f",
+            "#ff ff f",
+            "#ffééff",
+        ] {
+            assert!(color(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn premultiplies_colors() {
+        assert_eq!(
+            Color([255, 128, 0, 255]).premultiplied(255),
+            u32::from_le_bytes([255, 128, 0, 255])
+        );
+        assert_eq!(
+            Color([255, 128, 0, 128]).premultiplied(255),
+            u32::from_le_bytes([128, 64, 0, 128])
+        );
+        assert_eq!(Color([255, 255, 255, 255]).premultiplied(0), 0);
+    }
+
+    #[test]
+    fn reads_config_files() {
+        let config: Config = toml::from_str(
+            r##"
+            [theme]
+            tint = "#102030"
+            border_width = 0
+
+            [editor]
+            colors = ["#ff0000", "#00ff0080"]
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.theme.tint, Color([16, 32, 48, 255]));
+        assert_eq!(config.theme.border_width, 0);
+        // Left out keys keep their defaults
+        assert_eq!(config.theme.alpha, Theme::default().alpha);
+        assert_eq!(config.theme.border_color, Theme::default().border_color);
+        assert_eq!(
+            config.editor.colors,
+            [Color([255, 0, 0, 255]), Color([0, 255, 0, 128])]
+        );
+        assert_eq!(config.editor.width, EditorConfig::default().width);
+
+        let empty: Config = toml::from_str("").unwrap();
+        assert_eq!(empty.theme.tint, Theme::default().tint);
+
+        for invalid in [
+            "[theme]\ntint = \"#+f+f+f\"",
+            "[theme]\nalpha = 300",
+            "[theme]\ntints = \"#000000\"",
+            "[overlay]",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err(), "{invalid:?}");
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    mem,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{self, Path, PathBuf},
+    process, ptr,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use image::DynamicImage;
+use log::Level;
+use serde::{Deserialize, Serialize};
+use wayland_client::{
+    protocol::{wl_output::WlOutput, wl_shm::WlShm},
+    Proxy,
+};
+use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+use crate::{
+    cli::{ClientArgs, DaemonArgs, Depth, EncodingFormat, OutputTarget, ResizeFilter, SaveScale},
+    encode_image, image_format,
+    logging::Span,
+    numbered_path, pixel_format,
+    redact::{self, RedactStyle},
+    scale_image,
+    stitch::{logical_rect, stitch, target_scale},
+    types::Rect,
+    wayland::{
+        capture_frame, get_screen_data, init, output_changes,
+        types::{Delegate, FrameBuffer, ScreenData},
+        WaylandVarsNew,
+    },
+    SaveOptions,
+};
+
+/// A capture command, sent as a single line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CaptureRequest {
+    pub(crate) mode: CaptureMode,
+    /// Index of the output captured in [`CaptureMode::Screen`].
+    #[serde(default)]
+    pub(crate) screen: usize,
+    /// Crop rectangle in physical pixels of the output in [`CaptureMode::Screen`], the captured
+    /// rectangle in global logical coordinates in [`CaptureMode::Area`].
+    #[serde(default)]
+    pub(crate) region: Option<Region>,
+    #[serde(default)]
+    pub(crate) format: Option<EncodingFormat>,
+    #[serde(default)]
+    pub(crate) depth: Depth,
+    #[serde(default)]
+    pub(crate) scale: SaveScale,
+    #[serde(default)]
+    pub(crate) filter: ResizeFilter,
+    /// Rectangles in global logical coordinates hidden before anything else happens to the
+    /// capture, whatever the mode.
+    #[serde(default)]
+    pub(crate) redact: Vec<Region>,
+    #[serde(default)]
+    pub(crate) redact_style: RedactStyle,
+    /// `-` sends the encoded image back over the socket, defaults to `~/Pictures`.
+    #[serde(default)]
+    pub(crate) destination: Option<OutputTarget>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CaptureMode {
+    /// Every output, one image each.
+    Full,
+    /// A single output.
+    Screen,
+    /// A region in global logical coordinates, stitched together from every output it covers.
+    Area,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Region {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+}
+
+impl From<Region> for Rect {
+    fn from(region: Region) -> Self {
+        Rect {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parses `x,y,width,height`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        let [x, y, width, height] = values[..] else {
+            return Err("expected x,y,width,height".to_string());
+        };
+        if width <= 0 || height <= 0 {
+            return Err("width and height have to be positive".to_string());
+        }
+        Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// The daemon's answer, a single line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum CaptureResponse {
+    /// The images were written to these files.
+    Saved {
+        files: Vec<PathBuf>,
+    },
+    /// `size` bytes of the encoded image follow the response line.
+    Inline {
+        size: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug)]
+struct CaptureError(String);
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How long a client gets to send its request and to take the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wayland state kept alive between captures.
+struct Daemon {
+    vars: WaylandVarsNew,
+    screencopy_manager: ZwlrScreencopyManagerV1,
+    shm: WlShm,
+    outputs: Vec<WlOutput>,
+    screens: Vec<ScreenData>,
+    /// One per output, reused as long as the output keeps its layout.
+    frame_buffers: Vec<Option<FrameBuffer>>,
+    pixels: Vec<u8>,
+    pictures_dir: PathBuf,
+}
+
+fn socket_path(socket: Option<&PathBuf>) -> PathBuf {
+    match socket {
+        Some(socket) => socket.clone(),
+        None => dirs::runtime_dir()
+            .expect("XDG_RUNTIME_DIR not set")
+            .join("screen_capturer.sock"),
+    }
+}
+
+pub(crate) fn run(args: &DaemonArgs) {
+    let socket = socket_path(args.socket.as_ref());
+    if socket.exists() {
+        if UnixStream::connect(&socket).is_ok() {
+            log::error!("A daemon is already listening on {}", socket.display());
+            process::exit(1);
+        }
+        // Left behind by a daemon that didn't shut down cleanly
+        fs::remove_file(&socket).unwrap();
+    }
+    let listener = UnixListener::bind(&socket).expect("Failed to bind socket");
+
+    let mut vars = init();
+    let (screens, outputs): (Vec<_>, Vec<_>) = get_screen_data(&mut vars).into_iter().unzip();
+    let screencopy_manager = vars.globals.bind(&vars.qh, 1..=1, ()).unwrap();
+    let shm = vars.globals.bind(&vars.qh, 1..=1, ()).unwrap();
+
+    let mut daemon = Daemon {
+        frame_buffers: outputs.iter().map(|_| None).collect(),
+        vars,
+        screencopy_manager,
+        shm,
+        outputs,
+        screens,
+        pixels: vec![],
+        pictures_dir: dirs::home_dir()
+            .expect("Home dir not found")
+            .join("Pictures"),
+    };
+
+    let signals = shutdown_signals();
+    listener.set_nonblocking(true).unwrap();
+    log::info!("Listening on {}", socket.display());
+    loop {
+        let (clients, shutdown) = match daemon.wait(&listener, &signals) {
+            Ok(ready) => ready,
+            Err(err) => {
+                log::error!("Wayland connection lost: {err}");
+                shut_down(&socket, 1);
+            }
+        };
+        if shutdown {
+            log::info!("Shutting down");
+            shut_down(&socket, 0);
+        }
+        if !clients {
+            continue;
+        }
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("Failed to accept a client: {err}");
+                    break;
+                }
+            };
+            // A client that never sends its request must not keep the others waiting
+            let result = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.set_read_timeout(Some(CLIENT_TIMEOUT)))
+                .and_then(|()| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+                .and_then(|()| daemon.handle(stream));
+            if let Err(err) = result {
+                log::warn!("Client connection failed: {err}");
+            }
+        }
+    }
+}
+
+/// Blocks SIGINT and SIGTERM, they get read from the returned signalfd instead so the socket
+/// can be removed before exiting. Threads spawned later inherit the mask.
+fn shutdown_signals() -> OwnedFd {
+    // SAFETY: the set is initialized by sigemptyset before use, and the fd signalfd returns is
+    // owned by nothing else
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        let fd = libc::signalfd(-1, &set, libc::SFD_CLOEXEC);
+        assert!(fd >= 0, "signalfd failed: {}", io::Error::last_os_error());
+        OwnedFd::from_raw_fd(fd)
+    }
+}
+
+/// Removes the socket and exits with `code`.
+fn shut_down(socket: &Path, code: i32) -> ! {
+    if let Err(err) = fs::remove_file(socket) {
+        log::warn!("Failed to remove {}: {err}", socket.display());
+    }
+    process::exit(code);
+}
+
+impl Daemon {
+    /// Dispatches Wayland events until a client connects to `listener` or a shutdown signal
+    /// arrives on `signals`, returning which of them happened. Following output changes while
+    /// idle keeps events from piling up until the next request.
+    fn wait(&mut self, listener: &UnixListener, signals: &OwnedFd) -> io::Result<(bool, bool)> {
+        loop {
+            let queue = &mut self.vars.event_queue;
+            queue.flush().map_err(io::Error::other)?;
+            queue
+                .dispatch_pending(&mut Delegate)
+                .map_err(io::Error::other)?;
+            // Events queued in the meantime have to be dispatched first
+            let Some(guard) = queue.prepare_read() else {
+                continue;
+            };
+            let mut fds =
+                [listener.as_fd(), signals.as_fd(), guard.connection_fd()].map(|fd| libc::pollfd {
+                    fd: fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            // SAFETY: `fds` is valid for its length for the duration of the call
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            let [clients, shutdown, wayland] = fds.map(|fd| fd.revents != 0);
+            if wayland {
+                guard.read().map_err(io::Error::other)?;
+                self.vars
+                    .event_queue
+                    .dispatch_pending(&mut Delegate)
+                    .map_err(io::Error::other)?;
+                self.sync_outputs();
+            } else {
+                drop(guard);
+            }
+            if clients || shutdown {
+                return Ok((clients, shutdown));
+            }
+        }
+    }
+
+    fn handle(&mut self, mut stream: UnixStream) -> io::Result<()> {
+        let mut line = String::new();
+        BufReader::new(&mut stream).read_line(&mut line)?;
+
+        let (response, payload) = match serde_json::from_str::<CaptureRequest>(&line) {
+            Ok(request) => match self.capture(&request) {
+                Ok(response) => response,
+                Err(err) => (
+                    CaptureResponse::Error {
+                        message: err.to_string(),
+                    },
+                    None,
+                ),
+            },
+            Err(err) => (
+                CaptureResponse::Error {
+                    message: format!("Invalid request: {err}"),
+                },
+                None,
+            ),
+        };
+
+        serde_json::to_writer(&mut stream, &response)?;
+        stream.write_all(b"\n")?;
+        if let Some(payload) = payload {
+            stream.write_all(&payload)?;
+        }
+        stream.flush()
+    }
+
+    fn capture(
+        &mut self,
+        request: &CaptureRequest,
+    ) -> Result<(CaptureResponse, Option<Vec<u8>>), CaptureError> {
+        log::debug!("{:?}", request);
+        let _span = Span::new(Level::Debug, "Capture request");
+
+        // Catch up on everything that happened while we were idle
+        self.vars
+            .event_queue
+            .roundtrip(&mut Delegate)
+            .map_err(|err| CaptureError(format!("Wayland connection lost: {err}")))?;
+        self.sync_outputs();
+
+        let screens = match request.mode {
+            CaptureMode::Full => (0..self.outputs.len()).collect::<Vec<_>>(),
+            CaptureMode::Screen if request.screen < self.outputs.len() => vec![request.screen],
+            CaptureMode::Screen => {
+                return Err(CaptureError(format!("No output {}", request.screen)));
+            }
+            CaptureMode::Area => {
+                let region = request
+                    .region
+                    .ok_or_else(|| CaptureError("Area mode needs a region".to_string()))?;
+                (0..self.outputs.len())
+                    .filter(|&screen| {
+                        let output = logical_rect(&self.screens[screen]);
+                        Rect::from(region).intersection(&output).is_some()
+                    })
+                    .collect()
+            }
+        };
+        if request.region.is_some() && request.mode == CaptureMode::Full {
+            return Err(CaptureError(
+                "A region needs screen or area mode".to_string(),
+            ));
+        }
+        if screens.is_empty() {
+            return Err(CaptureError("The region covers no output".to_string()));
+        }
+        let image_count = match request.mode {
+            CaptureMode::Area => 1,
+            _ => screens.len(),
+        };
+        let inline = request.destination == Some(OutputTarget::Stdout);
+        if inline && image_count > 1 {
+            return Err(CaptureError(
+                "Only a single image can be sent back, use screen or area mode".to_string(),
+            ));
+        }
+        let format = image_format(request.format, request.destination.as_ref());
+        let options = SaveOptions {
+            format,
+            high_depth: request.depth.keeps_high_depth(format),
+            scale: request.scale,
+            filter: request.filter,
+        };
+
+        let redactions = redact::from_regions(&request.redact, request.redact_style);
+        let mut captures = vec![];
+        for &screen in screens.iter() {
+            let mut capture = self.capture_screen(screen, options.high_depth)?;
+            redact::redact_output(&mut capture, &self.screens[screen], &redactions);
+            captures.push(capture);
+        }
+        let images = match request.mode {
+            CaptureMode::Area => {
+                let region = Rect::from(request.region.unwrap());
+                let outputs = screens
+                    .iter()
+                    .map(|&screen| &self.screens[screen])
+                    .zip(&captures)
+                    .collect::<Vec<_>>();
+                let scale = target_scale(region, &outputs, options.scale);
+                let stitched = stitch(region, &outputs, scale, options.filter.filter_type())
+                    .ok_or_else(|| CaptureError("The region covers no output".to_string()))?;
+                vec![stitched]
+            }
+            _ => screens
+                .iter()
+                .zip(captures)
+                .map(|(&screen, mut img)| {
+                    let captured = (img.width(), img.height());
+                    if let Some(region) = request.region {
+                        img = crop(&img, region)?;
+                    }
+                    Ok(scale_image(img, &self.screens[screen], captured, &options))
+                })
+                .collect::<Result<Vec<_>, CaptureError>>()?,
+        };
+
+        let mut files = vec![];
+        for (image_num, img) in images.into_iter().enumerate() {
+            let path = match &request.destination {
+                Some(OutputTarget::Stdout) => {
+                    let encoded = encode_image(&img, format);
+                    let size = encoded.len();
+                    return Ok((CaptureResponse::Inline { size }, Some(encoded)));
+                }
+                Some(OutputTarget::Path(path)) if image_count > 1 => numbered_path(path, image_num),
+                Some(OutputTarget::Path(path)) => path.clone(),
+                None => self.pictures_dir.join(format!(
+                    "output{}.{}",
+                    image_num,
+                    format.extensions_str()[0]
+                )),
+            };
+            img.save_with_format(&path, format)
+                .map_err(|err| CaptureError(format!("Failed to save image: {err}")))?;
+            files.push(path);
+        }
+
+        Ok((CaptureResponse::Saved { files }, None))
+    }
+
+    /// Picks up outputs plugged in or removed since the last capture, keeping their order so
+    /// screen indices stay stable for the outputs that didn't change.
+    fn sync_outputs(&mut self) {
+        let known = self
+            .screens
+            .iter()
+            .map(|screen| screen.name)
+            .collect::<Vec<_>>();
+        let changes = output_changes(&mut self.vars, &known);
+
+        let mut index = 0;
+        while index < self.screens.len() {
+            if changes.removed.contains(&self.screens[index].name) {
+                self.screens.remove(index);
+                let output = self.outputs.remove(index);
+                if output.version() >= 3 {
+                    output.release();
+                }
+                self.frame_buffers.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+        for (screen, output) in changes.added {
+            self.screens.push(screen);
+            self.outputs.push(output);
+            self.frame_buffers.push(None);
+        }
+    }
+
+    /// Captures the whole output at its physical resolution.
+    fn capture_screen(
+        &mut self,
+        screen: usize,
+        high_depth: bool,
+    ) -> Result<DynamicImage, CaptureError> {
+        let frame_buffer = &mut self.frame_buffers[screen];
+        capture_frame(
+            &mut self.vars,
+            &self.screencopy_manager,
+            &self.shm,
+            &self.outputs[screen],
+            frame_buffer,
+        )
+        .ok_or_else(|| CaptureError("Screencopy failed".to_string()))?;
+        let frame_buffer = frame_buffer.as_ref().unwrap();
+        frame_buffer.read(&mut self.pixels);
+
+        pixel_format::to_image(frame_buffer.format, &self.pixels, high_depth)
+            .map_err(|err| CaptureError(err.to_string()))
+    }
+}
+
+fn crop(img: &DynamicImage, region: Region) -> Result<DynamicImage, CaptureError> {
+    let bounds = Rect {
+        x: 0,
+        y: 0,
+        width: img.width() as i32,
+        height: img.height() as i32,
+    };
+    let region = Rect::from(region);
+    if region.intersection(&bounds) != Some(region) {
+        return Err(CaptureError("Region is outside of the output".to_string()));
+    }
+    Ok(img.crop_imm(
+        region.x as u32,
+        region.y as u32,
+        region.width as u32,
+        region.height as u32,
+    ))
+}
+
+pub(crate) fn client(args: &ClientArgs) {
+    args.save.refuse_terminal();
+    let destination = match &args.save.output {
+        // The daemon doesn't share our working directory
+        Some(OutputTarget::Path(path)) => Some(OutputTarget::Path(
+            path::absolute(path).expect("Invalid output path"),
+        )),
+        destination => destination.clone(),
+    };
+    let request = CaptureRequest {
+        mode: args.mode,
+        screen: args.screen,
+        region: args.region,
+        format: args.save.format,
+        depth: args.save.depth,
+        scale: args.save.scale,
+        filter: args.save.filter,
+        redact: args.save.redact.clone(),
+        redact_style: args.save.redact_style,
+        destination,
+    };
+
+    let socket = socket_path(args.socket.as_ref());
+    let mut stream = UnixStream::connect(&socket).unwrap_or_else(|err| {
+        log::error!("Failed to connect to {}: {err}", socket.display());
+        process::exit(1);
+    });
+    serde_json::to_writer(&mut stream, &request).unwrap();
+    stream.write_all(b"\n").unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let hung_up = |err: io::Error| -> ! {
+        log::error!("The daemon hung up: {err}");
+        process::exit(1);
+    };
+    match reader.read_line(&mut line) {
+        Ok(0) => hung_up(io::ErrorKind::UnexpectedEof.into()),
+        Ok(_) => (),
+        Err(err) => hung_up(err),
+    }
+    match serde_json::from_str(&line).expect("Invalid response from daemon") {
+        CaptureResponse::Saved { files } => {
+            for file in files {
+                println!("{}", file.display());
+            }
+        }
+        CaptureResponse::Inline { size } => {
+            let mut encoded = vec![0; size];
+            if let Err(err) = reader.read_exact(&mut encoded) {
+                hung_up(err);
+            }
+            io::stdout()
+                .lock()
+                .write_all(&encoded)
+                .expect("Failed to write image to stdout");
+        }
+        CaptureResponse::Error { message } => {
+            log::error!("{message}");
+            process::exit(1);
+        }
+    }
+}
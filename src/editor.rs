@@ -0,0 +1,901 @@
+//! Selecting a region on the overlay and annotating it with the pointer and the keyboard.
+//!
+//! Dragging with the select tool picks the region, the other tools draw on top of it. The arrow
+//! keys, typed dimensions and a locked aspect ratio place the selection precisely. `Enter` or a
+//! tap inside of the selection saves, `Escape` cancels, and the status label on the overlay lists
+//! the remaining keys.
+
+use std::mem;
+
+use xkbcommon::xkb::Keysym;
+
+use crate::{
+    annotate::{Annotation, Annotations, Point, Shape, Tool},
+    config::{Color, EditorConfig},
+    ipc::Window,
+    overlay::{Event, BTN_LEFT},
+    redact::RedactStyle,
+    types::Rect,
+    wayland::{cursor::CursorIcon, keyboard::KeyPress},
+};
+
+/// Scroll distance that changes the stroke width by one.
+const SCROLL_STEP: f64 = 10.0;
+const MAX_WIDTH: u32 = 32;
+/// Aspect ratios `l` cycles through before unlocking again.
+const ASPECTS: [(u32, u32); 3] = [(16, 9), (4, 3), (1, 1)];
+/// How close to an edge of the selection the pointer grabs it, in logical pixels.
+const HANDLE_DISTANCE: f64 = 6.0;
+
+/// The edges of the selection a handle moves.
+#[derive(Debug, Clone, Copy)]
+struct Edges {
+    left: bool,
+    top: bool,
+    right: bool,
+    bottom: bool,
+}
+
+impl Edges {
+    fn cursor(self) -> CursorIcon {
+        match self {
+            Edges {
+                left: true,
+                top: true,
+                ..
+            }
+            | Edges {
+                right: true,
+                bottom: true,
+                ..
+            } => CursorIcon::ResizeNwse,
+            Edges {
+                right: true,
+                top: true,
+                ..
+            }
+            | Edges {
+                left: true,
+                bottom: true,
+                ..
+            } => CursorIcon::ResizeNesw,
+            Edges { left: true, .. } | Edges { right: true, .. } => CursorIcon::ResizeEw,
+            _ => CursorIcon::ResizeNs,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Drag {
+    /// A new selection from this corner to the pointer.
+    Select(Point),
+    /// Moves the selection, which was `rect` with the pointer at `from`, along with the pointer.
+    Move {
+        from: Point,
+        rect: Rect,
+    },
+    /// Moves `edges` of the selection, which was `rect`, to the pointer.
+    Resize {
+        edges: Edges,
+        rect: Rect,
+    },
+    Shape(Shape),
+}
+
+#[derive(Debug)]
+pub(crate) struct Editor {
+    colors: Vec<Color>,
+    color: usize,
+    /// Stroke width in logical pixels.
+    width: u32,
+    pub(crate) tool: Tool,
+    /// The region that gets saved, in global logical coordinates.
+    selection: Option<Rect>,
+    pub(crate) annotations: Annotations,
+    pointer: Point,
+    drag: Option<Drag>,
+    /// A label being typed at this position.
+    text: Option<(Point, String)>,
+    scrolled: f64,
+    /// Whether the magnifier is switched on, `m` toggles it.
+    loupe: bool,
+    /// Windows a click selects, topmost first.
+    windows: Vec<Window>,
+    /// Width to height ratio the selection is locked to.
+    aspect: Option<(u32, u32)>,
+    /// The rectangle around every output, typed sizes get shrunk to fit into it.
+    bounds: Option<Rect>,
+    /// Dimensions being typed after `d`.
+    dimensions: Option<String>,
+    /// Whether space is held, dragging then moves the selection.
+    space: bool,
+    /// Whether the last press was inside of the selection with the select tool, tapping there
+    /// saves.
+    tap_saves: bool,
+    /// Set once the user saved (`true`) or cancelled (`false`).
+    pub(crate) finished: Option<bool>,
+}
+
+/// `length` times `numerator / denominator`, at least a pixel. Computed in floating point, a
+/// large typed size times the ratio doesn't fit into an `i32`.
+fn fitted(length: i32, numerator: u32, denominator: u32) -> i32 {
+    (length as f64 * numerator as f64 / denominator as f64)
+        .round()
+        .clamp(1.0, i32::MAX as f64) as i32
+}
+
+impl Editor {
+    pub(crate) fn new(config: &EditorConfig) -> Editor {
+        let colors = if config.colors.is_empty() {
+            EditorConfig::default().colors
+        } else {
+            config.colors.clone()
+        };
+        Editor {
+            colors,
+            color: 0,
+            width: config.width.clamp(1, MAX_WIDTH),
+            tool: Tool::Select,
+            selection: None,
+            annotations: Annotations::default(),
+            pointer: (0.0, 0.0),
+            drag: None,
+            text: None,
+            scrolled: 0.0,
+            loupe: true,
+            windows: vec![],
+            aspect: None,
+            bounds: None,
+            dimensions: None,
+            space: false,
+            tap_saves: false,
+            finished: None,
+        }
+    }
+
+    /// Starts out with `selection` instead of asking for one.
+    pub(crate) fn with_selection(mut self, selection: Rect) -> Editor {
+        self.selection = Some(selection);
+        self
+    }
+
+    /// Lets a click select one of `windows` instead of dragging.
+    pub(crate) fn with_windows(mut self, windows: Vec<Window>) -> Editor {
+        self.windows = windows;
+        self
+    }
+
+    /// Keeps typed sizes within `bounds`, the rectangle around every output.
+    pub(crate) fn with_bounds(mut self, bounds: Rect) -> Editor {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// The topmost window under the pointer.
+    fn window_at_pointer(&self) -> Option<&Window> {
+        self.windows
+            .iter()
+            .find(|window| window.rect.contains(self.pointer))
+    }
+
+    /// The selection, following the pointer while it is being dragged. Before anything got
+    /// selected, the window under the pointer shows what a click would select.
+    pub(crate) fn selection(&self) -> Option<Rect> {
+        match self.drag {
+            Some(Drag::Select(start)) => {
+                Some(self.spanned(start)).filter(|rect| rect.width > 0 && rect.height > 0)
+            }
+            Some(_) => self.selection,
+            None => self
+                .selection
+                .or_else(|| self.window_at_pointer().map(|window| window.rect)),
+        }
+    }
+
+    /// The selection from `start` to the pointer, shrunk to the locked aspect ratio.
+    fn spanned(&self, start: Point) -> Rect {
+        let (mut dx, mut dy) = (self.pointer.0 - start.0, self.pointer.1 - start.1);
+        if let Some((width, height)) = self.aspect {
+            let scale = (dx.abs() / width as f64).min(dy.abs() / height as f64);
+            dx = dx.signum() * scale * width as f64;
+            dy = dy.signum() * scale * height as f64;
+        }
+        Rect::spanning(start, (start.0 + dx, start.1 + dy))
+    }
+
+    /// Changes the height of `rect` to match its width in the locked aspect ratio.
+    fn fit_height(&self, rect: Rect) -> Rect {
+        let Some((width, height)) = self.aspect else {
+            return rect;
+        };
+        Rect {
+            height: fitted(rect.width, height, width),
+            ..rect
+        }
+    }
+
+    /// Changes the width of `rect` to match its height in the locked aspect ratio.
+    fn fit_width(&self, rect: Rect) -> Rect {
+        let Some((width, height)) = self.aspect else {
+            return rect;
+        };
+        Rect {
+            width: fitted(rect.height, width, height),
+            ..rect
+        }
+    }
+
+    /// Moves the right and bottom edges of `rect` by `delta`, or the left and top ones when
+    /// `leading`, keeping at least a pixel and the locked aspect ratio.
+    fn resize(&self, rect: Rect, (dx, dy): (i32, i32), leading: bool) -> Rect {
+        let sign = if leading { -1 } else { 1 };
+        let resized = Rect {
+            width: (rect.width + sign * dx).max(1),
+            height: (rect.height + sign * dy).max(1),
+            ..rect
+        };
+        let resized = match dx {
+            0 => self.fit_width(resized),
+            _ => self.fit_height(resized),
+        };
+        if !leading {
+            return resized;
+        }
+        // The right and bottom edges stay in place
+        Rect {
+            x: rect.x + rect.width - resized.width,
+            y: rect.y + rect.height - resized.height,
+            ..resized
+        }
+    }
+
+    /// Parses typed `WIDTHxHEIGHT`, or just the width when the aspect ratio is locked.
+    fn typed_dimensions(&self, typed: &str) -> Option<(i32, i32)> {
+        let (width, height) = match typed.split_once('x') {
+            Some((width, height)) => (width.parse().ok()?, height.parse().ok()?),
+            None => {
+                // The height follows from the locked aspect ratio
+                self.aspect?;
+                let width = typed.parse().ok()?;
+                let fitted = self.fit_height(Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height: 0,
+                });
+                (width, fitted.height)
+            }
+        };
+        if width <= 0 || height <= 0 {
+            return None;
+        }
+        // Shrunk evenly, so a locked aspect ratio stays
+        let Some(bounds) = self.bounds else {
+            return Some((width, height));
+        };
+        let scale = (bounds.width as f64 / width as f64)
+            .min(bounds.height as f64 / height as f64)
+            .min(1.0);
+        let shrink = |length: i32| (length as f64 * scale).round().max(1.0) as i32;
+        Some((shrink(width), shrink(height)))
+    }
+
+    /// The edges of the selection the pointer is close enough to drag with the select tool.
+    fn handle_at_pointer(&self) -> Option<Edges> {
+        let rect = self.selection?;
+        let usable = self.tool == Tool::Select && self.drag.is_none() && self.text.is_none();
+        if !usable || self.space {
+            return None;
+        }
+        let (x, y) = self.pointer;
+        let near = |position: f64, edge: i32| (position - edge as f64).abs() <= HANDLE_DISTANCE;
+        let within = |position: f64, start: i32, length: i32| {
+            (start as f64 - HANDLE_DISTANCE..=(start + length) as f64 + HANDLE_DISTANCE)
+                .contains(&position)
+        };
+        let (along_x, along_y) = (
+            within(x, rect.x, rect.width),
+            within(y, rect.y, rect.height),
+        );
+        let edges = Edges {
+            left: along_y && near(x, rect.x),
+            top: along_x && near(y, rect.y),
+            right: along_y && near(x, rect.x + rect.width),
+            bottom: along_x && near(y, rect.y + rect.height),
+        };
+        Some(edges).filter(|edges| edges.left || edges.top || edges.right || edges.bottom)
+    }
+
+    /// Moves `edges` of `rect` to `position`, keeping the locked aspect ratio.
+    fn resize_edges(&self, rect: Rect, edges: Edges, position: Point) -> Option<Rect> {
+        let pick = |moved: bool, edge: i32, to: f64| if moved { to } else { edge as f64 };
+        let resized = Rect::spanning(
+            (
+                pick(edges.left, rect.x, position.0),
+                pick(edges.top, rect.y, position.1),
+            ),
+            (
+                pick(edges.right, rect.x + rect.width, position.0),
+                pick(edges.bottom, rect.y + rect.height, position.1),
+            ),
+        );
+        if resized.width <= 0 || resized.height <= 0 {
+            return None;
+        }
+        let fitted = if edges.left || edges.right {
+            self.fit_height(resized)
+        } else {
+            self.fit_width(resized)
+        };
+        // The edges across from the handle stay in place
+        let x = if edges.left {
+            resized.x + resized.width - fitted.width
+        } else {
+            resized.x
+        };
+        let y = if edges.top {
+            resized.y + resized.height - fitted.height
+        } else {
+            resized.y
+        };
+        Some(Rect { x, y, ..fitted })
+    }
+
+    /// The cursor over the overlay: arrows while moving the selection or over its edges.
+    pub(crate) fn cursor(&self) -> CursorIcon {
+        match &self.drag {
+            Some(Drag::Move { .. }) => CursorIcon::Move,
+            Some(Drag::Resize { edges, .. }) => edges.cursor(),
+            Some(_) => CursorIcon::Crosshair,
+            None if self.space && self.selection.is_some() => CursorIcon::Move,
+            None => self
+                .handle_at_pointer()
+                .map_or(CursorIcon::Crosshair, Edges::cursor),
+        }
+    }
+
+    pub(crate) fn pointer(&self) -> Point {
+        self.pointer
+    }
+
+    /// The magnifier helps placing the corners of the selection, it would only get in the way of
+    /// drawing.
+    pub(crate) fn loupe_visible(&self) -> bool {
+        self.loupe && self.text.is_none() && (self.selection.is_none() || self.tool == Tool::Select)
+    }
+
+    pub(crate) fn color(&self) -> Color {
+        self.colors[self.color]
+    }
+
+    fn annotation(&self, shape: Shape) -> Annotation {
+        Annotation {
+            shape,
+            color: self.color(),
+            width: self.width as f64,
+        }
+    }
+
+    /// The annotation being drawn or typed, not part of [`Editor::annotations`] yet.
+    pub(crate) fn pending(&self) -> Option<Annotation> {
+        if let Some((position, text)) = &self.text {
+            // Show where the label goes before anything got typed
+            let text = format!("{text}_");
+            return Some(self.annotation(Shape::Text {
+                position: *position,
+                text,
+            }));
+        }
+        match &self.drag {
+            Some(Drag::Shape(shape)) => Some(self.annotation(shape.clone())),
+            _ => None,
+        }
+    }
+
+    /// Keys of the current state, shown next to the selection.
+    pub(crate) fn status(&self) -> String {
+        if self.text.is_some() {
+            return "Type the label, Enter: done  Esc: discard".to_string();
+        }
+        if let Some(typed) = &self.dimensions {
+            let format = match self.aspect {
+                Some(_) => "WIDTH or WIDTHxHEIGHT",
+                None => "WIDTHxHEIGHT",
+            };
+            return format!("Size: {typed}_ as {format}, Enter: apply  Esc: discard");
+        }
+        let aspect = match self.aspect {
+            Some((width, height)) => format!("{width}:{height}"),
+            None => "free".to_string(),
+        };
+        if self.selection.is_none() {
+            return match self.window_at_pointer() {
+                Some(window) => format!(
+                    "Drag to select a region or click to select {}\n\
+                     d: size  l: aspect {aspect}  m: loupe  Esc: cancel",
+                    window.title
+                ),
+                None => format!(
+                    "Drag to select a region, d: size  l: aspect {aspect}  m: loupe  Esc: cancel"
+                ),
+            };
+        }
+        format!(
+            "{} {}px | s:select r:rect a:arrow p:pen h:highlight n:number t:text\n\
+             x:pixelate b:blur k:blackout c:color [ ]:width m:loupe ^Z ^Y:undo/redo\n\
+             arrows:move ^/alt+arrows:resize space+drag:move d:size l:aspect {aspect}\n\
+             Enter/tap:save Esc:cancel",
+            self.tool.name(),
+            self.width,
+        )
+    }
+
+    /// Handles one event, returns whether the overlay has to be redrawn.
+    pub(crate) fn handle(&mut self, event: Event) -> bool {
+        match event {
+            Event::Motion(position) => {
+                let previous = mem::replace(&mut self.pointer, position);
+                let hovers_windows = self.selection.is_none() && !self.windows.is_empty();
+                self.drag_to(position, previous) || self.loupe_visible() || hovers_windows
+            }
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: true,
+            } => {
+                self.press();
+                true
+            }
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: false,
+            } => self.release(),
+            Event::Button { .. } => false,
+            Event::Tap(position)
+                if self.tap_saves
+                    && self.selection().is_some_and(|rect| rect.contains(position)) =>
+            {
+                self.finished = Some(true);
+                false
+            }
+            Event::Tap(_) => false,
+            Event::Scroll(distance) => {
+                self.scrolled += distance;
+                let steps = (self.scrolled / SCROLL_STEP).trunc();
+                self.scrolled -= steps * SCROLL_STEP;
+                // Scrolling up makes strokes wider
+                self.set_width(self.width as i64 - steps as i64)
+            }
+            Event::Key(key) => self.key(key),
+            Event::KeyRelease(Keysym::space) => {
+                self.space = false;
+                false
+            }
+            Event::KeyRelease(_) => false,
+        }
+    }
+
+    fn set_width(&mut self, width: i64) -> bool {
+        let width = width.clamp(1, MAX_WIDTH as i64) as u32;
+        let changed = width != self.width;
+        self.width = width;
+        changed
+    }
+
+    fn drag_to(&mut self, position: Point, previous: Point) -> bool {
+        match &mut self.drag {
+            None => false,
+            Some(Drag::Select(start)) => {
+                // Holding space moves the selection being dragged instead of resizing it
+                if self.space {
+                    start.0 += position.0 - previous.0;
+                    start.1 += position.1 - previous.1;
+                }
+                true
+            }
+            Some(Drag::Move { from, rect }) => {
+                self.selection = Some(Rect {
+                    x: rect.x + (position.0 - from.0).round() as i32,
+                    y: rect.y + (position.1 - from.1).round() as i32,
+                    ..*rect
+                });
+                true
+            }
+            &mut Some(Drag::Resize { edges, rect }) => {
+                if let Some(resized) = self.resize_edges(rect, edges, position) {
+                    self.selection = Some(resized);
+                }
+                true
+            }
+            Some(Drag::Shape(
+                Shape::Rectangle { to, .. } | Shape::Arrow { to, .. } | Shape::Redact { to, .. },
+            )) => {
+                *to = position;
+                true
+            }
+            Some(Drag::Shape(Shape::Freehand(points) | Shape::Highlighter(points))) => {
+                let last = points.last().copied().unwrap_or(position);
+                // Skip points closer than half a logical pixel, they only make strokes slower
+                let moved = (last.0 - position.0).hypot(last.1 - position.1) >= 0.5;
+                if moved {
+                    points.push(position);
+                }
+                moved
+            }
+            Some(Drag::Shape(_)) => false,
+        }
+    }
+
+    fn press(&mut self) {
+        self.tap_saves = self.tool == Tool::Select
+            && self.text.is_none()
+            && self
+                .selection
+                .is_some_and(|rect| rect.contains(self.pointer));
+        self.finish_text();
+        let position = self.pointer;
+        if let Some(rect) = self.selection.filter(|_| self.space) {
+            self.drag = Some(Drag::Move {
+                from: position,
+                rect,
+            });
+            return;
+        }
+        if let (Some(edges), Some(rect)) = (self.handle_at_pointer(), self.selection) {
+            self.drag = Some(Drag::Resize { edges, rect });
+            return;
+        }
+        let tool = match self.selection {
+            Some(_) => self.tool,
+            None => Tool::Select,
+        };
+        self.drag = match tool {
+            Tool::Select => Some(Drag::Select(position)),
+            Tool::Rectangle => Some(Drag::Shape(Shape::Rectangle {
+                from: position,
+                to: position,
+            })),
+            Tool::Arrow => Some(Drag::Shape(Shape::Arrow {
+                from: position,
+                to: position,
+            })),
+            Tool::Freehand => Some(Drag::Shape(Shape::Freehand(vec![position]))),
+            Tool::Highlighter => Some(Drag::Shape(Shape::Highlighter(vec![position]))),
+            Tool::Marker => {
+                let number = self.annotations.next_marker();
+                self.annotations.push(self.annotation(Shape::Marker {
+                    center: position,
+                    number,
+                }));
+                None
+            }
+            Tool::Text => {
+                self.text = Some((position, String::new()));
+                None
+            }
+            Tool::Redact(style) => Some(Drag::Shape(Shape::Redact {
+                from: position,
+                to: position,
+                style,
+            })),
+        };
+    }
+
+    fn release(&mut self) -> bool {
+        match self.drag.take() {
+            None => false,
+            Some(Drag::Move { .. } | Drag::Resize { .. }) => true,
+            Some(Drag::Select(start)) => {
+                let rect = self.spanned(start);
+                if rect.width > 0 && rect.height > 0 {
+                    self.selection = Some(rect);
+                } else if let Some(window) = self.window_at_pointer() {
+                    self.selection = Some(window.rect);
+                }
+                // Any other click without dragging keeps the previous selection
+                true
+            }
+            Some(Drag::Shape(shape)) => {
+                let degenerate = match &shape {
+                    Shape::Rectangle { from, to } | Shape::Redact { from, to, .. } => {
+                        from.0 == to.0 || from.1 == to.1
+                    }
+                    Shape::Arrow { from, to } => from == to,
+                    _ => false,
+                };
+                if !degenerate {
+                    self.annotations.push(self.annotation(shape));
+                }
+                true
+            }
+        }
+    }
+
+    /// Adds the label being typed, unless it is empty.
+    fn finish_text(&mut self) {
+        if let Some((position, text)) = self.text.take() {
+            if !text.is_empty() {
+                self.annotations
+                    .push(self.annotation(Shape::Text { position, text }));
+            }
+        }
+    }
+
+    fn key(&mut self, key: KeyPress) -> bool {
+        if let Some((_, text)) = &mut self.text {
+            match key.keysym {
+                Keysym::Return | Keysym::KP_Enter => self.finish_text(),
+                Keysym::Escape => self.text = None,
+                Keysym::BackSpace => {
+                    text.pop();
+                }
+                _ if !key.ctrl && !key.text.chars().any(char::is_control) => {
+                    text.push_str(&key.text);
+                }
+                _ => return false,
+            }
+            return true;
+        }
+
+        if let Some(typed) = &mut self.dimensions {
+            match key.keysym {
+                Keysym::Return | Keysym::KP_Enter => {
+                    let typed = self.dimensions.as_deref().unwrap_or_default();
+                    let Some((width, height)) = self.typed_dimensions(typed) else {
+                        return false;
+                    };
+                    // Keep the corner of the selection, or start at the pointer
+                    let (x, y) = match self.selection {
+                        Some(rect) => (rect.x, rect.y),
+                        None => (self.pointer.0.floor() as i32, self.pointer.1.floor() as i32),
+                    };
+                    self.selection = Some(Rect {
+                        x,
+                        y,
+                        width,
+                        height,
+                    });
+                    self.dimensions = None;
+                }
+                Keysym::Escape => self.dimensions = None,
+                Keysym::BackSpace => {
+                    typed.pop();
+                }
+                _ if !key.text.is_empty()
+                    && key.text.chars().all(|c| c.is_ascii_digit() || c == 'x') =>
+                {
+                    typed.push_str(&key.text);
+                }
+                _ => return false,
+            }
+            return true;
+        }
+
+        match key.keysym {
+            Keysym::space => {
+                self.space = true;
+                return false;
+            }
+            Keysym::Left | Keysym::Right | Keysym::Up | Keysym::Down
+                if self.selection.is_some() && self.drag.is_none() =>
+            {
+                let step = if key.shift { 10 } else { 1 };
+                let delta = match key.keysym {
+                    Keysym::Left => (-step, 0),
+                    Keysym::Right => (step, 0),
+                    Keysym::Up => (0, -step),
+                    _ => (0, step),
+                };
+                let rect = self.selection.unwrap();
+                self.selection = Some(match (key.ctrl, key.alt) {
+                    (true, _) => self.resize(rect, delta, false),
+                    (_, true) => self.resize(rect, delta, true),
+                    _ => Rect {
+                        x: rect.x + delta.0,
+                        y: rect.y + delta.1,
+                        ..rect
+                    },
+                });
+            }
+            Keysym::Escape => self.finished = Some(false),
+            // Not while a new selection is dragged open without any size yet
+            Keysym::Return | Keysym::KP_Enter if self.selection().is_some() => {
+                self.finished = Some(true);
+            }
+            Keysym::z | Keysym::Z if key.ctrl && key.shift => return self.annotations.redo(),
+            Keysym::z if key.ctrl => return self.annotations.undo(),
+            Keysym::y if key.ctrl => return self.annotations.redo(),
+            _ if key.ctrl => return false,
+            _ => {
+                let tool = match key.text.as_str() {
+                    "s" => Tool::Select,
+                    "r" => Tool::Rectangle,
+                    "a" => Tool::Arrow,
+                    "p" => Tool::Freehand,
+                    "h" => Tool::Highlighter,
+                    "n" => Tool::Marker,
+                    "t" => Tool::Text,
+                    "x" => Tool::Redact(RedactStyle::Pixelate),
+                    "b" => Tool::Redact(RedactStyle::Blur),
+                    "k" => Tool::Redact(RedactStyle::Black),
+                    "c" => {
+                        self.color = (self.color + 1) % self.colors.len();
+                        return true;
+                    }
+                    "C" => {
+                        self.color = (self.color + self.colors.len() - 1) % self.colors.len();
+                        return true;
+                    }
+                    "m" => {
+                        self.loupe = !self.loupe;
+                        return true;
+                    }
+                    "l" => {
+                        let next = match self.aspect {
+                            None => 0,
+                            Some(aspect) => ASPECTS.iter().position(|&a| a == aspect).unwrap() + 1,
+                        };
+                        self.aspect = ASPECTS.get(next).copied();
+                        self.selection = self.selection.map(|rect| self.fit_height(rect));
+                        return true;
+                    }
+                    "d" if self.drag.is_none() => {
+                        self.dimensions = Some(String::new());
+                        return true;
+                    }
+                    "[" => return self.set_width(self.width as i64 - 1),
+                    "]" => return self.set_width(self.width as i64 + 1),
+                    _ => return false,
+                };
+                // Drawing needs a selection to draw on
+                if self.selection.is_some() && self.drag.is_none() {
+                    self.tool = tool;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(keysym: Keysym) -> Event {
+        Event::Key(KeyPress {
+            keysym,
+            text: String::new(),
+            shift: false,
+            ctrl: false,
+            alt: false,
+        })
+    }
+
+    fn button(pressed: bool) -> Event {
+        Event::Button {
+            button: BTN_LEFT,
+            pressed,
+        }
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn locked(aspect: (u32, u32)) -> Editor {
+        let mut editor = Editor::new(&EditorConfig::default());
+        editor.aspect = Some(aspect);
+        editor
+    }
+
+    #[test]
+    fn typed_dimensions() {
+        let editor = Editor::new(&EditorConfig::default());
+        assert_eq!(editor.typed_dimensions("200x100"), Some((200, 100)));
+        // A single number needs a locked aspect ratio for the height
+        assert_eq!(editor.typed_dimensions("200"), None);
+        for junk in [
+            "",
+            "x",
+            "0x100",
+            "100x0",
+            "100x",
+            "x100",
+            "1x2x3",
+            "99999999999x1",
+        ] {
+            assert_eq!(editor.typed_dimensions(junk), None, "{junk:?}");
+        }
+
+        let editor = locked((16, 9));
+        assert_eq!(editor.typed_dimensions("1920"), Some((1920, 1080)));
+        assert_eq!(editor.typed_dimensions("200"), Some((200, 113)));
+        // Both given, the ratio doesn't apply
+        assert_eq!(editor.typed_dimensions("200x200"), Some((200, 200)));
+    }
+
+    #[test]
+    fn typed_dimensions_fit_into_the_outputs() {
+        let editor = locked((16, 9)).with_bounds(rect(-1920, 0, 3840, 1080));
+        // Would overflow an i32 times the ratio
+        assert_eq!(editor.typed_dimensions("2000000000"), Some((1920, 1080)));
+        assert_eq!(editor.typed_dimensions("7680x2160"), Some((3840, 1080)));
+        assert_eq!(editor.typed_dimensions("100x5000"), Some((22, 1080)));
+        assert_eq!(editor.typed_dimensions("640x360"), Some((640, 360)));
+
+        let editor = locked((4, 3));
+        assert_eq!(
+            editor.typed_dimensions("2000000000"),
+            Some((2000000000, 1500000000))
+        );
+        assert_eq!(
+            locked((1, 16)).typed_dimensions("2000000000").unwrap().1,
+            i32::MAX
+        );
+    }
+
+    #[test]
+    fn resizing_keeps_the_aspect_ratio() {
+        let editor = Editor::new(&EditorConfig::default());
+        let selection = rect(10, 10, 100, 50);
+        assert_eq!(
+            editor.resize(selection, (5, 0), false),
+            rect(10, 10, 105, 50)
+        );
+        // The leading edges move, the trailing ones stay
+        assert_eq!(
+            editor.resize(selection, (-5, 0), true),
+            rect(5, 10, 105, 50)
+        );
+        assert_eq!(
+            editor.resize(selection, (0, -100), false),
+            rect(10, 10, 100, 1)
+        );
+
+        let editor = locked((2, 1));
+        assert_eq!(
+            editor.resize(selection, (10, 0), false),
+            rect(10, 10, 110, 55)
+        );
+        assert_eq!(
+            editor.resize(selection, (0, 5), false),
+            rect(10, 10, 110, 55)
+        );
+        assert_eq!(editor.resize(selection, (0, -5), true), rect(0, 5, 110, 55));
+        assert_eq!(
+            editor.resize(selection, (-200, 0), false),
+            rect(10, 10, 1, 1)
+        );
+    }
+
+    #[test]
+    fn saving_needs_a_selection() {
+        let mut editor = Editor::new(&EditorConfig::default());
+        editor.handle(key(Keysym::Return));
+        assert_eq!(editor.finished, None);
+
+        let selection = Rect {
+            x: 10,
+            y: 10,
+            width: 20,
+            height: 20,
+        };
+        let mut editor = Editor::new(&EditorConfig::default()).with_selection(selection);
+        // Starting a new selection outside of the old one, it has no size yet
+        editor.handle(Event::Motion((50.0, 50.0)));
+        editor.handle(button(true));
+        assert_eq!(editor.selection(), None);
+        editor.handle(key(Keysym::Return));
+        editor.handle(Event::Tap((50.0, 50.0)));
+        assert_eq!(editor.finished, None);
+
+        editor.handle(button(false));
+        editor.handle(key(Keysym::Return));
+        assert_eq!(editor.finished, Some(true));
+        assert!(editor.selection().is_some());
+    }
+}
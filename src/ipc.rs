@@ -0,0 +1,62 @@
+//! Window geometry from the IPC of the compositor, so the region selector can snap to windows.
+//!
+//! Every adapter connects to a socket path it is given, which keeps them usable against a fake
+//! server, and returns the windows of the visible workspaces in the global logical coordinate
+//! space [`ScreenData::logical_position`](crate::wayland::types::ScreenData) lives in, topmost
+//! first.
+
+use std::{env, io, path::PathBuf, time::Duration};
+
+use crate::types::Rect;
+
+pub mod hyprland;
+pub mod sway;
+
+/// How long a compositor gets to answer before we go on without windows.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A window on a visible workspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Window {
+    /// In global logical coordinates.
+    pub(crate) rect: Rect,
+    pub(crate) title: String,
+    pub(crate) floating: bool,
+}
+
+/// Puts floating windows in front of tiled ones, keeping the order within both.
+fn stack(mut windows: Vec<Window>) -> Vec<Window> {
+    windows.sort_by_key(|window| !window.floating);
+    windows
+}
+
+/// The windows of whichever supported compositor we run under, empty for the others.
+pub(crate) fn windows() -> Vec<Window> {
+    let result: io::Result<Vec<Window>> = if let Some(socket) = sway::socket() {
+        sway::windows(&socket)
+    } else if let Some(socket) = hyprland::socket() {
+        hyprland::windows(&socket)
+    } else {
+        log::debug!("No compositor IPC found, windows can't be selected");
+        return vec![];
+    };
+    match result {
+        Ok(windows) => {
+            log::debug!("{} windows to snap to", windows.len());
+            windows
+        }
+        Err(err) => {
+            log::warn!("Failed to get the windows from the compositor: {err}");
+            vec![]
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR`, where compositors put their sockets.
+fn runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
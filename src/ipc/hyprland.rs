@@ -0,0 +1,206 @@
+//! The request socket of Hyprland.
+//!
+//! Every request gets its own connection: we write the command, `j/` asking for JSON, and read
+//! the reply until Hyprland closes the socket. Window positions are in the global logical
+//! coordinates.
+
+use std::{
+    collections::HashSet,
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use super::{invalid_data, runtime_dir, stack, Window, TIMEOUT};
+use crate::types::Rect;
+
+/// `.socket.sock` of the running instance, under `$XDG_RUNTIME_DIR/hypr` or `/tmp/hypr` for
+/// older versions.
+pub(crate) fn socket() -> Option<PathBuf> {
+    let signature = env::var_os("HYPRLAND_INSTANCE_SIGNATURE")?;
+    runtime_dir()
+        .into_iter()
+        .chain([PathBuf::from("/tmp")])
+        .map(|dir| dir.join("hypr").join(&signature).join(".socket.sock"))
+        .find(|socket| socket.exists())
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkspaceRef {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Monitor {
+    active_workspace: WorkspaceRef,
+    /// Id 0 when no special workspace is shown.
+    special_workspace: Option<WorkspaceRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Client {
+    mapped: bool,
+    hidden: bool,
+    at: (i32, i32),
+    size: (i32, i32),
+    workspace: WorkspaceRef,
+    floating: bool,
+    title: String,
+}
+
+fn request(socket: &Path, command: &str) -> io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(format!("j/{command}").as_bytes())?;
+    let mut reply = vec![];
+    stream.read_to_end(&mut reply)?;
+    Ok(reply)
+}
+
+/// The windows on visible workspaces, asked from the Hyprland listening on `socket`.
+pub(crate) fn windows(socket: &Path) -> io::Result<Vec<Window>> {
+    let monitors: Vec<Monitor> =
+        serde_json::from_slice(&request(socket, "monitors")?).map_err(invalid_data)?;
+    let visible = monitors
+        .iter()
+        .flat_map(|monitor| {
+            [
+                Some(&monitor.active_workspace),
+                monitor.special_workspace.as_ref(),
+            ]
+        })
+        .flatten()
+        .map(|workspace| workspace.id)
+        .filter(|&id| id != 0)
+        .collect::<HashSet<_>>();
+    let clients: Vec<Client> =
+        serde_json::from_slice(&request(socket, "clients")?).map_err(invalid_data)?;
+
+    let windows = clients
+        .into_iter()
+        .filter(|client| client.mapped && !client.hidden && visible.contains(&client.workspace.id))
+        .filter(|client| client.size.0 > 0 && client.size.1 > 0)
+        .map(|client| Window {
+            rect: Rect {
+                x: client.at.0,
+                y: client.at.1,
+                width: client.size.0,
+                height: client.size.1,
+            },
+            title: client.title,
+            floating: client.floating,
+        })
+        .collect();
+    Ok(stack(windows))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Answers one connection per command of `requests`, in order, closing it after the reply.
+    fn serve(
+        listener: UnixListener,
+        requests: Vec<(&'static str, String)>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for (command, reply) in requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = vec![0; command.len() + 2];
+                stream.read_exact(&mut received).unwrap();
+                assert_eq!(received, format!("j/{command}").as_bytes());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        })
+    }
+
+    fn client(title: &str, at: (i32, i32), size: (i32, i32), workspace: i64) -> serde_json::Value {
+        json!({
+            "mapped": true,
+            "hidden": false,
+            "at": at,
+            "size": size,
+            "workspace": { "id": workspace, "name": workspace.to_string() },
+            "floating": false,
+            "title": title,
+        })
+    }
+
+    #[test]
+    fn windows_of_visible_workspaces() {
+        let monitors = json!([
+            {
+                "name": "eDP-1",
+                "x": 0,
+                "y": 0,
+                "activeWorkspace": { "id": 1, "name": "1" },
+                "specialWorkspace": { "id": 0, "name": "" },
+            },
+            {
+                "name": "HDMI-A-1",
+                "x": 1920,
+                "y": 0,
+                "activeWorkspace": { "id": 2, "name": "2" },
+                "specialWorkspace": { "id": -98, "name": "special:scratchpad" },
+            },
+        ]);
+        let mut floating = client("dialog", (2320, 300), (400, 200), 2);
+        floating["floating"] = json!(true);
+        let mut hidden = client("grouped", (0, 0), (1920, 1080), 1);
+        hidden["hidden"] = json!(true);
+        let mut unmapped = client("closing", (0, 0), (1920, 1080), 1);
+        unmapped["mapped"] = json!(false);
+        // The second monitor sits right of the first, its clients are at global positions
+        let clients = json!([
+            client("editor", (0, 0), (1920, 1080), 1),
+            client("terminal", (1920, 0), (1920, 1080), 2),
+            client("notes", (2000, 100), (800, 600), -98),
+            client("background", (0, 0), (1920, 1080), 3),
+            client("empty", (1920, 0), (0, 0), 2),
+            floating,
+            hidden,
+            unmapped,
+        ]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join(".socket.sock");
+        let server = serve(
+            UnixListener::bind(&socket).unwrap(),
+            vec![
+                ("monitors", monitors.to_string()),
+                ("clients", clients.to_string()),
+            ],
+        );
+        let windows = windows(&socket).unwrap();
+        server.join().unwrap();
+
+        let window = |title: &str, (x, y, width, height), floating| Window {
+            rect: Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            title: title.to_string(),
+            floating,
+        };
+        assert_eq!(
+            windows,
+            [
+                window("dialog", (2320, 300, 400, 200), true),
+                window("editor", (0, 0, 1920, 1080), false),
+                window("terminal", (1920, 0, 1920, 1080), false),
+                window("notes", (2000, 100, 800, 600), false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! The i3 IPC protocol spoken by sway and i3.
+//!
+//! Messages are the `i3-ipc` magic, the payload length and the message type as native endian
+//! 32 bit integers, then the payload. The layout coordinates of sway are the global logical
+//! coordinates already.
+
+use std::{
+    collections::HashSet,
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use super::{invalid_data, stack, Window, TIMEOUT};
+use crate::types::Rect;
+
+const MAGIC: &[u8; 6] = b"i3-ipc";
+const GET_WORKSPACES: u32 = 1;
+const GET_TREE: u32 = 4;
+
+/// `$SWAYSOCK`, or `$I3SOCK` under i3.
+pub(crate) fn socket() -> Option<PathBuf> {
+    env::var_os("SWAYSOCK")
+        .or_else(|| env::var_os("I3SOCK"))
+        .map(PathBuf::from)
+}
+
+#[derive(Debug, Deserialize)]
+struct IpcRect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl From<IpcRect> for Rect {
+    fn from(rect: IpcRect) -> Rect {
+        Rect {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Workspace {
+    name: String,
+    visible: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    rect: IpcRect,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    floating_nodes: Vec<Node>,
+    /// Set for Wayland views under sway.
+    pid: Option<u32>,
+    /// The X11 window, set for every window under i3 and for Xwayland views under sway.
+    window: Option<u32>,
+}
+
+/// Sends a message and reads the reply.
+fn request(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut message = MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(kind.to_ne_bytes());
+    message.extend(payload);
+    stream.write_all(&message)?;
+
+    let mut header = [0; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != MAGIC {
+        return Err(invalid_data("not an i3 IPC reply"));
+    }
+    let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let reply_kind = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    if reply_kind != kind {
+        return Err(invalid_data(format!(
+            "expected a reply of type {kind}, got {reply_kind}"
+        )));
+    }
+    let mut reply = vec![0; length as usize];
+    stream.read_exact(&mut reply)?;
+    Ok(reply)
+}
+
+/// Collects the windows below `node`, skipping workspaces that aren't `visible`.
+fn collect(node: Node, visible: &HashSet<String>, floating: bool, windows: &mut Vec<Window>) {
+    if node.kind == "workspace"
+        && !node
+            .name
+            .as_ref()
+            .is_some_and(|name| visible.contains(name))
+    {
+        return;
+    }
+    let is_window = node.pid.is_some() || node.window.is_some();
+    if is_window && node.nodes.is_empty() && node.rect.width > 0 && node.rect.height > 0 {
+        windows.push(Window {
+            rect: node.rect.into(),
+            title: node.name.unwrap_or_default(),
+            floating,
+        });
+        return;
+    }
+    for child in node.nodes {
+        collect(child, visible, floating, windows);
+    }
+    for child in node.floating_nodes {
+        collect(child, visible, true, windows);
+    }
+}
+
+/// The windows on visible workspaces, asked from the sway or i3 listening on `socket`.
+pub(crate) fn windows(socket: &Path) -> io::Result<Vec<Window>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let workspaces: Vec<Workspace> =
+        serde_json::from_slice(&request(&mut stream, GET_WORKSPACES, &[])?)
+            .map_err(invalid_data)?;
+    let visible = workspaces
+        .into_iter()
+        .filter(|workspace| workspace.visible)
+        .map(|workspace| workspace.name)
+        .collect();
+    let tree: Node =
+        serde_json::from_slice(&request(&mut stream, GET_TREE, &[])?).map_err(invalid_data)?;
+
+    let mut windows = vec![];
+    collect(tree, &visible, false, &mut windows);
+    Ok(stack(windows))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Answers the `requests` in order on a single connection, each with its reply.
+    fn serve(listener: UnixListener, requests: Vec<(u32, String)>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for (kind, reply) in requests {
+                let mut header = [0; 14];
+                stream.read_exact(&mut header).unwrap();
+                assert_eq!(&header[..6], MAGIC);
+                let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+                assert_eq!(length, 0);
+                assert_eq!(u32::from_ne_bytes(header[10..14].try_into().unwrap()), kind);
+
+                let mut message = MAGIC.to_vec();
+                message.extend((reply.len() as u32).to_ne_bytes());
+                message.extend(kind.to_ne_bytes());
+                message.extend(reply.as_bytes());
+                stream.write_all(&message).unwrap();
+            }
+        })
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> serde_json::Value {
+        json!({ "x": x, "y": y, "width": width, "height": height })
+    }
+
+    fn view(name: &str, rect: serde_json::Value) -> serde_json::Value {
+        json!({ "type": "con", "name": name, "rect": rect, "pid": 1 })
+    }
+
+    fn workspace(
+        name: &str,
+        nodes: Vec<serde_json::Value>,
+        floating_nodes: Vec<serde_json::Value>,
+    ) -> serde_json::Value {
+        json!({
+            "type": "workspace",
+            "name": name,
+            "rect": rect(0, 0, 0, 0),
+            "nodes": nodes,
+            "floating_nodes": floating_nodes,
+        })
+    }
+
+    #[test]
+    fn windows_of_visible_workspaces() {
+        let workspaces = json!([
+            { "name": "1", "visible": true },
+            { "name": "2", "visible": true },
+            { "name": "3", "visible": false },
+        ]);
+        // A second output right of the first, its windows keep their layout coordinates
+        let tree = json!({
+            "type": "root",
+            "name": "root",
+            "rect": rect(0, 0, 3840, 1080),
+            "nodes": [
+                {
+                    "type": "output",
+                    "name": "eDP-1",
+                    "rect": rect(0, 0, 1920, 1080),
+                    "nodes": [
+                        workspace("1", vec![view("editor", rect(0, 0, 1920, 1080))], vec![]),
+                        workspace("3", vec![view("hidden", rect(0, 0, 1920, 1080))], vec![]),
+                    ],
+                },
+                {
+                    "type": "output",
+                    "name": "HDMI-A-1",
+                    "rect": rect(1920, 0, 1920, 1080),
+                    "nodes": [workspace(
+                        "2",
+                        vec![
+                            view("terminal", rect(1920, 0, 1920, 1080)),
+                            view("minimized", rect(1920, 0, 0, 0)),
+                        ],
+                        vec![view("dialog", rect(2320, 300, 400, 200))],
+                    )],
+                },
+            ],
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sway.sock");
+        let server = serve(
+            UnixListener::bind(&socket).unwrap(),
+            vec![
+                (GET_WORKSPACES, workspaces.to_string()),
+                (GET_TREE, tree.to_string()),
+            ],
+        );
+        let windows = windows(&socket).unwrap();
+        server.join().unwrap();
+
+        let window = |title: &str, (x, y, width, height), floating| Window {
+            rect: Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            title: title.to_string(),
+            floating,
+        };
+        assert_eq!(
+            windows,
+            [
+                window("dialog", (2320, 300, 400, 200), true),
+                window("editor", (0, 0, 1920, 1080), false),
+                window("terminal", (1920, 0, 1920, 1080), false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! The region selected last, kept in `$XDG_STATE_HOME/screen_capturer/last_region.json` so
+//! `region --last-region` can capture it again.
+//!
+//! The outputs under the selection get saved with it: once they are unplugged, moved or
+//! rescaled, the same coordinates would show something else.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{stitch::logical_rect, types::Rect, wayland::types::ScreenData};
+
+/// An output as it was laid out when the region got selected.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct OutputLayout {
+    /// The connector name, matched when the compositor sends it.
+    name: Option<String>,
+    logical_position: (i32, i32),
+    logical_resolution: (i32, i32),
+}
+
+impl OutputLayout {
+    fn new(screen: &ScreenData) -> OutputLayout {
+        OutputLayout {
+            name: screen.connector.clone(),
+            logical_position: screen.logical_position,
+            logical_resolution: screen.logical_resolution,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LastRegion {
+    /// In global logical coordinates.
+    pub(crate) selection: Rect,
+    /// The outputs the selection covered.
+    outputs: Vec<OutputLayout>,
+}
+
+impl LastRegion {
+    /// Whether every output the selection covered is still there, in the same place and size.
+    pub(crate) fn matches(&self, screens: &[&ScreenData]) -> bool {
+        !self.outputs.is_empty()
+            && self.outputs.iter().all(|output| {
+                screens
+                    .iter()
+                    .any(|screen| OutputLayout::new(screen) == *output)
+            })
+    }
+
+    /// Whether any part of the selection is on one of `screens`.
+    pub(crate) fn visible(&self, screens: &[&ScreenData]) -> bool {
+        screens
+            .iter()
+            .any(|screen| self.selection.intersection(&logical_rect(screen)).is_some())
+    }
+}
+
+fn state_path() -> Option<PathBuf> {
+    Some(
+        dirs::state_dir()?
+            .join("screen_capturer")
+            .join("last_region.json"),
+    )
+}
+
+/// Reads the saved region, `None` when there is none or it can't be read.
+pub(crate) fn load() -> Option<LastRegion> {
+    load_from(&state_path()?)
+}
+
+fn load_from(path: &Path) -> Option<LastRegion> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            log::warn!("Failed to read {}: {err}", path.display());
+            return None;
+        }
+    };
+    serde_json::from_slice(&contents)
+        .map_err(|err| log::warn!("Ignoring invalid {}: {err}", path.display()))
+        .ok()
+}
+
+/// Remembers `selection` along with the `screens` it covers. Failing only gets logged, the
+/// capture itself worked.
+pub(crate) fn save(selection: Rect, screens: &[&ScreenData]) {
+    let Some(path) = state_path() else {
+        log::warn!("No state directory, the region won't be remembered");
+        return;
+    };
+    save_to(&path, selection, screens);
+}
+
+fn save_to(path: &Path, selection: Rect, screens: &[&ScreenData]) {
+    let last = LastRegion {
+        selection,
+        outputs: screens
+            .iter()
+            .filter(|screen| selection.intersection(&logical_rect(screen)).is_some())
+            .map(|screen| OutputLayout::new(screen))
+            .collect(),
+    };
+    let written = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|()| fs::write(path, serde_json::to_vec_pretty(&last).unwrap()));
+    match written {
+        Ok(()) => log::debug!("Remembered the region in {}", path.display()),
+        Err(err) => log::warn!("Failed to write {}: {err}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(connector: &str, position: (i32, i32), size: (i32, i32)) -> ScreenData {
+        ScreenData {
+            name: 1,
+            connector: Some(connector.to_string()),
+            resolution: size,
+            logical_resolution: size,
+            logical_position: position,
+        }
+    }
+
+    fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn saved_region_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("last_region.json");
+        assert!(load_from(&path).is_none());
+
+        let left = screen("eDP-1", (0, 0), (1920, 1080));
+        let right = screen("DP-1", (1920, 0), (2560, 1440));
+        let selection = rect(100, 100, 200, 200);
+        save_to(&path, selection, &[&left, &right]);
+        let last = load_from(&path).unwrap();
+        assert_eq!(last.selection, selection);
+        // Only the outputs under the selection are remembered
+        assert_eq!(last.outputs, [OutputLayout::new(&left)]);
+
+        fs::write(&path, "{").unwrap();
+        assert!(load_from(&path).is_none());
+    }
+
+    #[test]
+    fn matches_the_outputs_it_was_selected_on() {
+        let left = screen("eDP-1", (0, 0), (1920, 1080));
+        let right = screen("DP-1", (1920, 0), (2560, 1440));
+        let last = LastRegion {
+            selection: rect(1800, 100, 200, 200),
+            outputs: vec![OutputLayout::new(&left), OutputLayout::new(&right)],
+        };
+        assert!(last.matches(&[&left, &right]));
+        assert!(last.matches(&[&right, &screen("HDMI-A-1", (-1920, 0), (1920, 1080)), &left]));
+
+        // Unplugged, moved, rescaled or renamed
+        assert!(!last.matches(&[&left]));
+        let moved = screen("DP-1", (0, 1080), (2560, 1440));
+        assert!(!last.matches(&[&left, &moved]));
+        let rescaled = screen("DP-1", (1920, 0), (1707, 960));
+        assert!(!last.matches(&[&left, &rescaled]));
+        let renamed = screen("DP-2", (1920, 0), (2560, 1440));
+        assert!(!last.matches(&[&left, &renamed]));
+
+        // Nothing remembered never matches
+        let empty = LastRegion {
+            selection: rect(0, 0, 10, 10),
+            outputs: vec![],
+        };
+        assert!(!empty.matches(&[&left]));
+    }
+
+    #[test]
+    fn visible_on_any_output() {
+        let left = screen("eDP-1", (0, 0), (1920, 1080));
+        let last = |selection| LastRegion {
+            selection,
+            outputs: vec![],
+        };
+        assert!(last(rect(1900, 1000, 100, 100)).visible(&[&left]));
+        // Touching the edge isn't on it
+        assert!(!last(rect(1920, 0, 100, 100)).visible(&[&left]));
+        assert!(!last(rect(-100, -100, 50, 50)).visible(&[&left]));
+        assert!(!last(rect(0, 0, 10, 10)).visible(&[]));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    sync::Mutex,
+    time::Instant,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::cli::LogArgs;
+
+struct Logger {
+    level: LevelFilter,
+    start: Instant,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies only get to speak up when something went wrong
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        metadata.level() <= self.level && (ours || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut output = self.output.lock().unwrap();
+        let _ = writeln!(
+            output,
+            "[{elapsed:>8.3}s {:<5}] {}",
+            record.level(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.output.lock().unwrap().flush();
+    }
+}
+
+/// Installs the global logger, everything goes to stderr unless `--log-file` is given since
+/// stdout may carry image data.
+pub(crate) fn init(args: &LogArgs) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        // Also dumps the Wayland events we receive
+        (false, _) => LevelFilter::Trace,
+    };
+    let output: Box<dyn Write + Send> = match &args.log_file {
+        Some(path) => Box::new(File::create(path).expect("Failed to create log file")),
+        None => Box::new(io::stderr()),
+    };
+
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        start: Instant::now(),
+        output: Mutex::new(output),
+    }))
+    .unwrap();
+    log::set_max_level(level);
+}
+
+/// Logs how long a phase of the program took once it goes out of scope.
+pub(crate) struct Span {
+    name: &'static str,
+    level: Level,
+    start: Instant,
+}
+
+impl Span {
+    pub(crate) fn new(level: Level, name: &'static str) -> Span {
+        log::debug!("{name} started");
+        Span {
+            name,
+            level,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        log::log!(self.level, "{} took {:?}", self.name, self.start.elapsed());
+    }
+}
@@ -0,0 +1,137 @@
+//! A magnifier next to the pointer, showing the captured pixels under it.
+
+use image::RgbaImage;
+
+use crate::{
+    annotate::{self, contrasting, Point, Target, View},
+    config::Color,
+    stitch::logical_rect,
+    wayland::types::ScreenData,
+};
+
+/// Captured pixels shown on each side of the one under the pointer.
+const RADIUS: i32 = 7;
+/// Size of a magnified pixel, in logical pixels.
+const CELL: f64 = 8.0;
+/// Distance between the pointer and the loupe, in logical pixels.
+const OFFSET: f64 = 24.0;
+/// Font pixels of the coordinates below the loupe, in logical pixels.
+const LABEL_PIXEL: f64 = 1.5;
+
+/// The output under `pointer` and the physical pixel of its capture there.
+pub(crate) fn pixel_at(
+    pointer: Point,
+    captures: &[(ScreenData, RgbaImage)],
+) -> Option<(&ScreenData, &RgbaImage, (i32, i32))> {
+    captures.iter().find_map(|(screen, capture)| {
+        let output = logical_rect(screen);
+        let (x, y) = (pointer.0 - output.x as f64, pointer.1 - output.y as f64);
+        if x < 0.0 || y < 0.0 || x >= output.width as f64 || y >= output.height as f64 {
+            return None;
+        }
+        let (width, height) = capture.dimensions();
+        let physical = (
+            (x * width as f64 / output.width as f64) as i32,
+            (y * height as f64 / output.height as f64) as i32,
+        );
+        Some((screen, capture, physical))
+    })
+}
+
+/// Draws the loupe for `pointer`, flipped to the other side of it near the edges of the output.
+pub(crate) fn draw(
+    target: &mut dyn Target,
+    view: View,
+    pointer: Point,
+    captures: &[(ScreenData, RgbaImage)],
+) {
+    let Some((screen, capture, (pixel_x, pixel_y))) = pixel_at(pointer, captures) else {
+        return;
+    };
+
+    let size = (RADIUS * 2 + 1) as f64 * CELL;
+    let label = format!(
+        "{}, {}  ({pixel_x}, {pixel_y} on {})",
+        pointer.0.floor(),
+        pointer.1.floor(),
+        screen.name
+    );
+    let (label_width, label_height) = annotate::text_size(&label, LABEL_PIXEL);
+    let padding = LABEL_PIXEL * 2.0;
+    let (width, height) = (
+        size.max(label_width + padding * 2.0),
+        size + label_height + padding * 2.0,
+    );
+
+    let output = logical_rect(screen);
+    let mut x = pointer.0 + OFFSET;
+    if x + width > (output.x + output.width) as f64 {
+        x = pointer.0 - OFFSET - width;
+    }
+    let mut y = pointer.1 + OFFSET;
+    if y + height > (output.y + output.height) as f64 {
+        y = pointer.1 - OFFSET - height;
+    }
+
+    let background = Color([0, 0, 0, 230]);
+    annotate::draw_box(target, view, (x, y), (x + width, y + height), background);
+    for row in -RADIUS..=RADIUS {
+        for column in -RADIUS..=RADIUS {
+            let (source_x, source_y) = (pixel_x + column, pixel_y + row);
+            let in_capture = source_x >= 0
+                && source_y >= 0
+                && (source_x as u32) < capture.width()
+                && (source_y as u32) < capture.height();
+            if !in_capture {
+                continue;
+            }
+            let mut color = Color(capture.get_pixel(source_x as u32, source_y as u32).0);
+            // The overlay is see-through, show what got captured
+            color.0[3] = 255;
+            let min = (
+                x + (column + RADIUS) as f64 * CELL,
+                y + (row + RADIUS) as f64 * CELL,
+            );
+            // Leaving out the last logical pixel of every cell draws the grid
+            let max = (min.0 + CELL - 1.0, min.1 + CELL - 1.0);
+            annotate::draw_box(target, view, min, max, color);
+        }
+    }
+
+    // Crosshair around the pixel under the pointer
+    let center = (x + RADIUS as f64 * CELL, y + RADIUS as f64 * CELL);
+    let under = capture
+        .get_pixel_checked(pixel_x as u32, pixel_y as u32)
+        .map_or(Color([0, 0, 0, 255]), |pixel| Color(pixel.0));
+    let crosshair = contrasting(under);
+    let edges = [
+        (
+            (center.0 - 1.0, center.1 - 1.0),
+            (center.0 + CELL, center.1),
+        ),
+        (
+            (center.0 - 1.0, center.1 + CELL - 1.0),
+            (center.0 + CELL, center.1 + CELL),
+        ),
+        (
+            (center.0 - 1.0, center.1),
+            (center.0, center.1 + CELL - 1.0),
+        ),
+        (
+            (center.0 + CELL - 1.0, center.1),
+            (center.0 + CELL, center.1 + CELL - 1.0),
+        ),
+    ];
+    for (min, max) in edges {
+        annotate::draw_box(target, view, min, max, crosshair);
+    }
+
+    annotate::draw_text(
+        target,
+        view,
+        (x + padding, y + size + padding),
+        &label,
+        LABEL_PIXEL,
+        Color([255, 255, 255, 255]),
+    );
+}
@@ -1,23 +1,48 @@
-use image::ImageFormat;
+use clap::Parser;
+use cli::{Cli, Command, Depth, EncodingFormat, FullArgs, OutputTarget, ResizeFilter, SaveScale};
+use config::Theme;
+use image::{DynamicImage, ImageFormat};
+use log::Level;
+use logging::Span;
+use redact::Redaction;
 use std::{
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, Cursor, Read, Write},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    process,
+    sync::Arc,
     thread,
-    time::SystemTime,
 };
-use types::BuffersStore;
+use stitch::{capture_scale, logical_rect};
 use wayland::{
     create_popup, filter_unfocused_popups, screenshot,
-    types::{Delegate, Popup},
+    types::{FrameFormat, ScreenData},
     WaylandVarsNew,
 };
-use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
 
 pub mod types;
 pub mod wayland;
 
-mod test;
+mod annotate;
+mod canvas;
+mod cli;
+mod clipboard;
+mod config;
+mod daemon;
+mod editor;
+mod font;
+mod ipc;
+mod last_region;
+mod logging;
+mod loupe;
+mod measure;
+mod overlay;
+mod pick;
+mod pixel_format;
+mod preview;
+mod record;
+mod redact;
+mod region;
+mod stitch;
 mod wayland_data;
 mod wayland_fractional_scale;
 pub mod wayland_screencopy;
@@ -32,17 +57,59 @@ enum ScreenshotType {
 }
 
 struct Settings {
-    background: [u8; 4],
+    theme: Theme,
     path: PathBuf,
 }
 
 fn main() {
-    let time = SystemTime::now();
+    let cli = Cli::parse();
+    logging::init(&cli.log);
+
+    let full_args = match cli.command {
+        None => FullArgs::default(),
+        Some(Command::Full(args)) => args,
+        Some(Command::Region(args)) => {
+            region::run(&args);
+            return;
+        }
+        Some(Command::Pick(args)) => {
+            pick::run(&args);
+            return;
+        }
+        Some(Command::Measure(args)) => {
+            measure::run(&args);
+            return;
+        }
+        Some(Command::Record(args)) => {
+            let mut wayland_vars = wayland::init();
+            if let Err(err) = record::record(&mut wayland_vars, &args) {
+                log::error!("Failed to write frame: {err}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Daemon(args)) => {
+            daemon::run(&args);
+            return;
+        }
+        Some(Command::Client(args)) => {
+            daemon::client(&args);
+            return;
+        }
+        Some(Command::ServeClipboard(args)) => {
+            clipboard::serve(&args);
+            return;
+        }
+    };
+    let _total = Span::new(Level::Info, "Screenshot");
     let screenshot_type = ScreenshotType::Fullscreen {
-        single_monitor: false,
+        single_monitor: full_args.single_monitor,
     };
+    let options = full_args.save.options();
+    let (format, high_depth) = (options.format, options.high_depth);
+    let config = config::load();
     let settings = Settings {
-        background: [5, 5, 5, 80],
+        theme: config.theme.clone(),
         #[cfg(target_os = "linux")]
         path: dirs::home_dir()
             .expect("Home dir not found")
@@ -54,10 +121,23 @@ fn main() {
 
     // Create and save the screenshot with the screen info
     let screenshots_file = tempfile::tempfile().unwrap();
-    let mut screenshots_data = screenshot(&mut wayland_vars, screenshots_file);
+    let mut screenshots_data = {
+        let _span = Span::new(Level::Debug, "Screencopy");
+        screenshot(&mut wayland_vars, screenshots_file)
+    };
+    let deep_capture = screenshots_data
+        .buffers_metadata
+        .iter()
+        .any(|screen| pixel_format::bit_depth(screen.format.format).is_some_and(|bits| bits > 8));
+    if deep_capture && full_args.save.depth == Depth::Auto && !high_depth {
+        log::warn!("{format:?} only stores 8 bits per channel, use PNG or TIFF to keep them all");
+    }
 
-    println!("Creating popups");
-    let mut popups = create_popup(&mut wayland_vars, &screenshots_data);
+    log::info!("Creating popups");
+    let mut popups = {
+        let _span = Span::new(Level::Debug, "Creating popups");
+        create_popup(&mut wayland_vars, &screenshots_data)
+    };
 
     if matches!(screenshot_type, ScreenshotType::Fullscreen { single_monitor } if single_monitor) {
         filter_unfocused_popups(&mut popups, &mut wayland_vars);
@@ -68,50 +148,19 @@ fn main() {
         // Draw
         match screenshot_type {
             ScreenshotType::Fullscreen { single_monitor } => {
+                // Only the hovered output is left, highlight it as the one being captured
                 if single_monitor {
-                    filter_unfocused_popups(&mut popups, &mut wayland_vars);
+                    let hovered = popups
+                        .buffers_metadata
+                        .first()
+                        .map(|popup| logical_rect(&popup.screen_data));
+                    overlay::select(&mut popups, hovered);
                 }
-                draw_background(&mut popups, settings.background);
+                overlay::show(&mut popups, &mut wayland_vars, &settings.theme);
             }
             ScreenshotType::Window => todo!(),
         }
-        // Show the popup
-        popups.buffers_metadata.iter().for_each(|a| {
-            a.surface.attach(Some(&a.buffer), 0, 0);
-            a.surface.commit();
-        });
-        wayland_vars
-            .event_queue
-            .blocking_dispatch(&mut Delegate)
-            .unwrap();
-    }
-    #[cfg(target_os = "linux")]
-    {
-        let qh = &wayland_vars.qh;
-        let screencopy_manager: ZwlrScreencopyManagerV1 =
-            wayland_vars.globals.bind(qh, 1..=1, ()).unwrap();
-
-        let screencopying_counter = Arc::new(Mutex::new(0u8));
-        popups.buffers_metadata.iter().for_each(|e| {
-            e.screen_data.wayland_data.screencopy(
-                screencopying_counter.clone(),
-                qh,
-                &screencopy_manager,
-            )
-        });
-
-        loop {
-            wayland_vars
-                .event_queue
-                .blocking_dispatch(&mut Delegate)
-                .unwrap();
-
-            if *screencopying_counter.lock().unwrap() == 0 {
-                break;
-            }
-        }
     }
-
     // Save the screenshot
     let mut screens_buf = Vec::with_capacity(screenshots_data.file_len);
     screenshots_data
@@ -122,68 +171,178 @@ fn main() {
     // println!("{:?}", screens_buf);
 
     // Save image
+    let _span = Span::new(Level::Debug, "Saving");
     match screenshot_type {
         ScreenshotType::Fullscreen { .. } => {
+            // Outputs plugged in while the overlay was shown have nothing to save
+            let captures = popups
+                .buffers_metadata
+                .iter()
+                .filter_map(|popup| popup.capture.as_ref())
+                .collect::<Vec<_>>();
+            let image_count = captures.len();
+            if full_args.save.output == Some(OutputTarget::Stdout) && image_count > 1 {
+                log::error!("Only a single image can be written to stdout, use --single-monitor");
+                process::exit(1);
+            }
+
             let mut image_num = 0;
             let test = Arc::new(screens_buf);
+            let redactions =
+                redact::from_regions(&full_args.save.redact, full_args.save.redact_style);
 
-            let handles = popups
-                .buffers_metadata
+            let handles = captures
                 .iter()
-                .map(|e| {
-                    let screenshot_data = &e.screen_data;
-
+                .map(|screenshot_data| {
                     let pixels = test.clone();
                     let offset = screenshot_data.offset;
                     let span = screenshot_data.span;
-                    let resolution = screenshot_data.screen_data.resolution;
-                    let path = settings.path.join(format!("output{}.webp", image_num));
+                    let frame_format = screenshot_data.format;
+                    let screen = screenshot_data.screen_data.clone();
+                    let redactions = redactions.clone();
+                    let target = match &full_args.save.output {
+                        Some(OutputTarget::Path(path)) if image_count > 1 => {
+                            OutputTarget::Path(numbered_path(path, image_num))
+                        }
+                        Some(target) => target.clone(),
+                        None => OutputTarget::Path(settings.path.join(format!(
+                            "output{}.{}",
+                            image_num,
+                            format.extensions_str()[0]
+                        ))),
+                    };
                     image_num += 1;
-                    println!("Saving");
+                    let preview = full_args.preview;
 
-                    thread::spawn(move || save_image(resolution, &pixels[offset..span], &path))
+                    thread::spawn(move || {
+                        let img = render_image(
+                            frame_format,
+                            &pixels[offset..span],
+                            &screen,
+                            &redactions,
+                            options,
+                        );
+                        // Previews get shown one after the other once every image is ready
+                        if preview {
+                            let pixels_per_logical =
+                                img.width() as f64 / screen.logical_resolution.0 as f64;
+                            return Some((img, pixels_per_logical, target));
+                        }
+                        log::info!("Saving {target}");
+                        write_image(&img, &target, options.format);
+                        None
+                    })
                 })
                 .collect::<Vec<_>>();
 
-            for handle in handles {
-                handle.join().unwrap();
-            }
+            let previews = handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>();
+            // Every output gets reviewed before discarding or capturing again ends the process
+            let outcomes = previews
+                .into_iter()
+                .map(|(img, pixels_per_logical, target)| {
+                    preview::review(img, pixels_per_logical, &target, format, &config)
+                })
+                .collect::<Vec<_>>();
+            preview::conclude(&outcomes);
         }
         ScreenshotType::Window => todo!(),
     }
-    println!("{:?}", time.elapsed());
 }
 
-fn save_image((width, height): (i32, i32), pixels: &[u8], path: &Path) {
-    let img = image::ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
-        let index = (y * width as u32 + x) as usize * 4;
+/// Picks the requested encoding, falling back to the output extension and then WebP.
+pub(crate) fn image_format(
+    format: Option<EncodingFormat>,
+    output: Option<&OutputTarget>,
+) -> ImageFormat {
+    if let Some(format) = format {
+        return format.image_format();
+    }
+    match output {
+        Some(OutputTarget::Path(path)) => ImageFormat::from_path(path).unwrap_or(ImageFormat::WebP),
+        _ => EncodingFormat::Webp.image_format(),
+    }
+}
 
-        let r = pixels[index + 2];
-        let g = pixels[index + 1];
-        let b = pixels[index];
+/// `shot.png` becomes `shot1.png` when several outputs get saved.
+pub(crate) fn numbered_path(path: &Path, num: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{stem}{num}");
+    if let Some(extension) = path.extension() {
+        file_name = format!("{file_name}.{}", extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
 
-        image::Rgb([r, g, b])
-    });
+/// How captured pixels get turned into an image file.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SaveOptions {
+    pub(crate) format: ImageFormat,
+    /// Keep more than 8 bits per channel, see [`Depth::keeps_high_depth`].
+    pub(crate) high_depth: bool,
+    pub(crate) scale: SaveScale,
+    pub(crate) filter: ResizeFilter,
+}
 
-    //println!("{:?}", img);
+/// Turns the capture of `screen` into the image that gets saved.
+fn render_image(
+    frame_format: FrameFormat,
+    pixels: &[u8],
+    screen: &ScreenData,
+    redactions: &[Redaction],
+    options: SaveOptions,
+) -> DynamicImage {
+    let mut img =
+        pixel_format::to_image(frame_format, pixels, options.high_depth).unwrap_or_else(|err| {
+            log::error!("{err}");
+            process::exit(1);
+        });
+    redact::redact_output(&mut img, screen, redactions);
+    let captured = (img.width(), img.height());
+    scale_image(img, screen, captured, &options)
+}
 
-    println!("Compressing");
-    img.save_with_format(path, ImageFormat::WebP)
-        .expect("Failed to save image");
-    println!("Finished compressing");
+pub(crate) fn write_image(img: &DynamicImage, target: &OutputTarget, format: ImageFormat) {
+    let _span = Span::new(Level::Debug, "Compressing");
+    match target {
+        OutputTarget::Path(path) => img
+            .save_with_format(path, format)
+            .expect("Failed to save image"),
+        OutputTarget::Stdout => io::stdout()
+            .lock()
+            .write_all(&encode_image(img, format))
+            .expect("Failed to write image to stdout"),
+    }
 }
 
-pub(crate) fn draw_background(popups_mem: &mut BuffersStore<Popup>, background: [u8; 4]) {
-    for popup in popups_mem.buffers_metadata.iter() {
-        popups_mem
-            .buffer_file
-            .seek(SeekFrom::Start(popup.screen_data.offset as u64))
-            .unwrap();
+/// Resizes an image of `screen`, or of a part of it, to the scale asked for in `options`.
+/// `captured` is the size of the capture of the whole output the image came from.
+pub(crate) fn scale_image(
+    img: DynamicImage,
+    screen: &ScreenData,
+    captured: (u32, u32),
+    options: &SaveOptions,
+) -> DynamicImage {
+    let SaveScale::Logical(factor) = options.scale else {
+        return img;
+    };
+    let (scale_x, scale_y) = capture_scale(screen, captured);
+    let scaled = |length: u32, scale: f64| (length as f64 / scale * factor).round().max(1.0) as u32;
+    let (new_width, new_height) = (scaled(img.width(), scale_x), scaled(img.height(), scale_y));
+    if (new_width, new_height) == (img.width(), img.height()) {
+        return img;
+    }
 
-        let cashed = u32::from_ne_bytes(background);
-        let compressed_buf = vec![cashed; popup.screen_data.span / 4];
-        let uncompressed_buf = bytemuck::cast_slice::<u32, u8>(&compressed_buf[..]);
+    let _span = Span::new(Level::Debug, "Scaling");
+    img.resize_exact(new_width, new_height, options.filter.filter_type())
+}
 
-        popups_mem.buffer_file.write_all(uncompressed_buf).unwrap();
-    }
+pub(crate) fn encode_image(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    // Encoders want to seek, so buffer the whole image first
+    let mut encoded = Cursor::new(vec![]);
+    img.write_to(&mut encoded, format)
+        .expect("Failed to encode image");
+    encoded.into_inner()
 }
@@ -0,0 +1,476 @@
+//! The `measure` command: drag boxes and lines on the overlay to measure what is on screen.
+//!
+//! Sizes are shown in logical pixels and in physical pixels of the output the measurement starts
+//! on. The pointer snaps to edges found in the capture, and `Enter` prints every measurement.
+
+use std::{io::Read, process};
+
+use image::RgbaImage;
+use log::Level;
+use xkbcommon::xkb::Keysym;
+
+use crate::{
+    annotate::{self, Annotation, Point, Shape, View},
+    canvas::Canvas,
+    cli::MeasureArgs,
+    config::{self, Color, Theme},
+    logging::Span,
+    loupe,
+    overlay::{self, popup_view, Event, Interaction, BTN_LEFT},
+    region::previews,
+    stitch::{capture_scale, logical_rect},
+    types::Rect,
+    wayland::{
+        self, create_popup, screenshot,
+        types::{Popup, ScreenData},
+    },
+};
+
+/// Distance the pointer snaps to edges from, in logical pixels.
+const SNAP_DISTANCE: f64 = 6.0;
+/// Sum of the channel differences between neighbouring pixels that makes an edge.
+const EDGE_THRESHOLD: u32 = 48;
+/// Font pixels of the labels next to the measurements, in logical pixels.
+const LABEL_PIXEL: f64 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Box,
+    Line,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    kind: Kind,
+    from: Point,
+    to: Point,
+}
+
+/// Whole numbers without decimals, anything else with up to two.
+fn number(value: f64) -> String {
+    // Adding zero turns the -0 that small negative values round to into 0
+    let rounded = (value * 100.0).round() / 100.0 + 0.0;
+    if rounded.fract() == 0.0 {
+        format!("{rounded:.0}")
+    } else {
+        format!("{rounded}")
+    }
+}
+
+impl Measurement {
+    /// One line describing the measurement, physical sizes taken from the output under `from`.
+    fn describe(&self, captures: &[(ScreenData, RgbaImage)]) -> String {
+        let (from, to) = (self.from, self.to);
+        let (dx, dy) = ((to.0 - from.0).abs(), (to.1 - from.1).abs());
+        let physical = loupe::pixel_at(from, captures)
+            .map(|(screen, capture, _)| (screen.name, capture_scale(screen, capture.dimensions())));
+        match self.kind {
+            Kind::Box => {
+                let text = format!(
+                    "box {}x{} at {},{}",
+                    number(dx),
+                    number(dy),
+                    number(from.0.min(to.0)),
+                    number(from.1.min(to.1))
+                );
+                match physical {
+                    Some((name, (scale_x, scale_y))) => format!(
+                        "{text} ({}x{} physical on output {name})",
+                        number(dx * scale_x),
+                        number(dy * scale_y)
+                    ),
+                    None => text,
+                }
+            }
+            Kind::Line => {
+                let text = format!(
+                    "line {} (dx {}, dy {}) from {},{} to {},{}",
+                    number(dx.hypot(dy)),
+                    number(dx),
+                    number(dy),
+                    number(from.0),
+                    number(from.1),
+                    number(to.0),
+                    number(to.1)
+                );
+                match physical {
+                    Some((name, (scale_x, scale_y))) => format!(
+                        "{text} ({} physical on output {name})",
+                        number((dx * scale_x).hypot(dy * scale_y))
+                    ),
+                    None => text,
+                }
+            }
+        }
+    }
+
+    fn shape(&self) -> Shape {
+        match self.kind {
+            Kind::Box => Shape::Rectangle {
+                from: self.from,
+                to: self.to,
+            },
+            Kind::Line => Shape::Freehand(vec![self.from, self.to]),
+        }
+    }
+}
+
+/// The pixel boundary closest to `at` within `radius` where neighbouring pixels differ, boundary
+/// `b` lying between the pixels `b - 1` and `b` of a row or column `length` pixels long.
+fn nearest_edge(at: f64, radius: f64, length: u32, differ: impl Fn(u32) -> bool) -> Option<f64> {
+    let first = ((at - radius).ceil().max(1.0)) as u32;
+    let last = ((at + radius).floor() as i64).min(length as i64 - 1);
+    (first as i64..=last)
+        .map(|boundary| boundary as u32)
+        .filter(|&boundary| differ(boundary))
+        .map(|boundary| boundary as f64)
+        .min_by(|a, b| (a - at).abs().total_cmp(&(b - at).abs()))
+}
+
+/// Moves `pointer` onto the closest edges in the capture under it, separately on each axis.
+fn snap(pointer: Point, captures: &[(ScreenData, RgbaImage)]) -> Point {
+    let Some((screen, capture, (pixel_x, pixel_y))) = loupe::pixel_at(pointer, captures) else {
+        return pointer;
+    };
+    let output = logical_rect(screen);
+    let scale = capture_scale(screen, capture.dimensions());
+    let at = (
+        (pointer.0 - output.x as f64) * scale.0,
+        (pointer.1 - output.y as f64) * scale.1,
+    );
+    let differ = |a: (u32, u32), b: (u32, u32)| {
+        let (Some(a), Some(b)) = (
+            capture.get_pixel_checked(a.0, a.1),
+            capture.get_pixel_checked(b.0, b.1),
+        ) else {
+            return false;
+        };
+        let distance: u32 = (0..3)
+            .map(|channel| a[channel].abs_diff(b[channel]) as u32)
+            .sum();
+        distance >= EDGE_THRESHOLD
+    };
+
+    let (row, column) = (pixel_y as u32, pixel_x as u32);
+    let x = nearest_edge(at.0, SNAP_DISTANCE * scale.0, capture.width(), |x| {
+        differ((x - 1, row), (x, row))
+    });
+    let y = nearest_edge(at.1, SNAP_DISTANCE * scale.1, capture.height(), |y| {
+        differ((column, y - 1), (column, y))
+    });
+    (
+        x.map_or(pointer.0, |x| output.x as f64 + x / scale.0),
+        y.map_or(pointer.1, |y| output.y as f64 + y / scale.1),
+    )
+}
+
+struct Measure<'a> {
+    captures: &'a [(ScreenData, RgbaImage)],
+    kind: Kind,
+    snapping: bool,
+    loupe: bool,
+    /// Where the pointer really is, [`Measure::pointer`] snaps it.
+    raw_pointer: Point,
+    pointer: Point,
+    /// Start of the measurement being dragged.
+    drag: Option<Point>,
+    done: Vec<Measurement>,
+    finished: Option<bool>,
+}
+
+impl Measure<'_> {
+    fn current(&self) -> Option<Measurement> {
+        self.drag.map(|from| Measurement {
+            kind: self.kind,
+            from,
+            to: self.pointer,
+        })
+    }
+
+    fn move_pointer(&mut self, position: Point) {
+        self.raw_pointer = position;
+        self.pointer = if self.snapping {
+            snap(position, self.captures)
+        } else {
+            position
+        };
+    }
+
+    fn status(&self) -> String {
+        if let Some(current) = self.current() {
+            return current.describe(self.captures);
+        }
+        format!(
+            "{} | Tab:box/line s:snapping {} m:loupe Backspace:undo Enter:print Esc:cancel",
+            match self.kind {
+                Kind::Box => "Drag a box",
+                Kind::Line => "Drag a line",
+            },
+            if self.snapping { "on" } else { "off" },
+        )
+    }
+}
+
+/// Draws `text` on a dark background with its top left corner at `at`.
+fn draw_label(canvas: &mut Canvas, view: View, at: Point, text: &str) {
+    let (width, height) = annotate::text_size(text, LABEL_PIXEL);
+    let padding = LABEL_PIXEL * 2.0;
+    annotate::draw_box(
+        canvas,
+        view,
+        at,
+        (at.0 + width + padding * 2.0, at.1 + height + padding * 2.0),
+        Color([0, 0, 0, 200]),
+    );
+    annotate::draw_text(
+        canvas,
+        view,
+        (at.0 + padding, at.1 + padding),
+        text,
+        LABEL_PIXEL,
+        Color([255, 255, 255, 255]),
+    );
+}
+
+impl Interaction for Measure<'_> {
+    fn handle(&mut self, event: Event) -> bool {
+        match event {
+            Event::Motion(position) => {
+                self.move_pointer(position);
+                true
+            }
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: true,
+            } => {
+                self.drag = Some(self.pointer);
+                true
+            }
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: false,
+            } => match self.current() {
+                Some(measurement) => {
+                    self.drag = None;
+                    if measurement.from != measurement.to {
+                        self.done.push(measurement);
+                    }
+                    true
+                }
+                None => false,
+            },
+            Event::Button { .. } | Event::Tap(_) | Event::Scroll(_) | Event::KeyRelease(_) => false,
+            Event::Key(key) => {
+                match key.keysym {
+                    Keysym::Escape if self.drag.is_some() => self.drag = None,
+                    Keysym::Escape => self.finished = Some(false),
+                    Keysym::Return | Keysym::KP_Enter => self.finished = Some(true),
+                    Keysym::BackSpace => return self.done.pop().is_some(),
+                    Keysym::z if key.ctrl => return self.done.pop().is_some(),
+                    Keysym::Tab => {
+                        self.kind = match self.kind {
+                            Kind::Box => Kind::Line,
+                            Kind::Line => Kind::Box,
+                        }
+                    }
+                    _ => match key.text.as_str() {
+                        "s" => {
+                            self.snapping = !self.snapping;
+                            self.move_pointer(self.raw_pointer);
+                        }
+                        "m" => self.loupe = !self.loupe,
+                        _ => return false,
+                    },
+                }
+                true
+            }
+        }
+    }
+
+    /// The box being dragged stays undimmed.
+    fn selection(&self) -> Option<Rect> {
+        self.current()
+            .filter(|current| current.kind == Kind::Box)
+            .map(|current| Rect::spanning(current.from, current.to))
+            .filter(|rect| rect.width > 0 && rect.height > 0)
+    }
+
+    fn decorate(&self, popup: &Popup, canvas: &mut Canvas, theme: &Theme) {
+        let view = popup_view(popup);
+        for measurement in &self.done {
+            annotate::draw(
+                canvas,
+                view,
+                &Annotation {
+                    shape: measurement.shape(),
+                    color: theme.border_color,
+                    width: 1.0,
+                },
+            );
+            let (x, y) = (
+                measurement.from.0.max(measurement.to.0),
+                measurement.from.1.max(measurement.to.1),
+            );
+            draw_label(
+                canvas,
+                view,
+                (x + 4.0, y + 4.0),
+                &measurement.describe(self.captures),
+            );
+        }
+        if let Some(current) = self.current().filter(|current| current.kind == Kind::Line) {
+            annotate::draw(
+                canvas,
+                view,
+                &Annotation {
+                    shape: current.shape(),
+                    color: theme.border_color,
+                    width: 1.0,
+                },
+            );
+        }
+        if self.loupe {
+            loupe::draw(canvas, view, self.pointer, self.captures);
+        }
+        overlay::draw_status(
+            popup,
+            canvas,
+            theme,
+            (None, self.pointer),
+            &self.status(),
+            theme.border_color,
+        );
+    }
+
+    fn finished(&self) -> Option<bool> {
+        self.finished
+    }
+}
+
+pub(crate) fn run(args: &MeasureArgs) {
+    let _total = Span::new(Level::Info, "Measure");
+    let config = config::load();
+
+    let mut wayland_vars = wayland::init();
+    // Capture first, edges are searched for in what is below the overlay
+    let screenshots_file = tempfile::tempfile().unwrap();
+    let mut screenshots_data = {
+        let _span = Span::new(Level::Debug, "Screencopy");
+        screenshot(&mut wayland_vars, screenshots_file)
+    };
+    let mut popups = create_popup(&mut wayland_vars, &screenshots_data);
+
+    let mut screens_buf = Vec::with_capacity(screenshots_data.file_len);
+    screenshots_data
+        .buffer_file
+        .read_to_end(&mut screens_buf)
+        .unwrap();
+    let captures = previews(&screenshots_data, &screens_buf);
+
+    let mut measure = Measure {
+        captures: &captures,
+        kind: Kind::Box,
+        snapping: !args.no_snap,
+        loupe: true,
+        raw_pointer: (0.0, 0.0),
+        pointer: (0.0, 0.0),
+        drag: None,
+        done: vec![],
+        finished: None,
+    };
+    if !overlay::interact(&mut popups, &mut wayland_vars, &config.theme, &mut measure) {
+        log::info!("Cancelled");
+        process::exit(1);
+    }
+    // A measurement still being dragged counts too
+    let unfinished = measure
+        .current()
+        .filter(|current| current.from != current.to);
+    for measurement in measure.done.iter().chain(&unfinished) {
+        println!("{}", measurement.describe(&captures));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    /// An output right of another one with two pixels per logical pixel. The left 80 pixels of
+    /// the top 40 rows are black, the rest of them white, the rows below red.
+    fn captures() -> Vec<(ScreenData, RgbaImage)> {
+        let screen = ScreenData {
+            name: 1,
+            connector: None,
+            resolution: (200, 100),
+            logical_resolution: (100, 50),
+            logical_position: (100, 0),
+        };
+        let capture = RgbaImage::from_fn(200, 100, |x, y| match (x, y) {
+            (_, 40..) => Rgba([255, 0, 0, 255]),
+            (..80, _) => Rgba([0, 0, 0, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        });
+        vec![(screen, capture)]
+    }
+
+    #[test]
+    fn numbers() {
+        for (value, text) in [
+            (3.0, "3"),
+            (2.5, "2.5"),
+            (1.234, "1.23"),
+            (1.999, "2"),
+            (-12.5, "-12.5"),
+            (-0.001, "0"),
+        ] {
+            assert_eq!(number(value), text, "{value}");
+        }
+    }
+
+    #[test]
+    fn nearest_edges() {
+        let edges = |boundary: u32| boundary == 3 || boundary == 7;
+        assert_eq!(nearest_edge(6.0, 6.0, 10, edges), Some(7.0));
+        assert_eq!(nearest_edge(4.5, 6.0, 10, edges), Some(3.0));
+        // Equally close, the first one wins
+        assert_eq!(nearest_edge(5.0, 6.0, 10, edges), Some(3.0));
+        assert_eq!(nearest_edge(5.0, 1.5, 10, edges), None);
+        // Boundaries only lie between two pixels
+        let everywhere = |_| true;
+        assert_eq!(nearest_edge(0.2, 3.0, 10, everywhere), Some(1.0));
+        assert_eq!(nearest_edge(9.8, 3.0, 10, everywhere), Some(9.0));
+    }
+
+    #[test]
+    fn snaps_to_edges_in_the_capture() {
+        let captures = captures();
+        // The black to white edge is at 80 pixels, 140 logical
+        assert_eq!(snap((141.0, 10.0), &captures), (140.0, 10.0));
+        // The red rows start at 40 pixels, 20 logical
+        assert_eq!(snap((139.5, 18.0), &captures), (140.0, 20.0));
+        // Too far from any edge, or off every output
+        assert_eq!(snap((120.0, 5.0), &captures), (120.0, 5.0));
+        assert_eq!(snap((50.0, 5.0), &captures), (50.0, 5.0));
+    }
+
+    #[test]
+    fn descriptions() {
+        let captures = captures();
+        let measurement = |kind, from, to| Measurement { kind, from, to };
+        assert_eq!(
+            measurement(Kind::Box, (120.0, 25.0), (110.0, 5.0)).describe(&captures),
+            "box 10x20 at 110,5 (20x40 physical on output 1)"
+        );
+        assert_eq!(
+            measurement(Kind::Line, (110.0, 0.0), (113.0, 4.0)).describe(&captures),
+            "line 5 (dx 3, dy 4) from 110,0 to 113,4 (10 physical on output 1)"
+        );
+        // Starting off every output, there are no physical pixels to count
+        assert_eq!(
+            measurement(Kind::Line, (90.5, 0.0), (110.5, 0.0)).describe(&captures),
+            "line 20 (dx 20, dy 0) from 90.5,0 to 110.5,0"
+        );
+    }
+}
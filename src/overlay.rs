@@ -0,0 +1,513 @@
+//! Renders the dimmed overlay shown on top of the outputs while capturing.
+
+use std::mem;
+
+use image::RgbaImage;
+use wayland_client::{
+    protocol::{wl_pointer::WlPointer, wl_surface::WlSurface},
+    QueueHandle,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1::KeyboardInteractivity;
+use xkbcommon::xkb::Keysym;
+
+use crate::{
+    annotate::{self, Point, View},
+    canvas::Canvas,
+    config::{Color, Theme},
+    editor::Editor,
+    loupe,
+    stitch::output_portion,
+    types::{BuffersStore, Rect},
+    wayland::{
+        add_plugged_popups, bind_input,
+        cursor::{CursorIcon, Cursors},
+        keyboard::{KeyPress, Keyboard},
+        output_names, remove_closed_popups, rescale_popups,
+        types::{Delegate, InputEvent, Popup, ScreenData},
+        WaylandVarsNew,
+    },
+};
+
+/// Fills `canvas` with the tint, leaving `selection` undimmed inside of a border.
+pub(crate) fn render(canvas: &mut Canvas, selection: Option<Rect>, theme: &Theme) {
+    let mut tint = theme.tint;
+    tint.0[3] = (tint.0[3] as u32 * theme.alpha as u32 / 255) as u8;
+    canvas.fill(tint);
+    let Some(hole) = selection else {
+        return;
+    };
+    canvas.fill_rect(hole, Color([0, 0, 0, 0]));
+    // The border goes inside of the selection, so it stays visible at the output edges
+    canvas.outline(hole, theme.border_width as i32, theme.border_color);
+}
+
+/// Shows `selection`, in global logical coordinates, on every captured output it covers. Every
+/// popup gets drawn again, decorations may have changed anywhere.
+pub(crate) fn select(popups_mem: &mut BuffersStore<Popup>, selection: Option<Rect>) {
+    for popup in popups_mem.buffers_metadata.iter_mut() {
+        let screen = &popup.screen_data;
+        let (width, height) = popup.buffer_size;
+        popup.selection = selection
+            .filter(|_| popup.capture.is_some())
+            .and_then(|selection| output_portion(selection, screen, (width as u32, height as u32)));
+        popup.dirty = true;
+    }
+}
+
+/// Maps global logical coordinates onto the overlay buffer of `popup`.
+pub(crate) fn popup_view(popup: &Popup) -> View {
+    let screen = &popup.screen_data;
+    let ((x, y), (l_width, l_height)) = (screen.logical_position, screen.logical_resolution);
+    let (width, height) = popup.buffer_size;
+    View {
+        origin: (x as f64, y as f64),
+        scale: (
+            width as f64 / l_width as f64,
+            height as f64 / l_height as f64,
+        ),
+    }
+}
+
+/// The rectangle around every pixel that differs between the frames `old` and `new`, which are
+/// `width` pixels wide.
+fn damage(old: &[u32], new: &[u32], width: usize) -> Option<Rect> {
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    let rows = old.chunks_exact(width).zip(new.chunks_exact(width));
+    for (y, (old_row, new_row)) in rows.enumerate() {
+        if old_row == new_row {
+            continue;
+        }
+        let differs = |x: &usize| old_row[*x] != new_row[*x];
+        let left = (0..width).find(differs).unwrap();
+        let right = (0..width).rfind(differs).unwrap() + 1;
+        bounds = Some(match bounds {
+            None => (left, y, right, y + 1),
+            Some((min_left, top, max_right, _)) => {
+                (min_left.min(left), top, max_right.max(right), y + 1)
+            }
+        });
+    }
+    bounds.map(|(left, top, right, bottom)| Rect {
+        x: left as i32,
+        y: top as i32,
+        width: (right - left) as i32,
+        height: (bottom - top) as i32,
+    })
+}
+
+/// Draws the dirty popups into a free buffer and shows it, `decorate` draws on top of the
+/// dimmed overlay of the captured outputs. Only what changed since the last frame gets damaged.
+/// A popup whose compositor isn't ready for the next frame stays dirty, returns whether any did.
+pub(crate) fn draw(
+    popups_mem: &mut BuffersStore<Popup>,
+    qh: &QueueHandle<Delegate>,
+    theme: &Theme,
+    mut decorate: impl FnMut(&Popup, &mut Canvas),
+) -> bool {
+    let dirty = |popup: &Popup| popup.dirty;
+    let ready = |popup: &Popup| popup.dirty && popup.back_buffer().is_some();
+    if !popups_mem.buffers_metadata.iter().any(ready) {
+        return popups_mem.buffers_metadata.iter().any(dirty);
+    }
+
+    // Without any buffers there is nothing ready to draw
+    let mapped = popups_mem.mapped.as_mut().unwrap();
+    for popup in popups_mem.buffers_metadata.iter_mut() {
+        let Some(back) = popup.back_buffer().filter(|_| popup.dirty) else {
+            continue;
+        };
+        let selection = popup.selection;
+        let (width, height) = popup.buffer_size;
+        let frame = popup.buffers[back].offset..popup.buffers[back].offset + popup.frame_len();
+        let mut canvas =
+            Canvas::from_bytes(&mut mapped[frame.clone()], width as u32, height as u32);
+        render(&mut canvas, selection, theme);
+        match popup.capture {
+            Some(_) => decorate(popup, &mut canvas),
+            None => draw_uncovered(popup, &mut canvas, theme),
+        }
+        popup.dirty = false;
+
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let damaged = match popup.front {
+            Some(front) => {
+                let shown =
+                    popup.buffers[front].offset..popup.buffers[front].offset + popup.frame_len();
+                damage(
+                    bytemuck::cast_slice(&mapped[shown]),
+                    bytemuck::cast_slice(&mapped[frame]),
+                    width as usize,
+                )
+            }
+            None => Some(whole),
+        };
+        // Nothing changed, the frame on screen is still right
+        let Some(damaged) = damaged else {
+            continue;
+        };
+
+        let buffer = &popup.buffers[back];
+        *buffer.busy.lock().unwrap() = true;
+        *popup.frame_pending.lock().unwrap() = true;
+        popup.surface.attach(Some(&buffer.buffer), 0, 0);
+        popup
+            .surface
+            .damage_buffer(damaged.x, damaged.y, damaged.width, damaged.height);
+        popup.surface.frame(qh, popup.frame_pending.clone());
+        popup.surface.commit();
+        popup.front = Some(back);
+    }
+    popups_mem.buffers_metadata.iter().any(dirty)
+}
+
+/// Draws the popups and shows them, again whenever their preferred scale changes or outputs get
+/// plugged in.
+pub(crate) fn show(popups_mem: &mut BuffersStore<Popup>, vars: &mut WaylandVarsNew, theme: &Theme) {
+    let mut known = output_names(vars);
+    rescale_popups(vars, popups_mem);
+    loop {
+        let pending = draw(popups_mem, &vars.qh, theme, |_, _| {});
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+
+        // Whatever is left still shows the previous frame, which stays valid without the
+        // removed popups
+        remove_closed_popups(popups_mem);
+        let added = add_plugged_popups(vars, popups_mem, &mut known);
+        if !rescale_popups(vars, popups_mem) && !pending && added == 0 {
+            break;
+        }
+    }
+}
+
+/// `BTN_LEFT` from `linux/input-event-codes.h`.
+pub(crate) const BTN_LEFT: u32 = 0x110;
+
+/// Input on the overlay, with the pointer in global logical coordinates.
+#[derive(Debug, Clone)]
+pub(crate) enum Event {
+    Motion(Point),
+    Button {
+        button: u32,
+        pressed: bool,
+    },
+    /// A short touch or stylus contact that stayed in place, right after its `BTN_LEFT` release.
+    Tap(Point),
+    Scroll(f64),
+    Key(KeyPress),
+    KeyRelease(Keysym),
+}
+
+/// What the user does on the overlay while [`interact`] runs.
+pub(crate) trait Interaction {
+    /// Handles one event, returns whether the overlay has to be redrawn.
+    fn handle(&mut self, event: Event) -> bool;
+    /// The region left undimmed, in global logical coordinates.
+    fn selection(&self) -> Option<Rect>;
+    /// Draws on top of the dimmed overlay of `popup`.
+    fn decorate(&self, popup: &Popup, canvas: &mut Canvas, theme: &Theme);
+    /// Set once the user is done (`true`) or cancelled (`false`).
+    fn finished(&self) -> Option<bool>;
+    /// The cursor shown over the overlay.
+    fn cursor(&self) -> CursorIcon {
+        CursorIcon::Crosshair
+    }
+}
+
+/// Font pixels of the status label, in logical pixels.
+const STATUS_PIXEL: f64 = 2.0;
+
+/// Draws `text` with a `swatch` of color in front of it, below `selection` or next to the
+/// pointer when there is none. Next to the pointer it goes above, out of the way of the loupe.
+pub(crate) fn draw_status(
+    popup: &Popup,
+    canvas: &mut Canvas,
+    theme: &Theme,
+    (selection, pointer): (Option<Rect>, Point),
+    text: &str,
+    swatch: Color,
+) {
+    let view = popup_view(popup);
+    let (width, height) = annotate::text_size(text, STATUS_PIXEL);
+    let padding = STATUS_PIXEL * 2.0;
+    let output = popup.screen_data.logical_position;
+    let output_bottom = output.1 as f64 + popup.screen_data.logical_resolution.1 as f64;
+    // The swatch is as high as the first line
+    let swatch_size = annotate::text_size("", STATUS_PIXEL).1 + padding * 2.0;
+    let box_height = height + padding * 2.0;
+    // Below the selection, or inside of it when that would leave the output
+    let (x, y) = match selection {
+        Some(selection) => {
+            let below = (selection.y + selection.height) as f64 + padding;
+            if below + box_height > output_bottom {
+                (selection.x as f64 + padding, selection.y as f64 + padding)
+            } else {
+                (selection.x as f64, below)
+            }
+        }
+        None => {
+            let above = pointer.1 - 16.0 - box_height;
+            if above < output.1 as f64 {
+                (pointer.0 + 16.0, pointer.1 + 16.0)
+            } else {
+                (pointer.0 + 16.0, above)
+            }
+        }
+    };
+
+    let mut background = theme.tint;
+    background.0[3] = 220;
+    annotate::draw_box(
+        canvas,
+        view,
+        (x, y),
+        (x + swatch_size + width + padding * 2.0, y + box_height),
+        background,
+    );
+    annotate::draw_box(
+        canvas,
+        view,
+        (x + padding, y + padding),
+        (x + swatch_size - padding, y + swatch_size - padding),
+        swatch,
+    );
+    annotate::draw_text(
+        canvas,
+        view,
+        (x + swatch_size + padding, y + padding),
+        text,
+        STATUS_PIXEL,
+        Color([255, 255, 255, 255]),
+    );
+}
+
+/// Marks the output of `popup` as missing from the screenshot, it got plugged in after the
+/// capture.
+fn draw_uncovered(popup: &Popup, canvas: &mut Canvas, theme: &Theme) {
+    let view = popup_view(popup);
+    let text = "Not captured";
+    let (width, height) = annotate::text_size(text, STATUS_PIXEL);
+    let padding = STATUS_PIXEL * 2.0;
+    let ((x, y), (l_width, l_height)) = (
+        popup.screen_data.logical_position,
+        popup.screen_data.logical_resolution,
+    );
+    let center = (
+        x as f64 + l_width as f64 / 2.0,
+        y as f64 + l_height as f64 / 2.0,
+    );
+    let min = (center.0 - width / 2.0, center.1 - height / 2.0);
+
+    let mut background = theme.tint;
+    background.0[3] = 220;
+    annotate::draw_box(
+        canvas,
+        view,
+        (min.0 - padding, min.1 - padding),
+        (min.0 + width + padding, min.1 + height + padding),
+        background,
+    );
+    annotate::draw_text(
+        canvas,
+        view,
+        min,
+        text,
+        STATUS_PIXEL,
+        Color([255, 255, 255, 255]),
+    );
+}
+
+/// `position` on the overlay `surface` in global logical coordinates.
+fn global_position(
+    popups_mem: &BuffersStore<Popup>,
+    surface: &WlSurface,
+    position: Point,
+) -> Option<Point> {
+    let popup = popups_mem
+        .buffers_metadata
+        .iter()
+        .find(|popup| popup.surface == *surface)?;
+    let (x, y) = popup.screen_data.logical_position;
+    Some((x as f64 + position.0, y as f64 + position.1))
+}
+
+/// Shows the overlay with keyboard focus until `interaction` is finished, then hides it.
+/// Returns whether the user finished, `false` when they cancelled or every output went away.
+pub(crate) fn interact(
+    popups_mem: &mut BuffersStore<Popup>,
+    vars: &mut WaylandVarsNew,
+    theme: &Theme,
+    interaction: &mut impl Interaction,
+) -> bool {
+    let input = bind_input(vars);
+    for popup in popups_mem.buffers_metadata.iter() {
+        popup
+            .layer_surface
+            .set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+    }
+    let mut keyboard = Keyboard::new();
+    let mut cursors = Cursors::new(vars);
+    // The cursor set for every pointer over the overlay, since it entered with that serial
+    let mut shown_cursors: Vec<(WlPointer, u32, CursorIcon)> = vec![];
+
+    let mut known = output_names(vars);
+    rescale_popups(vars, popups_mem);
+    let mut redraw = true;
+    let finished = loop {
+        if redraw {
+            select(popups_mem, interaction.selection());
+        }
+        // Popups the compositor isn't ready for get drawn once their frame callback arrives,
+        // with whatever the interaction looks like by then
+        draw(popups_mem, &vars.qh, theme, |popup, canvas| {
+            interaction.decorate(popup, canvas, theme)
+        });
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+
+        remove_closed_popups(popups_mem);
+        if popups_mem.buffers_metadata.is_empty() {
+            log::error!("Every output went away");
+            return false;
+        }
+        let added = add_plugged_popups(vars, popups_mem, &mut known);
+        let count = popups_mem.buffers_metadata.len();
+        for popup in &popups_mem.buffers_metadata[count - added..] {
+            popup
+                .layer_surface
+                .set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+        }
+        redraw = rescale_popups(vars, popups_mem) || added > 0;
+
+        let events = mem::take(&mut input.lock().unwrap().events);
+        for event in events {
+            let event = match event {
+                InputEvent::Motion { surface, position } => {
+                    match global_position(popups_mem, &surface, position) {
+                        Some(position) => Event::Motion(position),
+                        None => continue,
+                    }
+                }
+                InputEvent::Tap { surface, position } => {
+                    match global_position(popups_mem, &surface, position) {
+                        Some(position) => Event::Tap(position),
+                        None => continue,
+                    }
+                }
+                InputEvent::Button { button, pressed } => Event::Button { button, pressed },
+                InputEvent::Scroll(distance) => Event::Scroll(distance),
+                InputEvent::Keymap(keymap) => {
+                    keyboard.set_keymap(keymap);
+                    continue;
+                }
+                InputEvent::Modifiers {
+                    depressed,
+                    latched,
+                    locked,
+                    group,
+                } => {
+                    keyboard.update_modifiers(depressed, latched, locked, group);
+                    continue;
+                }
+                InputEvent::Key { key, pressed: true } => match keyboard.press(key) {
+                    Some(key) => Event::Key(key),
+                    None => continue,
+                },
+                InputEvent::Key {
+                    key,
+                    pressed: false,
+                } => match keyboard.release(key) {
+                    Some(keysym) => Event::KeyRelease(keysym),
+                    None => continue,
+                },
+            };
+            redraw |= interaction.handle(event);
+        }
+
+        if let Some(finished) = interaction.finished() {
+            break finished;
+        }
+
+        let icon = interaction.cursor();
+        let entered = input.lock().unwrap().entered.clone();
+        shown_cursors = entered
+            .into_iter()
+            .map(|(pointer, serial)| {
+                if !shown_cursors.contains(&(pointer.clone(), serial, icon)) {
+                    cursors.set(&vars.qh, &pointer, serial, icon);
+                }
+                (pointer, serial, icon)
+            })
+            .collect();
+    };
+
+    // Get the overlay out of the way before saving
+    for popup in popups_mem.buffers_metadata.iter() {
+        popup.surface.attach(None, 0, 0);
+        popup.surface.commit();
+    }
+    vars.event_queue.roundtrip(&mut Delegate).unwrap();
+    finished
+}
+
+/// The editor along with what got captured for its magnifier.
+struct Editing<'a> {
+    editor: Editor,
+    captures: &'a [(ScreenData, RgbaImage)],
+}
+
+impl Interaction for Editing<'_> {
+    fn handle(&mut self, event: Event) -> bool {
+        self.editor.handle(event)
+    }
+
+    fn selection(&self) -> Option<Rect> {
+        self.editor.selection()
+    }
+
+    /// Draws the annotations, the one in progress and the status label.
+    fn decorate(&self, popup: &Popup, canvas: &mut Canvas, theme: &Theme) {
+        let editor = &self.editor;
+        let view = popup_view(popup);
+        annotate::draw_all(canvas, view, editor.annotations.iter());
+        if let Some(pending) = editor.pending() {
+            annotate::draw(canvas, view, &pending);
+        }
+        if editor.loupe_visible() {
+            loupe::draw(canvas, view, editor.pointer(), self.captures);
+        }
+        draw_status(
+            popup,
+            canvas,
+            theme,
+            (editor.selection(), editor.pointer()),
+            &editor.status(),
+            editor.color(),
+        );
+    }
+
+    fn finished(&self) -> Option<bool> {
+        self.editor.finished
+    }
+
+    fn cursor(&self) -> CursorIcon {
+        self.editor.cursor()
+    }
+}
+
+/// Lets the user select a region and annotate it with `editor`, `captures` holds what got
+/// captured of every output for the magnifier. Returns the editor once the user saved, `None`
+/// when they cancelled or every output went away.
+pub(crate) fn edit(
+    popups_mem: &mut BuffersStore<Popup>,
+    vars: &mut WaylandVarsNew,
+    theme: &Theme,
+    editor: Editor,
+    captures: &[(ScreenData, RgbaImage)],
+) -> Option<Editor> {
+    let mut editing = Editing { editor, captures };
+    interact(popups_mem, vars, theme, &mut editing).then_some(editing.editor)
+}
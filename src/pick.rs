@@ -0,0 +1,236 @@
+//! The `pick` command: click a pixel on the overlay and print its color.
+//!
+//! The color comes from the capture taken before the overlay showed up, never from the tinted
+//! overlay itself.
+
+use std::{io::Read, process};
+
+use image::RgbaImage;
+use log::Level;
+use xkbcommon::xkb::Keysym;
+
+use crate::{
+    annotate::Point,
+    canvas::Canvas,
+    cli::{ColorFormat, PickArgs},
+    clipboard,
+    config::{self, Color, Theme},
+    logging::Span,
+    loupe,
+    overlay::{self, popup_view, Event, Interaction, BTN_LEFT},
+    region::previews,
+    types::Rect,
+    wayland::{
+        self, create_popup, screenshot,
+        types::{Popup, ScreenData},
+    },
+};
+
+struct Picker<'a> {
+    captures: &'a [(ScreenData, RgbaImage)],
+    pointer: Point,
+    finished: Option<bool>,
+}
+
+impl Picker<'_> {
+    /// The captured color under the pointer, `None` off every capture.
+    fn color(&self) -> Option<[u8; 3]> {
+        let (_, capture, (x, y)) = loupe::pixel_at(self.pointer, self.captures)?;
+        let [r, g, b, _] = capture.get_pixel_checked(x as u32, y as u32)?.0;
+        Some([r, g, b])
+    }
+}
+
+impl Interaction for Picker<'_> {
+    fn handle(&mut self, event: Event) -> bool {
+        match event {
+            Event::Motion(position) => {
+                self.pointer = position;
+                true
+            }
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: true,
+            } if self.color().is_some() => {
+                self.finished = Some(true);
+                false
+            }
+            Event::Key(key) => match key.keysym {
+                Keysym::Escape => {
+                    self.finished = Some(false);
+                    false
+                }
+                Keysym::Return | Keysym::KP_Enter if self.color().is_some() => {
+                    self.finished = Some(true);
+                    false
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn selection(&self) -> Option<Rect> {
+        None
+    }
+
+    fn decorate(&self, popup: &Popup, canvas: &mut Canvas, theme: &Theme) {
+        let Some([r, g, b]) = self.color() else {
+            return;
+        };
+        loupe::draw(canvas, popup_view(popup), self.pointer, self.captures);
+        overlay::draw_status(
+            popup,
+            canvas,
+            theme,
+            (None, self.pointer),
+            &format!("{}  Click: pick  Esc: cancel", hex([r, g, b])),
+            Color([r, g, b, 255]),
+        );
+    }
+
+    fn finished(&self) -> Option<bool> {
+        self.finished
+    }
+}
+
+fn hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Hue in degrees, saturation and lightness in percent.
+fn hsl([r, g, b]: [u8; 3]) -> (f64, f64, f64) {
+    let [r, g, b] = [r, g, b].map(|channel| channel as f64 / 255.0);
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    let lightness = (max + min) / 2.0;
+    let chroma = max - min;
+    if chroma == 0.0 {
+        return (0.0, 0.0, lightness * 100.0);
+    }
+    let saturation = chroma / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = match max {
+        _ if max == r => ((g - b) / chroma).rem_euclid(6.0),
+        _ if max == g => (b - r) / chroma + 2.0,
+        _ => (r - g) / chroma + 4.0,
+    };
+    (hue * 60.0, saturation * 100.0, lightness * 100.0)
+}
+
+/// Formats `color` for printing, with `template` taking precedence over `format`.
+fn format_color(color: [u8; 3], format: ColorFormat, template: Option<&str>) -> String {
+    let [r, g, b] = color;
+    let (h, s, l) = hsl(color);
+    let (h, s, l) = (h.round() as u32 % 360, s.round() as u32, l.round() as u32);
+    match template {
+        Some(template) => template
+            .replace("{r}", &r.to_string())
+            .replace("{g}", &g.to_string())
+            .replace("{b}", &b.to_string())
+            .replace("{hex}", &hex(color))
+            .replace("{h}", &h.to_string())
+            .replace("{s}", &s.to_string())
+            .replace("{l}", &l.to_string()),
+        None => match format {
+            ColorFormat::Hex => hex(color),
+            ColorFormat::Rgb => format!("rgb({r}, {g}, {b})"),
+            ColorFormat::Hsl => format!("hsl({h}, {s}%, {l}%)"),
+        },
+    }
+}
+
+pub(crate) fn run(args: &PickArgs) {
+    let _total = Span::new(Level::Info, "Pick");
+    let config = config::load();
+
+    let mut wayland_vars = wayland::init();
+    // Capture first, the color has to come from what is below the overlay
+    let screenshots_file = tempfile::tempfile().unwrap();
+    let mut screenshots_data = {
+        let _span = Span::new(Level::Debug, "Screencopy");
+        screenshot(&mut wayland_vars, screenshots_file)
+    };
+    let mut popups = create_popup(&mut wayland_vars, &screenshots_data);
+
+    let mut screens_buf = Vec::with_capacity(screenshots_data.file_len);
+    screenshots_data
+        .buffer_file
+        .read_to_end(&mut screens_buf)
+        .unwrap();
+    let captures = previews(&screenshots_data, &screens_buf);
+
+    let mut picker = Picker {
+        captures: &captures,
+        pointer: (0.0, 0.0),
+        finished: None,
+    };
+    if !overlay::interact(&mut popups, &mut wayland_vars, &config.theme, &mut picker) {
+        log::info!("Cancelled");
+        process::exit(1);
+    }
+    let color = picker.color().unwrap();
+    let text = format_color(color, args.format, args.template.as_deref());
+
+    if args.copy {
+        if let Err(err) = clipboard::copy(text.as_bytes(), "text/plain;charset=utf-8") {
+            log::error!("Failed to copy the color: {err}");
+        }
+    }
+    println!("{text}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hsl_values() {
+        for (color, expected) in [
+            ([255, 0, 0], (0.0, 100.0, 50.0)),
+            ([0, 255, 0], (120.0, 100.0, 50.0)),
+            ([0, 0, 255], (240.0, 100.0, 50.0)),
+            ([255, 0, 255], (300.0, 100.0, 50.0)),
+            ([255, 255, 255], (0.0, 0.0, 100.0)),
+            ([0, 0, 0], (0.0, 0.0, 0.0)),
+            ([51, 102, 153], (210.0, 50.0, 40.0)),
+        ] {
+            let (h, s, l) = hsl(color);
+            let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+            assert!(
+                close(h, expected.0) && close(s, expected.1) && close(l, expected.2),
+                "{color:?} is {:?}",
+                (h, s, l)
+            );
+        }
+    }
+
+    #[test]
+    fn formats_colors() {
+        let orange = [255, 128, 0];
+        assert_eq!(format_color(orange, ColorFormat::Hex, None), "#ff8000");
+        assert_eq!(
+            format_color(orange, ColorFormat::Rgb, None),
+            "rgb(255, 128, 0)"
+        );
+        assert_eq!(
+            format_color(orange, ColorFormat::Hsl, None),
+            "hsl(30, 100%, 50%)"
+        );
+        assert_eq!(
+            format_color(
+                orange,
+                ColorFormat::Hex,
+                Some("{r} {g} {b} {hex} {h} {s} {l} {x}")
+            ),
+            "255 128 0 #ff8000 30 100 50 {x}"
+        );
+        // A hue rounding up to 360 degrees wraps around
+        assert_eq!(
+            format_color([255, 0, 1], ColorFormat::Hsl, None),
+            "hsl(0, 100%, 50%)"
+        );
+        assert_eq!(
+            format_color([0, 10, 255], ColorFormat::Hex, None),
+            "#000aff"
+        );
+    }
+}
@@ -0,0 +1,483 @@
+//! Conversion of screencopy buffers in any packed RGB `wl_shm` format into plain RGBA.
+
+use std::fmt;
+
+use half::f16;
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+use wayland_client::protocol::wl_shm::Format;
+
+use crate::wayland::types::FrameFormat;
+
+pub(crate) type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UnsupportedFormat(pub(crate) Format);
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported buffer format {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedFormat {}
+
+/// Position of a channel inside of a little endian pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Channel {
+    shift: u32,
+    bits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    bytes_per_pixel: usize,
+    /// Channels hold IEEE half floats instead of unsigned normalized integers.
+    float: bool,
+    r: Channel,
+    g: Channel,
+    b: Channel,
+    a: Option<Channel>,
+}
+
+/// Builds a layout from the DRM fourcc description of a format, e.g. `("ARGB", [8, 8, 8, 8])`
+/// for `[31:0] A:R:G:B 8:8:8:8 little endian`. The first channel holds the most significant bits,
+/// `X` marks padding.
+fn packed(order: &str, bits: &[u32]) -> Layout {
+    let total_bits = bits.iter().sum::<u32>();
+    let mut shift = total_bits;
+    let (mut r, mut g, mut b, mut a) = (None, None, None, None);
+    for (name, &bits) in order.chars().zip(bits) {
+        shift -= bits;
+        let channel = Some(Channel { shift, bits });
+        match name {
+            'R' => r = channel,
+            'G' => g = channel,
+            'B' => b = channel,
+            'A' => a = channel,
+            _ => {}
+        }
+    }
+
+    Layout {
+        bytes_per_pixel: total_bits as usize / 8,
+        float: false,
+        r: r.unwrap(),
+        g: g.unwrap(),
+        b: b.unwrap(),
+        a,
+    }
+}
+
+fn half_float(order: &str) -> Layout {
+    Layout {
+        float: true,
+        ..packed(order, &[16, 16, 16, 16])
+    }
+}
+
+fn layout(format: Format) -> Option<Layout> {
+    use Format::*;
+
+    let layout = match format {
+        Rgb332 => packed("RGB", &[3, 3, 2]),
+        Bgr233 => packed("BGR", &[2, 3, 3]),
+
+        Xrgb4444 => packed("XRGB", &[4, 4, 4, 4]),
+        Xbgr4444 => packed("XBGR", &[4, 4, 4, 4]),
+        Rgbx4444 => packed("RGBX", &[4, 4, 4, 4]),
+        Bgrx4444 => packed("BGRX", &[4, 4, 4, 4]),
+        Argb4444 => packed("ARGB", &[4, 4, 4, 4]),
+        Abgr4444 => packed("ABGR", &[4, 4, 4, 4]),
+        Rgba4444 => packed("RGBA", &[4, 4, 4, 4]),
+        Bgra4444 => packed("BGRA", &[4, 4, 4, 4]),
+
+        Xrgb1555 => packed("XRGB", &[1, 5, 5, 5]),
+        Xbgr1555 => packed("XBGR", &[1, 5, 5, 5]),
+        Rgbx5551 => packed("RGBX", &[5, 5, 5, 1]),
+        Bgrx5551 => packed("BGRX", &[5, 5, 5, 1]),
+        Argb1555 => packed("ARGB", &[1, 5, 5, 5]),
+        Abgr1555 => packed("ABGR", &[1, 5, 5, 5]),
+        Rgba5551 => packed("RGBA", &[5, 5, 5, 1]),
+        Bgra5551 => packed("BGRA", &[5, 5, 5, 1]),
+
+        Rgb565 => packed("RGB", &[5, 6, 5]),
+        Bgr565 => packed("BGR", &[5, 6, 5]),
+
+        Rgb888 => packed("RGB", &[8, 8, 8]),
+        Bgr888 => packed("BGR", &[8, 8, 8]),
+
+        Xrgb8888 => packed("XRGB", &[8, 8, 8, 8]),
+        Xbgr8888 => packed("XBGR", &[8, 8, 8, 8]),
+        Rgbx8888 => packed("RGBX", &[8, 8, 8, 8]),
+        Bgrx8888 => packed("BGRX", &[8, 8, 8, 8]),
+        Argb8888 => packed("ARGB", &[8, 8, 8, 8]),
+        Abgr8888 => packed("ABGR", &[8, 8, 8, 8]),
+        Rgba8888 => packed("RGBA", &[8, 8, 8, 8]),
+        Bgra8888 => packed("BGRA", &[8, 8, 8, 8]),
+
+        Xrgb2101010 => packed("XRGB", &[2, 10, 10, 10]),
+        Xbgr2101010 => packed("XBGR", &[2, 10, 10, 10]),
+        Rgbx1010102 => packed("RGBX", &[10, 10, 10, 2]),
+        Bgrx1010102 => packed("BGRX", &[10, 10, 10, 2]),
+        Argb2101010 => packed("ARGB", &[2, 10, 10, 10]),
+        Abgr2101010 => packed("ABGR", &[2, 10, 10, 10]),
+        Rgba1010102 => packed("RGBA", &[10, 10, 10, 2]),
+        Bgra1010102 => packed("BGRA", &[10, 10, 10, 2]),
+
+        Xrgb16161616 => packed("XRGB", &[16, 16, 16, 16]),
+        Xbgr16161616 => packed("XBGR", &[16, 16, 16, 16]),
+        Argb16161616 => packed("ARGB", &[16, 16, 16, 16]),
+        Abgr16161616 => packed("ABGR", &[16, 16, 16, 16]),
+
+        Xrgb16161616f => half_float("XRGB"),
+        Xbgr16161616f => half_float("XBGR"),
+        Argb16161616f => half_float("ARGB"),
+        Abgr16161616f => half_float("ABGR"),
+
+        _ => return None,
+    };
+    Some(layout)
+}
+
+/// Most bits any color channel of the format carries, half floats count as 16.
+pub(crate) fn bit_depth(format: Format) -> Option<u32> {
+    let layout = layout(format)?;
+    if layout.float {
+        return Some(16);
+    }
+    [layout.r, layout.g, layout.b]
+        .map(|channel| channel.bits)
+        .into_iter()
+        .max()
+}
+
+impl Layout {
+    /// Scales a channel of `pixel` to the full `u16` range.
+    fn read(&self, pixel: u64, channel: Channel) -> u16 {
+        let max = (1u64 << channel.bits) - 1;
+        let value = (pixel >> channel.shift) & max;
+        if self.float {
+            // No transfer function is applied, values outside of 0..=1 get clamped
+            let value = f16::from_bits(value as u16).to_f32();
+            return (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+        }
+        ((value * u16::MAX as u64 + max / 2) / max) as u16
+    }
+
+    /// Calls `put` with the RGBA value of every pixel, row by row.
+    fn for_each_pixel(&self, format: FrameFormat, data: &[u8], mut put: impl FnMut([u16; 4])) {
+        let (width, stride) = (format.width as usize, format.stride as usize);
+        let row_bytes = width * self.bytes_per_pixel;
+
+        for row in data.chunks(stride).take(format.height as usize) {
+            for bytes in row[..row_bytes].chunks_exact(self.bytes_per_pixel) {
+                let mut pixel = [0u8; 8];
+                pixel[..bytes.len()].copy_from_slice(bytes);
+                let pixel = u64::from_le_bytes(pixel);
+
+                let alpha = match self.a {
+                    Some(a) => self.read(pixel, a),
+                    None => u16::MAX,
+                };
+                put([
+                    self.read(pixel, self.r),
+                    self.read(pixel, self.g),
+                    self.read(pixel, self.b),
+                    alpha,
+                ]);
+            }
+        }
+    }
+}
+
+/// Appends the frame as tightly packed RGBA8 to `out`.
+pub(crate) fn rgba8_into(
+    format: FrameFormat,
+    data: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), UnsupportedFormat> {
+    let layout = layout(format.format).ok_or(UnsupportedFormat(format.format))?;
+    out.reserve(format.width as usize * format.height as usize * 4);
+    layout.for_each_pixel(format, data, |pixel| {
+        // Rounds, so 8 bit channels survive the trip through 16 bits unchanged
+        out.extend(pixel.map(|channel| ((channel as u32 + 128) / 257) as u8));
+    });
+    Ok(())
+}
+
+pub(crate) fn to_rgba8(format: FrameFormat, data: &[u8]) -> Result<RgbaImage, UnsupportedFormat> {
+    let mut pixels = vec![];
+    rgba8_into(format, data, &mut pixels)?;
+    Ok(RgbaImage::from_raw(format.width, format.height, pixels).unwrap())
+}
+
+pub(crate) fn to_rgba16(
+    format: FrameFormat,
+    data: &[u8],
+) -> Result<Rgba16Image, UnsupportedFormat> {
+    let layout = layout(format.format).ok_or(UnsupportedFormat(format.format))?;
+    let mut pixels = Vec::with_capacity(format.width as usize * format.height as usize * 4);
+    layout.for_each_pixel(format, data, |pixel| pixels.extend(pixel));
+    Ok(Rgba16Image::from_raw(format.width, format.height, pixels).unwrap())
+}
+
+/// Converts the frame into an image without alpha. Captures are opaque, whatever the compositor
+/// leaves in the alpha bits of formats that have them.
+///
+/// Formats with more than 8 bits per channel become 16 bit images if `high_depth` is set,
+/// everything else is downconverted to 8 bits.
+pub(crate) fn to_image(
+    format: FrameFormat,
+    data: &[u8],
+    high_depth: bool,
+) -> Result<DynamicImage, UnsupportedFormat> {
+    let high_depth = high_depth && bit_depth(format.format).is_some_and(|bits| bits > 8);
+    Ok(if high_depth {
+        DynamicImage::ImageRgb16(DynamicImage::from(to_rgba16(format, data)?).into_rgb16())
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::from(to_rgba8(format, data)?).into_rgb8())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One pixel of every format with its RGBA8 value. Channels are distinct so swapped ones
+    /// show up, padding bits are set so reading them shows up as well.
+    const PIXELS: &[(Format, u64, [u8; 4])] = &[
+        // R=7 G=4 B=1
+        (Format::Rgb332, 0xF1, [255, 146, 85, 255]),
+        (Format::Bgr233, 0x67, [255, 146, 85, 255]),
+        // R=1 G=2 B=3 A=4
+        (Format::Xrgb4444, 0xF123, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Xbgr4444, 0xF321, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Rgbx4444, 0x123F, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Bgrx4444, 0x321F, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Argb4444, 0x4123, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Abgr4444, 0x4321, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Rgba4444, 0x1234, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Bgra4444, 0x3214, [0x11, 0x22, 0x33, 0x44]),
+        // R=31 G=16 B=8 A=0
+        (Format::Xrgb1555, 0xFE08, [255, 132, 66, 255]),
+        (Format::Xbgr1555, 0xA21F, [255, 132, 66, 255]),
+        (Format::Rgbx5551, 0xFC11, [255, 132, 66, 255]),
+        (Format::Bgrx5551, 0x443F, [255, 132, 66, 255]),
+        (Format::Argb1555, 0x7E08, [255, 132, 66, 0]),
+        (Format::Abgr1555, 0x221F, [255, 132, 66, 0]),
+        (Format::Rgba5551, 0xFC10, [255, 132, 66, 0]),
+        (Format::Bgra5551, 0x443E, [255, 132, 66, 0]),
+        // R=31 G=32 B=8
+        (Format::Rgb565, 0xFC08, [255, 130, 66, 255]),
+        (Format::Bgr565, 0x441F, [255, 130, 66, 255]),
+        (Format::Rgb888, 0x112233, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Bgr888, 0x332211, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Xrgb8888, 0x99112233, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Xbgr8888, 0x99332211, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Rgbx8888, 0x11223399, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Bgrx8888, 0x33221199, [0x11, 0x22, 0x33, 0xFF]),
+        (Format::Argb8888, 0x44112233, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Abgr8888, 0x44332211, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Rgba8888, 0x11223344, [0x11, 0x22, 0x33, 0x44]),
+        (Format::Bgra8888, 0x33221144, [0x11, 0x22, 0x33, 0x44]),
+        // R=1023 G=512 B=256 A=1
+        (Format::Xrgb2101010, 0xFFF80100, [255, 128, 64, 255]),
+        (Format::Xbgr2101010, 0xD00803FF, [255, 128, 64, 255]),
+        (Format::Rgbx1010102, 0xFFE00403, [255, 128, 64, 255]),
+        (Format::Bgrx1010102, 0x40200FFE, [255, 128, 64, 255]),
+        (Format::Argb2101010, 0x7FF80100, [255, 128, 64, 85]),
+        (Format::Abgr2101010, 0x500803FF, [255, 128, 64, 85]),
+        (Format::Rgba1010102, 0xFFE00401, [255, 128, 64, 85]),
+        (Format::Bgra1010102, 0x40200FFD, [255, 128, 64, 85]),
+        // R=0xFFFF G=0x8000 B=0x0101 A=0x4040
+        (
+            Format::Xrgb16161616,
+            0x1234_FFFF_8000_0101,
+            [255, 128, 1, 255],
+        ),
+        (
+            Format::Xbgr16161616,
+            0x1234_0101_8000_FFFF,
+            [255, 128, 1, 255],
+        ),
+        (
+            Format::Argb16161616,
+            0x4040_FFFF_8000_0101,
+            [255, 128, 1, 64],
+        ),
+        (
+            Format::Abgr16161616,
+            0x4040_0101_8000_FFFF,
+            [255, 128, 1, 64],
+        ),
+        // R=1.5 G=0.5 B=-0.25 A=0.25, padding is NaN
+        (
+            Format::Xrgb16161616f,
+            0x7E00_3E00_3800_B400,
+            [255, 128, 0, 255],
+        ),
+        (
+            Format::Xbgr16161616f,
+            0x7E00_B400_3800_3E00,
+            [255, 128, 0, 255],
+        ),
+        (
+            Format::Argb16161616f,
+            0x3400_3E00_3800_B400,
+            [255, 128, 0, 64],
+        ),
+        (
+            Format::Abgr16161616f,
+            0x3400_B400_3800_3E00,
+            [255, 128, 0, 64],
+        ),
+    ];
+
+    fn frame(format: Format, width: u32, height: u32, stride: u32) -> FrameFormat {
+        FrameFormat {
+            format,
+            width,
+            height,
+            stride,
+        }
+    }
+
+    /// A single pixel of `format` in memory.
+    fn pixel_bytes(format: Format, pixel: u64) -> Vec<u8> {
+        let bytes_per_pixel = layout(format).unwrap().bytes_per_pixel;
+        pixel.to_le_bytes()[..bytes_per_pixel].to_vec()
+    }
+
+    #[test]
+    fn every_format_converts_a_known_pixel() {
+        for &(format, pixel, expected) in PIXELS {
+            let data = pixel_bytes(format, pixel);
+            let image = to_rgba8(frame(format, 1, 1, data.len() as u32), &data).unwrap();
+            assert_eq!(image.get_pixel(0, 0).0, expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn alpha_follows_the_format() {
+        for &(format, _, expected) in PIXELS {
+            let name = format!("{format:?}");
+            let with_alpha = name.contains('a') || name.starts_with('A');
+            let layout = layout(format).unwrap();
+            assert_eq!(layout.a.is_some(), with_alpha, "{format:?}");
+            if !with_alpha {
+                assert_eq!(expected[3], 255, "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn stride_padding_is_skipped() {
+        // Two pixels per row, followed by padding that would show up as a third one
+        let data = [
+            [0x33, 0x22, 0x11, 0x44],
+            [0x03, 0x02, 0x01, 0x04],
+            [0xEE; 4],
+            [0x30, 0x20, 0x10, 0x40],
+            [0x00, 0x00, 0x00, 0xFF],
+            [0xEE; 4],
+        ]
+        .concat();
+        let image = to_rgba8(frame(Format::Argb8888, 2, 2, 12), &data).unwrap();
+        let pixels = image.pixels().map(|pixel| pixel.0).collect::<Vec<_>>();
+        assert_eq!(
+            pixels,
+            [
+                [0x11, 0x22, 0x33, 0x44],
+                [0x01, 0x02, 0x03, 0x04],
+                [0x10, 0x20, 0x30, 0x40],
+                [0x00, 0x00, 0x00, 0xFF],
+            ]
+        );
+
+        // Three byte pixels leave rows that don't end on a pixel boundary
+        let data = [
+            0x33, 0x22, 0x11, 0x03, 0x02, 0x01, 0xEE, 0xEE, 0x30, 0x20, 0x10,
+        ];
+        let image = to_rgba8(frame(Format::Rgb888, 1, 2, 8), &data).unwrap();
+        let pixels = image.pixels().map(|pixel| pixel.0).collect::<Vec<_>>();
+        assert_eq!(pixels, [[0x11, 0x22, 0x33, 0xFF], [0x10, 0x20, 0x30, 0xFF]]);
+    }
+
+    #[test]
+    fn ten_bit_channels_expand_to_sixteen() {
+        let data = pixel_bytes(Format::Argb2101010, 0x7FF80100);
+        let image = to_rgba16(frame(Format::Argb2101010, 1, 1, 4), &data).unwrap();
+        // 1023, 512, 256 and 1 of 3 scaled to the full range
+        assert_eq!(image.get_pixel(0, 0).0, [65535, 32800, 16400, 21845]);
+
+        // The extremes map to the extremes
+        let data = pixel_bytes(Format::Xbgr2101010, 0x3FF00000);
+        let image = to_rgba16(frame(Format::Xbgr2101010, 1, 1, 4), &data).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 65535, 65535]);
+    }
+
+    #[test]
+    fn half_floats_get_clamped_and_rounded() {
+        // 1.5 clamps to 1, 0.5 rounds up from 32767.5, -0.25 clamps to 0, 0.25 rounds 16383.75
+        let data = pixel_bytes(Format::Abgr16161616f, 0x3400_B400_3800_3E00);
+        let image = to_rgba16(frame(Format::Abgr16161616f, 1, 1, 8), &data).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [65535, 32768, 0, 16384]);
+
+        // Infinities clamp like any other value out of range
+        let data = pixel_bytes(Format::Xrgb16161616f, 0x0000_7C00_FC00_3C00);
+        let image = to_rgba16(frame(Format::Xrgb16161616f, 1, 1, 8), &data).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [65535, 0, 65535, 65535]);
+    }
+
+    #[test]
+    fn eight_bit_channels_survive_sixteen_bits() {
+        let data = (0..=255u8)
+            .flat_map(|value| [value, 0, 0, 0])
+            .collect::<Vec<_>>();
+        let image = to_rgba8(frame(Format::Xbgr8888, 256, 1, 1024), &data).unwrap();
+        for (value, pixel) in image.pixels().enumerate() {
+            assert_eq!(pixel.0[0] as usize, value);
+        }
+    }
+
+    #[test]
+    fn captures_save_without_alpha() {
+        let image = |format: Format, high_depth: bool| {
+            // Transparent wherever the format has alpha bits
+            let data = vec![0; layout(format).unwrap().bytes_per_pixel];
+            to_image(frame(format, 1, 1, data.len() as u32), &data, high_depth).unwrap()
+        };
+        for &(format, _, _) in PIXELS {
+            let image = image(format, false);
+            assert!(matches!(image, DynamicImage::ImageRgb8(_)), "{format:?}");
+        }
+        assert!(matches!(
+            image(Format::Argb2101010, true),
+            DynamicImage::ImageRgb16(_)
+        ));
+        assert!(matches!(
+            image(Format::Abgr16161616f, true),
+            DynamicImage::ImageRgb16(_)
+        ));
+        // 8 bit formats stay 8 bits even when more are asked for
+        assert!(matches!(
+            image(Format::Argb8888, true),
+            DynamicImage::ImageRgb8(_)
+        ));
+    }
+
+    #[test]
+    fn bit_depths() {
+        assert_eq!(bit_depth(Format::Rgb565), Some(6));
+        assert_eq!(bit_depth(Format::Xrgb8888), Some(8));
+        assert_eq!(bit_depth(Format::Xrgb2101010), Some(10));
+        assert_eq!(bit_depth(Format::Abgr16161616f), Some(16));
+        assert_eq!(bit_depth(Format::Yuyv), None);
+    }
+
+    #[test]
+    fn unsupported_formats_are_errors() {
+        let result = to_rgba8(frame(Format::Nv12, 1, 1, 1), &[0]);
+        assert_eq!(result.unwrap_err(), UnsupportedFormat(Format::Nv12));
+    }
+}
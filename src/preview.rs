@@ -0,0 +1,451 @@
+//! A window showing the capture before it gets saved, with a toolbar to save, copy, annotate,
+//! capture again or discard it.
+//!
+//! The window opens at the logical size of the capture, shrunk to fit [`MAX_SIZE`], and renders
+//! at the fractional scale of its output. `e` runs the annotation editor on the image inside of
+//! the window, the selection of the editor crops it.
+
+use std::{env, os::unix::process::CommandExt, process};
+
+use image::{imageops, DynamicImage, ImageFormat, RgbaImage};
+use xkbcommon::xkb::Keysym;
+
+use crate::{
+    annotate::{self, Annotation, Point, Shape, Target, View},
+    canvas::Canvas,
+    cli::OutputTarget,
+    clipboard,
+    config::{Color, Config, EditorConfig, Theme},
+    editor::Editor,
+    encode_image,
+    overlay::{Event, BTN_LEFT},
+    redact,
+    types::Rect,
+    wayland_data, write_image,
+};
+
+/// Largest logical size the window asks for, without the toolbar.
+const MAX_SIZE: (f64, f64) = (1280.0, 800.0);
+/// Height of the toolbar below the image, in logical pixels.
+pub(crate) const TOOLBAR: f64 = 36.0;
+/// Font pixels of the toolbar, in logical pixels.
+const TOOLBAR_PIXEL: f64 = 1.5;
+/// Space around the buttons and their labels, in logical pixels.
+const PADDING: f64 = 8.0;
+const BACKGROUND: Color = Color([40, 40, 40, 255]);
+
+/// What the user decided to do with the capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Save,
+    Copy,
+    /// Run the same command again.
+    Recapture,
+    Discard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Button {
+    Done(Outcome),
+    Edit,
+}
+
+const BUTTONS: [(Button, &str); 5] = [
+    (Button::Done(Outcome::Save), "Save s"),
+    (Button::Done(Outcome::Copy), "Copy c"),
+    (Button::Edit, "Edit e"),
+    (Button::Done(Outcome::Recapture), "Recapture r"),
+    (Button::Done(Outcome::Discard), "Discard Esc"),
+];
+
+pub(crate) struct Preview {
+    image: DynamicImage,
+    pixels_per_logical: f64,
+    /// 8 bit copy of `image` for the window.
+    shown: RgbaImage,
+    /// `shown` resized for the current buffer, so frames don't resize it again.
+    scaled: Option<RgbaImage>,
+    editor_config: EditorConfig,
+    /// Annotating the image, in logical pixels of the image.
+    editor: Option<Editor>,
+    /// In logical pixels of the window.
+    pointer: Point,
+    pub(crate) outcome: Option<Outcome>,
+}
+
+impl Preview {
+    pub(crate) fn new(
+        image: DynamicImage,
+        pixels_per_logical: f64,
+        editor_config: EditorConfig,
+    ) -> Preview {
+        Preview {
+            shown: image.to_rgba8(),
+            image,
+            pixels_per_logical,
+            scaled: None,
+            editor_config,
+            editor: None,
+            pointer: (0.0, 0.0),
+            outcome: None,
+        }
+    }
+
+    fn logical_size(&self) -> (f64, f64) {
+        (
+            self.shown.width() as f64 / self.pixels_per_logical,
+            self.shown.height() as f64 / self.pixels_per_logical,
+        )
+    }
+
+    /// Logical size the window asks for before the compositor picks one.
+    pub(crate) fn preferred_size(&self) -> (u32, u32) {
+        let (width, height) = self.logical_size();
+        let zoom = (MAX_SIZE.0 / width).min(MAX_SIZE.1 / height).min(1.0);
+        (
+            (width * zoom).round().max(1.0) as u32,
+            ((height * zoom).round() + TOOLBAR) as u32,
+        )
+    }
+
+    /// Top left corner of the image in a window of `size` logical pixels, and the window pixels
+    /// per logical pixel of the image. Images never get enlarged.
+    fn layout(&self, (width, height): (u32, u32)) -> (Point, f64) {
+        let (image_width, image_height) = self.logical_size();
+        let area = (width as f64, (height as f64 - TOOLBAR).max(1.0));
+        let zoom = (area.0 / image_width).min(area.1 / image_height).min(1.0);
+        (
+            (
+                ((area.0 - image_width * zoom) / 2.0).round(),
+                ((area.1 - image_height * zoom) / 2.0).round(),
+            ),
+            zoom,
+        )
+    }
+
+    /// The buttons of the toolbar with their corners, in logical pixels of the window.
+    fn buttons(size: (u32, u32)) -> Vec<(Button, &'static str, Point, Point)> {
+        let top = size.1 as f64 - TOOLBAR + PADDING / 2.0;
+        let bottom = size.1 as f64 - PADDING / 2.0;
+        let mut x = PADDING / 2.0;
+        BUTTONS
+            .iter()
+            .map(|&(button, label)| {
+                let width = annotate::text_size(label, TOOLBAR_PIXEL).0 + PADDING * 2.0;
+                let corners = ((x, top), (x + width, bottom));
+                x += width + PADDING / 2.0;
+                (button, label, corners.0, corners.1)
+            })
+            .collect()
+    }
+
+    fn press(&mut self, button: Button) {
+        match button {
+            Button::Done(outcome) => self.outcome = Some(outcome),
+            Button::Edit => {
+                let (width, height) = self.logical_size();
+                let whole = Rect {
+                    x: 0,
+                    y: 0,
+                    width: width.round() as i32,
+                    height: height.round() as i32,
+                };
+                self.editor = Some(Editor::new(&self.editor_config).with_selection(whole));
+            }
+        }
+    }
+
+    /// Handles one event with the pointer in logical pixels of a window of `size`, returns
+    /// whether the window has to be redrawn.
+    pub(crate) fn handle(&mut self, event: Event, size: (u32, u32)) -> bool {
+        if let Event::Motion(position) = event {
+            self.pointer = position;
+        }
+
+        let ((x, y), zoom) = self.layout(size);
+        if let Some(editor) = &mut self.editor {
+            let event = match event {
+                Event::Motion((pointer_x, pointer_y)) => {
+                    Event::Motion(((pointer_x - x) / zoom, (pointer_y - y) / zoom))
+                }
+                event => event,
+            };
+            let redraw = editor.handle(event);
+            if editor.finished.is_some() {
+                let editor = self.editor.take().unwrap();
+                if editor.finished == Some(true) {
+                    self.apply(&editor);
+                }
+                return true;
+            }
+            return redraw;
+        }
+
+        match event {
+            // Buttons light up under the pointer
+            Event::Motion(_) => true,
+            Event::Button {
+                button: BTN_LEFT,
+                pressed: true,
+            } => {
+                let (x, y) = self.pointer;
+                let clicked = Preview::buttons(size)
+                    .into_iter()
+                    .find(|(_, _, min, max)| x >= min.0 && x < max.0 && y >= min.1 && y < max.1);
+                match clicked {
+                    Some((button, ..)) => {
+                        self.press(button);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Event::Key(key) => {
+                let button = match key.keysym {
+                    Keysym::Return | Keysym::KP_Enter => Button::Done(Outcome::Save),
+                    Keysym::Escape => Button::Done(Outcome::Discard),
+                    _ => match key.text.as_str() {
+                        "s" => Button::Done(Outcome::Save),
+                        "c" => Button::Done(Outcome::Copy),
+                        "e" => Button::Edit,
+                        "r" => Button::Done(Outcome::Recapture),
+                        "q" => Button::Done(Outcome::Discard),
+                        _ => return false,
+                    },
+                };
+                self.press(button);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Burns the edits into the image: redactions first, then the crop to the selection and
+    /// the annotations on top.
+    fn apply(&mut self, editor: &Editor) {
+        let scale = self.pixels_per_logical;
+        let bounds = Rect {
+            x: 0,
+            y: 0,
+            width: self.image.width() as i32,
+            height: self.image.height() as i32,
+        };
+        let to_pixels = |rect: Rect| {
+            Rect::spanning(
+                (rect.x as f64 * scale, rect.y as f64 * scale),
+                (
+                    (rect.x + rect.width) as f64 * scale,
+                    (rect.y + rect.height) as f64 * scale,
+                ),
+            )
+            .intersection(&bounds)
+        };
+
+        for redaction in editor.annotations.redactions() {
+            if let Some(area) = to_pixels(redaction.rect) {
+                redact::redact(&mut self.image, area, redaction.style, scale);
+            }
+        }
+        if let Some(crop) = editor.selection().and_then(to_pixels) {
+            let cropped = self.image.crop_imm(
+                crop.x as u32,
+                crop.y as u32,
+                crop.width as u32,
+                crop.height as u32,
+            );
+            self.image = annotate::rasterize(
+                cropped,
+                (crop.x as f64 / scale, crop.y as f64 / scale),
+                (scale, scale),
+                &editor.annotations,
+            );
+        }
+        self.shown = self.image.to_rgba8();
+        self.scaled = None;
+    }
+
+    /// Renders a window of `size` logical pixels into `canvas`, which is at the scale of the
+    /// window.
+    pub(crate) fn draw(&mut self, canvas: &mut Canvas, size: (u32, u32), theme: &Theme) {
+        canvas.fill(BACKGROUND);
+        let buffer_size = canvas.size();
+        let ((x, y), zoom) = self.layout(size);
+        let scale = (
+            buffer_size.0 as f64 / size.0 as f64,
+            buffer_size.1 as f64 / size.1 as f64,
+        );
+
+        let (width, height) = self.logical_size();
+        let scaled_size = (
+            ((width * zoom * scale.0).round() as u32).max(1),
+            ((height * zoom * scale.1).round() as u32).max(1),
+        );
+        let scaled = match self.scaled.take() {
+            Some(scaled) if scaled.dimensions() == scaled_size => scaled,
+            _ => imageops::resize(
+                &self.shown,
+                scaled_size.0,
+                scaled_size.1,
+                imageops::FilterType::Triangle,
+            ),
+        };
+        let (left, top) = ((x * scale.0).round() as i32, (y * scale.1).round() as i32);
+        canvas.blit(&scaled, (left, top));
+        self.scaled = Some(scaled);
+
+        let window = View {
+            origin: (0.0, 0.0),
+            scale,
+        };
+        if let Some(editor) = &self.editor {
+            let image = View {
+                origin: (-x / zoom, -y / zoom),
+                scale: (zoom * scale.0, zoom * scale.1),
+            };
+            draw_editor(canvas, image, editor, (width, height), theme);
+        }
+
+        // Toolbar
+        let mut background = theme.tint;
+        background.0[3] = 255;
+        annotate::draw_box(
+            canvas,
+            window,
+            (0.0, size.1 as f64 - TOOLBAR),
+            (size.0 as f64, size.1 as f64),
+            background,
+        );
+        let label_height = annotate::text_size("", TOOLBAR_PIXEL).1;
+        let label_top = size.1 as f64 - (TOOLBAR + label_height) / 2.0;
+        if let Some(editor) = &self.editor {
+            annotate::draw_text(
+                canvas,
+                window,
+                (PADDING, label_top),
+                &editor.status(),
+                TOOLBAR_PIXEL,
+                Color([255, 255, 255, 255]),
+            );
+            return;
+        }
+        let (pointer_x, pointer_y) = self.pointer;
+        for (_, label, min, max) in Preview::buttons(size) {
+            let hovered =
+                pointer_x >= min.0 && pointer_x < max.0 && pointer_y >= min.1 && pointer_y < max.1;
+            let fill = if hovered {
+                Color([255, 255, 255, 80])
+            } else {
+                Color([255, 255, 255, 30])
+            };
+            annotate::draw_box(canvas, window, min, max, fill);
+            annotate::draw_text(
+                canvas,
+                window,
+                (min.0 + PADDING, label_top),
+                label,
+                TOOLBAR_PIXEL,
+                Color([255, 255, 255, 255]),
+            );
+        }
+    }
+
+    pub(crate) fn into_image(self) -> DynamicImage {
+        self.image
+    }
+}
+
+/// Draws the editor over the image of `size` logical pixels: the selection with everything
+/// outside of it dimmed, then the annotations.
+fn draw_editor(canvas: &mut Canvas, view: View, editor: &Editor, size: (f64, f64), theme: &Theme) {
+    if let Some(selection) = editor.selection() {
+        let (left, top) = (selection.x as f64, selection.y as f64);
+        let (right, bottom) = (
+            (selection.x + selection.width) as f64,
+            (selection.y + selection.height) as f64,
+        );
+        let mut tint = theme.tint;
+        tint.0[3] = theme.alpha;
+        for (min, max) in [
+            ((0.0, 0.0), (size.0, top)),
+            ((0.0, bottom), size),
+            ((0.0, top), (left, bottom)),
+            ((right, top), (size.0, bottom)),
+        ] {
+            annotate::draw_box(canvas, view, min, max, tint);
+        }
+        annotate::draw(
+            canvas,
+            view,
+            &Annotation {
+                shape: Shape::Rectangle {
+                    from: (left, top),
+                    to: (right, bottom),
+                },
+                color: theme.border_color,
+                width: 1.0,
+            },
+        );
+    }
+    annotate::draw_all(canvas, view, editor.annotations.iter());
+    if let Some(pending) = editor.pending() {
+        annotate::draw(canvas, view, &pending);
+    }
+}
+
+/// Shows `image`, which has `pixels_per_logical` pixels per logical pixel, until the user
+/// decides what to do with it. Returns the decision and the image with any edits.
+pub(crate) fn show(
+    image: DynamicImage,
+    pixels_per_logical: f64,
+    config: &Config,
+) -> (Outcome, DynamicImage) {
+    let preview = Preview::new(image, pixels_per_logical, config.editor.clone());
+    wayland_data::show_preview(preview, config.theme.clone())
+}
+
+/// Shows `image` and then saves it to `target` or copies it. Capturing again and discarding are
+/// left to the caller, which may have more images to review first, see [`conclude`].
+pub(crate) fn review(
+    image: DynamicImage,
+    pixels_per_logical: f64,
+    target: &OutputTarget,
+    format: ImageFormat,
+    config: &Config,
+) -> Outcome {
+    let (outcome, image) = show(image, pixels_per_logical, config);
+    match outcome {
+        Outcome::Save => {
+            log::info!("Saving {target}");
+            write_image(&image, target, format);
+        }
+        Outcome::Copy => {
+            let png = encode_image(&image, ImageFormat::Png);
+            match clipboard::copy(&png, "image/png") {
+                Ok(()) => log::info!("Copied to the clipboard"),
+                Err(err) => {
+                    log::error!("Failed to copy the capture: {err}");
+                    process::exit(1);
+                }
+            }
+        }
+        Outcome::Recapture | Outcome::Discard => {}
+    }
+    outcome
+}
+
+/// Acts on the `outcomes` of every reviewed image once they all got saved or copied: captures
+/// again when any asked for it, fails when every image got discarded.
+pub(crate) fn conclude(outcomes: &[Outcome]) {
+    if outcomes.contains(&Outcome::Recapture) {
+        // A fresh process starts over with a fresh capture and the same arguments
+        let args = env::args_os().collect::<Vec<_>>();
+        let program = env::current_exe().unwrap_or_else(|_| args[0].clone().into());
+        let err = process::Command::new(program).args(&args[1..]).exec();
+        log::error!("Failed to capture again: {err}");
+        process::exit(1);
+    }
+    if !outcomes.is_empty() && outcomes.iter().all(|&outcome| outcome == Outcome::Discard) {
+        log::info!("Discarded");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, ErrorKind, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use wayland_client::protocol::wl_shm::WlShm;
+use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+use crate::{
+    cli::{RecordArgs, StreamFormat},
+    pixel_format,
+    wayland::{
+        capture_frame, get_screen_data, output_exists,
+        types::{Delegate, FrameBuffer, FrameFormat},
+        WaylandVarsNew,
+    },
+};
+
+pub(crate) fn record(vars: &mut WaylandVarsNew, args: &RecordArgs) -> io::Result<()> {
+    let (screen, output) = get_screen_data(vars)
+        .into_iter()
+        .nth(args.screen)
+        .expect("Output not found");
+    let screencopy_manager: ZwlrScreencopyManagerV1 =
+        vars.globals.bind(&vars.qh, 1..=1, ()).unwrap();
+    let shm: WlShm = vars.globals.bind(&vars.qh, 1..=1, ()).unwrap();
+
+    let mut writer = BufWriter::new(open_destination(&args.output));
+    let interval = Duration::from_secs(1) / args.fps;
+
+    let mut frame_buffer: Option<FrameBuffer> = None;
+    let mut pixels = vec![];
+    let (mut rgba, mut planes) = (vec![], vec![]);
+    // Presentation time of the first frame, and when we received it
+    let mut start: Option<(Duration, Instant)> = None;
+    let mut frames_written = 0u64;
+
+    loop {
+        let previous_format = frame_buffer.as_ref().map(|buffer| buffer.format);
+        let Some(presented_at) =
+            capture_frame(vars, &screencopy_manager, &shm, &output, &mut frame_buffer)
+        else {
+            // Frames of an unplugged output fail, that ends the recording like a layout change
+            vars.event_queue.roundtrip(&mut Delegate).unwrap();
+            if !output_exists(vars, screen.name) {
+                log::warn!("Output removed, stopping");
+                let _ = writer.flush();
+                return Ok(());
+            }
+            panic!("Screencopy failed");
+        };
+        let frame_buffer = frame_buffer.as_ref().unwrap();
+        let format = frame_buffer.format;
+
+        match previous_format {
+            None => write_header(&mut writer, args, format).unwrap(),
+            // Encoders can't deal with the frame size changing mid stream
+            Some(previous_format) if previous_format != format => {
+                log::warn!("Output layout changed, stopping");
+                break;
+            }
+            Some(_) => {}
+        }
+        frame_buffer.read(&mut pixels);
+
+        let (first_presented, first_received) =
+            *start.get_or_insert((presented_at, Instant::now()));
+        let elapsed = presented_at.saturating_sub(first_presented);
+
+        // Emit the frame once for every slot of the target framerate it covers, so the stream
+        // keeps a constant rate when the compositor is slower or faster than requested.
+        while interval * frames_written as u32 <= elapsed {
+            match write_frame(
+                &mut writer,
+                args.format,
+                format,
+                &pixels,
+                &mut rgba,
+                &mut planes,
+            ) {
+                Ok(()) => {}
+                // The encoder went away, nothing left to do
+                Err(err) if err.kind() == ErrorKind::BrokenPipe => return Ok(()),
+                Err(err) => {
+                    // Keep the frames written so far
+                    let _ = writer.flush();
+                    return Err(err);
+                }
+            }
+            frames_written += 1;
+
+            if args.frames.is_some_and(|frames| frames_written >= frames) {
+                let _ = writer.flush();
+                return Ok(());
+            }
+        }
+        match writer.flush() {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        // Sleep until the next slot is due
+        let next_due = first_received + interval * frames_written as u32;
+        if let Some(wait) = next_due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+    Ok(())
+}
+
+fn open_destination(path: &Path) -> Box<dyn Write> {
+    if path == Path::new("-") {
+        return Box::new(io::stdout().lock());
+    }
+    // A FIFO blocks here until the reading end is opened
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .expect("Failed to open output");
+    Box::new(file)
+}
+
+fn write_header(writer: &mut impl Write, args: &RecordArgs, format: FrameFormat) -> io::Result<()> {
+    let (width, height) = (format.width, format.height);
+    match args.format {
+        StreamFormat::Y4m => writeln!(
+            writer,
+            "YUV4MPEG2 W{width} H{height} F{}:1 Ip A1:1 C444 XCOLORRANGE=LIMITED",
+            args.fps
+        ),
+        StreamFormat::Rawvideo => {
+            let header = serde_json::json!({
+                "width": width,
+                "height": height,
+                "pixel_format": "rgba",
+                "fps": args.fps,
+                "frame_size": width * height * 4,
+            });
+            writeln!(writer, "{header}")
+        }
+    }
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    stream_format: StreamFormat,
+    format: FrameFormat,
+    pixels: &[u8],
+    rgba: &mut Vec<u8>,
+    planes: &mut Vec<u8>,
+) -> io::Result<()> {
+    rgba.clear();
+    pixel_format::rgba8_into(format, pixels, rgba).map_err(io::Error::other)?;
+
+    match stream_format {
+        StreamFormat::Rawvideo => writer.write_all(rgba),
+        StreamFormat::Y4m => {
+            // Planar Y, then U, then V
+            let pixel_count = format.width as usize * format.height as usize;
+            planes.resize(pixel_count * 3, 0);
+            let (luma, chroma) = planes.split_at_mut(pixel_count);
+            let (cb, cr) = chroma.split_at_mut(pixel_count);
+            for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+                let (y, u, v) = rgb_to_yuv(pixel[0], pixel[1], pixel[2]);
+                luma[i] = y;
+                cb[i] = u;
+                cr[i] = v;
+            }
+            writer.write_all(b"FRAME\n")?;
+            writer.write_all(planes)
+        }
+    }
+}
+
+/// BT.601 limited range conversion.
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
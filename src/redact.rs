@@ -0,0 +1,226 @@
+//! Hides sensitive parts of a capture for good before anything else sees it.
+//!
+//! Redactions are given in the global logical coordinate space and applied to the physical
+//! capture of every output they cover, rounded outwards so partially covered pixels get
+//! redacted too. Stitching, scaling, annotating and encoding only ever see the redacted pixels.
+
+use clap::ValueEnum;
+use image::{imageops::FilterType, DynamicImage, GenericImage, GenericImageView, Rgba};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    daemon::Region,
+    stitch::{capture_scale, logical_rect},
+    types::Rect,
+    wayland::types::ScreenData,
+};
+
+/// Size of a pixelated block, in logical pixels.
+const BLOCK: f64 = 10.0;
+/// Distance between the samples a blur is computed from, in logical pixels. Throwing away
+/// everything in between keeps the detail from being recovered by deblurring.
+const BLUR_SAMPLE: f64 = 4.0;
+/// Standard deviation of the blur, in samples.
+const BLUR_SIGMA: f32 = 2.0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RedactStyle {
+    /// Averages blocks of pixels.
+    #[default]
+    Pixelate,
+    /// A gaussian blur of a coarse copy.
+    Blur,
+    /// Solid black.
+    Black,
+}
+
+impl RedactStyle {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RedactStyle::Pixelate => "Pixelate",
+            RedactStyle::Blur => "Blur",
+            RedactStyle::Black => "Blackout",
+        }
+    }
+}
+
+/// A rectangle to hide, in global logical coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Redaction {
+    pub(crate) rect: Rect,
+    pub(crate) style: RedactStyle,
+}
+
+/// The `--redact` rectangles, all hidden the same way.
+pub(crate) fn from_regions(regions: &[Region], style: RedactStyle) -> Vec<Redaction> {
+    regions
+        .iter()
+        .map(|&region| Redaction {
+            rect: region.into(),
+            style,
+        })
+        .collect()
+}
+
+/// Pixels of a `size` pixels large capture of `screen` touched by `rect`, rounded outwards.
+fn covered_pixels(rect: Rect, screen: &ScreenData, size: (u32, u32)) -> Option<Rect> {
+    let output = logical_rect(screen);
+    let part = rect.intersection(&output)?;
+    let (scale_x, scale_y) = capture_scale(screen, size);
+    let (x, y) = (part.x - output.x, part.y - output.y);
+    let covered = Rect::spanning(
+        (x as f64 * scale_x, y as f64 * scale_y),
+        (
+            (x + part.width) as f64 * scale_x,
+            (y + part.height) as f64 * scale_y,
+        ),
+    );
+    covered.intersection(&Rect {
+        x: 0,
+        y: 0,
+        width: size.0 as i32,
+        height: size.1 as i32,
+    })
+}
+
+/// Applies every redaction covering `screen` to its physical capture `img`.
+pub(crate) fn redact_output(img: &mut DynamicImage, screen: &ScreenData, redactions: &[Redaction]) {
+    let size = img.dimensions();
+    let (scale, _) = capture_scale(screen, size);
+    for redaction in redactions {
+        if let Some(area) = covered_pixels(redaction.rect, screen, size) {
+            log::debug!("Redacting {area:?} of output {}", screen.name);
+            redact(img, area, redaction.style, scale);
+        }
+    }
+}
+
+/// Replaces `area` of `img`, `scale` being its pixels per logical pixel.
+pub(crate) fn redact(img: &mut DynamicImage, area: Rect, style: RedactStyle, scale: f64) {
+    let (x, y, width, height) = (
+        area.x as u32,
+        area.y as u32,
+        area.width as u32,
+        area.height as u32,
+    );
+    let samples =
+        |length: u32, distance: f64| ((length as f64 / (distance * scale)).round() as u32).max(1);
+
+    let patch = match style {
+        RedactStyle::Black => {
+            let mut patch = img.crop_imm(x, y, width, height);
+            for y in 0..height {
+                for x in 0..width {
+                    patch.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+                }
+            }
+            patch
+        }
+        RedactStyle::Pixelate => img
+            .crop_imm(x, y, width, height)
+            // A triangle filter over the whole block averages it
+            .resize_exact(
+                samples(width, BLOCK),
+                samples(height, BLOCK),
+                FilterType::Triangle,
+            )
+            .resize_exact(width, height, FilterType::Nearest),
+        RedactStyle::Blur => img
+            .crop_imm(x, y, width, height)
+            .resize_exact(
+                samples(width, BLUR_SAMPLE),
+                samples(height, BLUR_SAMPLE),
+                FilterType::Triangle,
+            )
+            .blur(BLUR_SIGMA)
+            .resize_exact(width, height, FilterType::Triangle),
+    };
+    debug_assert_eq!(patch.dimensions(), (width, height));
+    img.copy_from(&patch, x, y).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const PINK: [u8; 3] = [200, 50, 100];
+
+    /// A checkerboard, so every pixel differs from any average of its neighbours.
+    fn checkerboard(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb(if (x + y) % 2 == 0 { WHITE } else { PINK })
+        }))
+    }
+
+    #[test]
+    fn covered_pixels_never_survive() {
+        // Twice as many pixels as logical pixels, right of another output
+        let screen = ScreenData {
+            name: 1,
+            connector: None,
+            resolution: (200, 100),
+            logical_resolution: (100, 50),
+            logical_position: (100, 0),
+        };
+        let original = checkerboard(200, 100);
+        // Starts on the other output, only x 100..130 and y 10..30 are on this one
+        let rect = Rect {
+            x: 50,
+            y: 10,
+            width: 80,
+            height: 20,
+        };
+        let covered = Rect {
+            x: 0,
+            y: 20,
+            width: 60,
+            height: 40,
+        };
+        assert_eq!(covered_pixels(rect, &screen, (200, 100)), Some(covered));
+
+        for style in [RedactStyle::Pixelate, RedactStyle::Blur, RedactStyle::Black] {
+            let mut img = original.clone();
+            redact_output(&mut img, &screen, &[Redaction { rect, style }]);
+            for (x, y, pixel) in img.pixels() {
+                let before = original.get_pixel(x, y);
+                if covered.contains((x as f64, y as f64)) {
+                    let [r, g, b, _] = pixel.0;
+                    assert!(
+                        ![WHITE, PINK].contains(&[r, g, b]),
+                        "{style:?} kept ({x}, {y})"
+                    );
+                } else {
+                    assert_eq!(pixel, before, "{style:?} changed ({x}, {y})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn redactions_elsewhere_leave_the_output_alone() {
+        let screen = ScreenData {
+            name: 1,
+            connector: None,
+            resolution: (100, 50),
+            logical_resolution: (100, 50),
+            logical_position: (0, 0),
+        };
+        let original = checkerboard(100, 50);
+        let mut img = original.clone();
+        let redaction = Redaction {
+            rect: Rect {
+                x: 100,
+                y: 0,
+                width: 50,
+                height: 50,
+            },
+            style: RedactStyle::Black,
+        };
+        redact_output(&mut img, &screen, &[redaction]);
+        assert_eq!(img, original);
+    }
+}
@@ -0,0 +1,213 @@
+//! The `region` command: select a part of the screen on the overlay, annotate it and save it.
+
+use std::{
+    io::{self, Read},
+    process,
+};
+
+use image::RgbaImage;
+use log::Level;
+
+use crate::{
+    annotate,
+    cli::{self, Geometry, OutputTarget, RegionArgs},
+    config,
+    editor::Editor,
+    ipc, last_region,
+    logging::Span,
+    overlay, pixel_format, preview, redact,
+    stitch::{logical_rect, stitch, target_scale},
+    types::{BuffersStore, Rect, Screenshot},
+    wayland::{self, create_popup, screenshot, types::ScreenData},
+    write_image,
+};
+
+/// 8 bit copies of the captures in `screens_buf`, for the magnifier. Outputs in formats we can't
+/// convert are left out.
+pub(crate) fn previews(
+    screenshots_data: &BuffersStore<Screenshot>,
+    screens_buf: &[u8],
+) -> Vec<(ScreenData, RgbaImage)> {
+    screenshots_data
+        .buffers_metadata
+        .iter()
+        .filter_map(|screenshot| {
+            let pixels = &screens_buf[screenshot.offset..screenshot.span];
+            let capture = pixel_format::to_rgba8(screenshot.format, pixels).ok()?;
+            Some((screenshot.screen_data.clone(), capture))
+        })
+        .collect()
+}
+
+/// The rectangle given with `-g`, read from stdin for `-`. Exits when it isn't valid.
+fn geometry(geometry: Geometry) -> Rect {
+    match geometry {
+        Geometry::Rect(rect) => rect,
+        Geometry::Stdin => {
+            let mut text = String::new();
+            if let Err(err) = io::stdin().read_to_string(&mut text) {
+                log::error!("Failed to read the geometry from stdin: {err}");
+                process::exit(1);
+            }
+            cli::parse_geometry(&text).unwrap_or_else(|err| {
+                log::error!("Invalid geometry on stdin: {err}");
+                process::exit(1);
+            })
+        }
+    }
+}
+
+pub(crate) fn run(args: &RegionArgs) {
+    let _total = Span::new(Level::Info, "Region");
+    let options = args.save.options();
+    let format = options.format;
+    let config = config::load();
+    // Read before capturing, by then slurp piping it in got its own overlay out of the way
+    let geometry = args.geometry.map(geometry);
+
+    let mut wayland_vars = wayland::init();
+    // Capture first, so the overlay doesn't end up in the image
+    let screenshots_file = tempfile::tempfile().unwrap();
+    let mut screenshots_data = {
+        let _span = Span::new(Level::Debug, "Screencopy");
+        screenshot(&mut wayland_vars, screenshots_file)
+    };
+    let mut screens_buf = Vec::with_capacity(screenshots_data.file_len);
+    screenshots_data
+        .buffer_file
+        .read_to_end(&mut screens_buf)
+        .unwrap();
+
+    let screens = screenshots_data
+        .buffers_metadata
+        .iter()
+        .map(|screenshot| &screenshot.screen_data)
+        .collect::<Vec<_>>();
+    let last_region = if args.last_region {
+        let last = last_region::load();
+        if last.is_none() {
+            log::warn!("No region was selected before, select one");
+        }
+        last
+    } else {
+        None
+    };
+    let (editor, screenshots) = match (geometry, last_region) {
+        (Some(geometry), _) => {
+            log::info!(
+                "Capturing {}x{} at {},{}",
+                geometry.width,
+                geometry.height,
+                geometry.x,
+                geometry.y
+            );
+            let editor = Editor::new(&config.editor).with_selection(geometry);
+            (editor, screenshots_data.buffers_metadata.clone())
+        }
+        (None, Some(last)) if last.matches(&screens) => {
+            log::info!("Capturing the last region again");
+            let editor = Editor::new(&config.editor).with_selection(last.selection);
+            (editor, screenshots_data.buffers_metadata.clone())
+        }
+        (None, last) => {
+            let mut editor = Editor::new(&config.editor).with_windows(ipc::windows());
+            let bounds = screens
+                .iter()
+                .map(|screen| logical_rect(screen))
+                .reduce(|bounds, rect| bounds.union(&rect));
+            if let Some(bounds) = bounds {
+                editor = editor.with_bounds(bounds);
+            }
+            if let Some(last) = last {
+                log::warn!("The outputs changed since the last region was selected, confirm it");
+                if last.visible(&screens) {
+                    editor = editor.with_selection(last.selection);
+                }
+            }
+            let mut popups = create_popup(&mut wayland_vars, &screenshots_data);
+            let captures = previews(&screenshots_data, &screens_buf);
+            let Some(editor) = overlay::edit(
+                &mut popups,
+                &mut wayland_vars,
+                &config.theme,
+                editor,
+                &captures,
+            ) else {
+                log::info!("Cancelled");
+                process::exit(1);
+            };
+            let screenshots = popups
+                .buffers_metadata
+                .into_iter()
+                .filter_map(|popup| popup.capture)
+                .collect();
+            (editor, screenshots)
+        }
+    };
+    let Some(selection) = editor.selection() else {
+        log::error!("Nothing got selected");
+        process::exit(1);
+    };
+    // A given geometry wasn't selected, `--last-region` keeps offering the last selection
+    if geometry.is_none() {
+        last_region::save(
+            selection,
+            &screenshots
+                .iter()
+                .map(|screenshot| &screenshot.screen_data)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let mut redactions = redact::from_regions(&args.save.redact, args.save.redact_style);
+    redactions.extend(editor.annotations.redactions());
+    let images = screenshots
+        .iter()
+        .map(|screenshot| {
+            let pixels = &screens_buf[screenshot.offset..screenshot.span];
+            let mut img = pixel_format::to_image(screenshot.format, pixels, options.high_depth)
+                .unwrap_or_else(|err| {
+                    log::error!("{err}");
+                    process::exit(1);
+                });
+            redact::redact_output(&mut img, &screenshot.screen_data, &redactions);
+            img
+        })
+        .collect::<Vec<_>>();
+    let outputs = screenshots
+        .iter()
+        .map(|screenshot| &screenshot.screen_data)
+        .zip(&images)
+        .collect::<Vec<_>>();
+
+    let _span = Span::new(Level::Debug, "Saving");
+    let scale = target_scale(selection, &outputs, options.scale);
+    let Some(img) = stitch(selection, &outputs, scale, options.filter.filter_type()) else {
+        log::error!("The selection covers no output");
+        process::exit(1);
+    };
+    let pixels_per_logical = (
+        img.width() as f64 / selection.width as f64,
+        img.height() as f64 / selection.height as f64,
+    );
+    let img = annotate::rasterize(
+        img,
+        (selection.x as f64, selection.y as f64),
+        pixels_per_logical,
+        &editor.annotations,
+    );
+
+    let target = args.save.output.clone().unwrap_or_else(|| {
+        let pictures = dirs::home_dir()
+            .expect("Home dir not found")
+            .join("Pictures");
+        OutputTarget::Path(pictures.join(format!("region.{}", format.extensions_str()[0])))
+    });
+    if args.preview {
+        let outcome = preview::review(img, pixels_per_logical.0, &target, format, &config);
+        preview::conclude(&[outcome]);
+        return;
+    }
+    log::info!("Saving {target}");
+    write_image(&img, &target, format);
+}
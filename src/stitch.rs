@@ -0,0 +1,244 @@
+//! Assembles a capture of a rectangle in the global logical coordinate space, which may span
+//! outputs with different scales.
+//!
+//! The result is `round(width * scale)` by `round(height * scale)` pixels. Every output covered by
+//! the selection contributes the part of the selection it overlaps: the edges of that part get
+//! mapped to its own physical pixels and to the result by multiplying their logical offsets with
+//! the respective scale and rounding half away from zero. Since neighbouring parts share their
+//! edges they tile the result without gaps or overlap, and the same inputs always produce the
+//! same pixels. Each part is resampled from its physical size to its size in the result with the
+//! chosen filter, parts of the selection outside of every output stay transparent.
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+use crate::{cli::SaveScale, pixel_format::Rgba16Image, types::Rect, wayland::types::ScreenData};
+
+/// The output in the global logical coordinate space.
+pub(crate) fn logical_rect(screen: &ScreenData) -> Rect {
+    let ((x, y), (width, height)) = (screen.logical_position, screen.logical_resolution);
+    Rect {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+/// Pixels of a capture of the whole `screen`, `size` pixels large, per logical pixel horizontally
+/// and vertically. The capture gets measured instead of taking the mode of the output, which
+/// ignores that the output may be rotated.
+pub(crate) fn capture_scale(screen: &ScreenData, (width, height): (u32, u32)) -> (f64, f64) {
+    let (l_width, l_height) = screen.logical_resolution;
+    (
+        width as f64 / l_width as f64,
+        height as f64 / l_height as f64,
+    )
+}
+
+/// Maps the edges `[start, end)` of a span from logical offsets to pixels.
+fn scale_span(start: i32, end: i32, scale: f64) -> (u32, u32) {
+    let scaled = |offset: i32| (offset as f64 * scale).round() as u32;
+    (scaled(start), scaled(end))
+}
+
+/// The part of `selection` that lies on `screen`, in pixels of an image of the whole output that
+/// is `size` pixels large.
+pub(crate) fn output_portion(
+    selection: Rect,
+    screen: &ScreenData,
+    size: (u32, u32),
+) -> Option<Rect> {
+    let output = logical_rect(screen);
+    let part = selection.intersection(&output)?;
+    let (scale_x, scale_y) = capture_scale(screen, size);
+
+    let (x, right) = scale_span(part.x - output.x, part.x + part.width - output.x, scale_x);
+    let (y, bottom) = scale_span(part.y - output.y, part.y + part.height - output.y, scale_y);
+    Some(Rect {
+        x: x as i32,
+        y: y as i32,
+        width: (right - x) as i32,
+        height: (bottom - y) as i32,
+    })
+}
+
+/// Pixels per logical pixel of the result. Physical keeps every pixel of the densest capture in
+/// `outputs` covered by the selection.
+pub(crate) fn target_scale(
+    selection: Rect,
+    outputs: &[(&ScreenData, &DynamicImage)],
+    scale: SaveScale,
+) -> f64 {
+    match scale {
+        SaveScale::Logical(factor) => factor,
+        SaveScale::Physical => outputs
+            .iter()
+            .filter(|(screen, _)| selection.intersection(&logical_rect(screen)).is_some())
+            .map(|(screen, image)| {
+                let (scale_x, scale_y) = capture_scale(screen, image.dimensions());
+                scale_x.max(scale_y)
+            })
+            .fold(1.0, f64::max),
+    }
+}
+
+/// Stitches the parts of `selection` shown on each output together, `outputs` holds the full
+/// physical capture of each output. The result keeps 16 bits per channel if any capture has them.
+/// Returns `None` when the selection covers no output.
+pub(crate) fn stitch(
+    selection: Rect,
+    outputs: &[(&ScreenData, &DynamicImage)],
+    scale: f64,
+    filter: FilterType,
+) -> Option<DynamicImage> {
+    let (_, width) = scale_span(0, selection.width, scale);
+    let (_, height) = scale_span(0, selection.height, scale);
+    let mut canvas = Rgba16Image::new(width.max(1), height.max(1));
+    // Which pixels of the result some output filled, outputs mirroring or overlapping each other
+    // must not make up for a gap elsewhere
+    let mut covered = vec![false; canvas.width() as usize * canvas.height() as usize];
+
+    let (mut opaque, mut deep) = (true, false);
+    for (screen, image) in outputs {
+        let Some(part) = selection.intersection(&logical_rect(screen)) else {
+            continue;
+        };
+        let source = output_portion(selection, screen, image.dimensions()).unwrap();
+        let (x, right) = scale_span(
+            part.x - selection.x,
+            part.x + part.width - selection.x,
+            scale,
+        );
+        let (y, bottom) = scale_span(
+            part.y - selection.y,
+            part.y + part.height - selection.y,
+            scale,
+        );
+        if right == x || bottom == y || source.width == 0 || source.height == 0 {
+            continue;
+        }
+
+        let mut piece = image.crop_imm(
+            source.x as u32,
+            source.y as u32,
+            source.width as u32,
+            source.height as u32,
+        );
+        if (piece.width(), piece.height()) != (right - x, bottom - y) {
+            piece = piece.resize_exact(right - x, bottom - y, filter);
+        }
+        opaque &= !piece.color().has_alpha();
+        deep |= piece.color().bytes_per_pixel() / piece.color().channel_count() > 1;
+        for row in y..bottom {
+            let start = (row * canvas.width()) as usize;
+            covered[start + x as usize..start + right as usize].fill(true);
+        }
+
+        image::imageops::replace(&mut canvas, &piece.to_rgba16(), x as i64, y as i64);
+    }
+    if !covered.contains(&true) {
+        return None;
+    }
+
+    let canvas = DynamicImage::ImageRgba16(canvas);
+    let full = !covered.contains(&false);
+    Some(match (full && opaque, deep) {
+        (true, true) => DynamicImage::ImageRgb16(canvas.into_rgb16()),
+        (true, false) => DynamicImage::ImageRgb8(canvas.into_rgb8()),
+        (false, true) => canvas,
+        (false, false) => DynamicImage::ImageRgba8(canvas.into_rgba8()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    /// An output at `position`, its mode `resolution` before any rotation.
+    fn screen(position: (i32, i32), resolution: (i32, i32), logical: (i32, i32)) -> ScreenData {
+        ScreenData {
+            name: 1,
+            connector: None,
+            resolution,
+            logical_resolution: logical,
+            logical_position: position,
+        }
+    }
+
+    /// An opaque capture in a single color.
+    fn filled(width: u32, height: u32, color: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb(color)))
+    }
+
+    #[test]
+    fn rotated_outputs_scale_by_their_capture() {
+        // A 2560x1440 mode turned upright at scale 2
+        let upright = screen((0, 0), (2560, 1440), (720, 1280));
+        assert_eq!(capture_scale(&upright, (1440, 2560)), (2.0, 2.0));
+        let selection = Rect {
+            x: 100,
+            y: 1000,
+            width: 200,
+            height: 400,
+        };
+        assert_eq!(
+            output_portion(selection, &upright, (1440, 2560)),
+            Some(Rect {
+                x: 200,
+                y: 2000,
+                width: 400,
+                height: 560,
+            })
+        );
+
+        let capture = filled(1440, 2560, [1, 2, 3]);
+        let outputs = [(&upright, &capture)];
+        assert_eq!(target_scale(selection, &outputs, SaveScale::Physical), 2.0);
+        let image = stitch(selection, &outputs, 2.0, FilterType::Nearest).unwrap();
+        assert_eq!((image.width(), image.height()), (400, 800));
+    }
+
+    #[test]
+    fn mirrored_outputs_dont_hide_gaps() {
+        let left = screen((0, 0), (100, 100), (100, 100));
+        let mirror = screen((0, 0), (100, 100), (100, 100));
+        let capture = filled(100, 100, [1, 2, 3]);
+        // Half of the selection lies right of both outputs
+        let selection = Rect {
+            x: 50,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        let outputs = [(&left, &capture), (&mirror, &capture)];
+        let image = stitch(selection, &outputs, 1.0, FilterType::Nearest).unwrap();
+        let DynamicImage::ImageRgba8(image) = image else {
+            panic!("the gap has to stay transparent, got {:?}", image.color());
+        };
+        assert_eq!(image.get_pixel(10, 10).0, [1, 2, 3, 255]);
+        assert_eq!(image.get_pixel(90, 10).0[3], 0);
+    }
+
+    #[test]
+    fn covered_selections_are_opaque() {
+        let left = screen((0, 0), (100, 100), (100, 100));
+        let right = screen((100, 0), (200, 200), (100, 100));
+        let (left_capture, right_capture) =
+            (filled(100, 100, [1, 2, 3]), filled(200, 200, [4, 5, 6]));
+        let selection = Rect {
+            x: 50,
+            y: 0,
+            width: 100,
+            height: 100,
+        };
+        let outputs = [(&left, &left_capture), (&right, &right_capture)];
+        let image = stitch(selection, &outputs, 1.0, FilterType::Nearest).unwrap();
+        let DynamicImage::ImageRgb8(image) = image else {
+            panic!("expected an opaque image, got {:?}", image.color());
+        };
+        assert_eq!(image.get_pixel(10, 10).0, [1, 2, 3]);
+        assert_eq!(image.get_pixel(90, 10).0, [4, 5, 6]);
+    }
+}
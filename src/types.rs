@@ -3,27 +3,90 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use memmap::MmapMut;
+use serde::{Deserialize, Serialize};
 use wayland_client::{protocol::wl_output::WlOutput, QueueHandle};
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
 
-use crate::wayland::types::ScreenData;
+use crate::wayland::types::{FrameFormat, ScreenData};
 #[cfg(target_os = "linux")]
-use crate::wayland::{init::Delegate, types::ScreenshotWayland};
+use crate::wayland::{init::Delegate, types::ScreenshotWayland, OVERLAY_CURSOR};
 
 #[derive(Debug)]
 pub(crate) struct BuffersStore<B> {
     pub(crate) buffer_file: File,
     pub(crate) file_len: usize,
+    /// The file mapped for drawing, the overlay maps it once per allocation. `None` for the
+    /// screenshots, which get read from the file instead, and for an empty file.
+    pub(crate) mapped: Option<MmapMut>,
 
     pub(crate) buffers_metadata: Vec<B>,
 }
 
+/// A rectangle, in physical or logical pixels depending on where it is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Rect {
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+}
+
+impl Rect {
+    /// The rectangle with `a` and `b` as opposite corners, grown to whole pixels.
+    pub(crate) fn spanning(a: (f64, f64), b: (f64, f64)) -> Rect {
+        let (x, y) = (a.0.min(b.0).floor() as i32, a.1.min(b.1).floor() as i32);
+        let (right, bottom) = (a.0.max(b.0).ceil() as i32, a.1.max(b.1).ceil() as i32);
+        Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+
+    /// The part of `self` that lies inside of `other`, if any.
+    pub(crate) fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        (right > x && bottom > y).then_some(Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+
+    /// The smallest rectangle around both.
+    pub(crate) fn union(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+
+    pub(crate) fn contains(&self, (x, y): (f64, f64)) -> bool {
+        x >= self.x as f64
+            && y >= self.y as f64
+            && x < (self.x + self.width) as f64
+            && y < (self.y + self.height) as f64
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Screenshot {
     pub(crate) screen_data: ScreenData,
     #[cfg(target_os = "linux")]
     pub(crate) wayland_data: ScreenshotWayland,
 
+    /// Layout of the bytes between `offset` and `span` in the [`BuffersStore`] file.
+    pub(crate) format: FrameFormat,
     pub(crate) offset: usize,
     pub(crate) span: usize,
 }
@@ -38,7 +101,12 @@ impl Screenshot {
         screencopying_counter: Arc<Mutex<u8>>,
     ) {
         *screencopying_counter.lock().unwrap() += 1;
-        let frame = screencopy_manager.capture_output(1, output, qh, screencopying_counter.clone());
+        let frame = screencopy_manager.capture_output(
+            OVERLAY_CURSOR,
+            output,
+            qh,
+            screencopying_counter.clone(),
+        );
         frame.copy(&self.wayland_data.buffer);
     }
 }
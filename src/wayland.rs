@@ -1,36 +1,69 @@
 use std::{
     fs::File,
+    io::{Read, Write},
+    mem,
     os::fd::AsFd,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
 };
 
+use memmap::MmapMut;
+use smithay_client_toolkit::globals::GlobalData;
 use tempfile::tempfile;
 use wayland_client::{
-    delegate_noop,
-    globals::{registry_queue_init, GlobalList, GlobalListContents},
+    delegate_noop, event_created_child,
+    globals::{registry_queue_init, Global, GlobalList, GlobalListContents},
     protocol::{
-        wl_buffer::WlBuffer,
-        wl_callback::WlCallback,
+        wl_buffer::{self, WlBuffer},
+        wl_callback::{self, WlCallback},
         wl_compositor::WlCompositor,
+        wl_keyboard::{self, WlKeyboard},
         wl_output::{self, WlOutput},
         wl_pointer::{self, WlPointer},
         wl_region::WlRegion,
         wl_registry::{self, WlRegistry},
-        wl_seat::WlSeat,
+        wl_seat::{self, WlSeat},
         wl_shm::{Format, WlShm},
         wl_shm_pool::WlShmPool,
         wl_surface::WlSurface,
+        wl_touch::{self, WlTouch},
     },
-    Connection, Dispatch, EventQueue, QueueHandle,
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols::{
-    wp::viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+    wp::{
+        cursor_shape::v1::client::{
+            wp_cursor_shape_device_v1::WpCursorShapeDeviceV1,
+            wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+        },
+        fractional_scale::v1::client::{
+            wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+            wp_fractional_scale_v1::{self, WpFractionalScaleV1},
+        },
+        tablet::zv2::client::{
+            zwp_tablet_manager_v2::ZwpTabletManagerV2,
+            zwp_tablet_pad_group_v2::{self, ZwpTabletPadGroupV2},
+            zwp_tablet_pad_ring_v2::ZwpTabletPadRingV2,
+            zwp_tablet_pad_strip_v2::ZwpTabletPadStripV2,
+            zwp_tablet_pad_v2::{self, ZwpTabletPadV2},
+            zwp_tablet_seat_v2::{self, ZwpTabletSeatV2},
+            zwp_tablet_tool_v2::{self, ZwpTabletToolV2},
+            zwp_tablet_v2::ZwpTabletV2,
+        },
+        viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+    },
     xdg::xdg_output::zv1::client::{
         zxdg_output_manager_v1::ZxdgOutputManagerV1,
         zxdg_output_v1::{self, ZxdgOutputV1},
     },
 };
 use wayland_protocols_wlr::{
+    data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+        zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+    },
     layer_shell::v1::client::{
         zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
         zwlr_layer_surface_v1::{self, Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
@@ -41,12 +74,20 @@ use wayland_protocols_wlr::{
     },
 };
 
-use crate::types::{BuffersStore, Screenshot};
+use crate::{
+    types::{BuffersStore, Screenshot},
+    wayland_fractional_scale::FractionalScale,
+};
 
-use self::types::{Delegate, Popup, ScreenData, ScreenshotWayland};
+use self::types::{
+    ClipboardSource, Delegate, FrameBuffer, FrameFormat, FrameState, Input, InputEvent,
+    LogicalGeometry, OverlayBuffer, Popup, ScreenData, ScreenshotWayland, SeatDevices,
+};
 
+pub mod cursor;
 pub mod get_screencopy;
 pub mod init;
+pub mod keyboard;
 pub mod types;
 
 delegate_noop!(Delegate: ignore WlShm);
@@ -62,6 +103,14 @@ delegate_noop!(Delegate: ignore ZwlrScreencopyManagerV1);
 delegate_noop!(Delegate: ignore ZxdgOutputManagerV1);
 delegate_noop!(Delegate: ignore ZwlrLayerShellV1);
 delegate_noop!(Delegate: ignore WlSeat);
+delegate_noop!(Delegate: ignore ZwlrDataControlManagerV1);
+delegate_noop!(Delegate: ignore ZwlrDataControlOfferV1);
+delegate_noop!(Delegate: ignore WpCursorShapeManagerV1);
+delegate_noop!(Delegate: ignore WpCursorShapeDeviceV1);
+delegate_noop!(Delegate: ignore ZwpTabletManagerV2);
+delegate_noop!(Delegate: ignore ZwpTabletV2);
+delegate_noop!(Delegate: ignore ZwpTabletPadRingV2);
+delegate_noop!(Delegate: ignore ZwpTabletPadStripV2);
 
 impl Dispatch<WlRegistry, GlobalListContents> for Delegate {
     fn event(
@@ -83,9 +132,7 @@ impl Dispatch<WlPointer, Arc<Mutex<Option<WlSurface>>>> for Delegate {
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        if let wl_pointer::Event::Enter { .. } = event {
-            println!("{:#?}", event);
-        }
+        log::trace!("{:#?}", event);
         if let wl_pointer::Event::Enter { surface, .. } = event {
             let mut hovering_over_surface = store_surface.lock().unwrap();
             *hovering_over_surface = Some(surface);
@@ -93,6 +140,433 @@ impl Dispatch<WlPointer, Arc<Mutex<Option<WlSurface>>>> for Delegate {
     }
 }
 
+impl Dispatch<WlSeat, Arc<Mutex<Input>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        seat: &WlSeat,
+        event: wl_seat::Event,
+        input: &Arc<Mutex<Input>>,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        else {
+            return;
+        };
+
+        let mut input_guard = input.lock().unwrap();
+        let index = match input_guard
+            .seats
+            .iter()
+            .position(|devices| devices.seat == *seat)
+        {
+            Some(index) => index,
+            None => {
+                input_guard.seats.push(SeatDevices {
+                    seat: seat.clone(),
+                    pointer: None,
+                    keyboard: None,
+                    touch: None,
+                });
+                input_guard.seats.len() - 1
+            }
+        };
+        let devices = &mut input_guard.seats[index];
+
+        let has_pointer = capabilities.contains(wl_seat::Capability::Pointer);
+        match (&devices.pointer, has_pointer) {
+            (None, true) => devices.pointer = Some(seat.get_pointer(qh, input.clone())),
+            (Some(_), false) => {
+                let pointer = devices.pointer.take().unwrap();
+                if pointer.version() >= 3 {
+                    pointer.release();
+                }
+            }
+            _ => {}
+        }
+        let has_keyboard = capabilities.contains(wl_seat::Capability::Keyboard);
+        match (&devices.keyboard, has_keyboard) {
+            (None, true) => devices.keyboard = Some(seat.get_keyboard(qh, input.clone())),
+            (Some(_), false) => {
+                let keyboard = devices.keyboard.take().unwrap();
+                if keyboard.version() >= 3 {
+                    keyboard.release();
+                }
+            }
+            _ => {}
+        }
+        let has_touch = capabilities.contains(wl_seat::Capability::Touch);
+        match (&devices.touch, has_touch) {
+            (None, true) => devices.touch = Some(seat.get_touch(qh, input.clone())),
+            (Some(_), false) => {
+                let touch = devices.touch.take().unwrap();
+                if touch.version() >= 3 {
+                    touch.release();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlPointer, Arc<Mutex<Input>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        pointer: &WlPointer,
+        event: wl_pointer::Event,
+        input: &Arc<Mutex<Input>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let mut input = input.lock().unwrap();
+        match event {
+            wl_pointer::Event::Enter {
+                serial,
+                surface,
+                surface_x,
+                surface_y,
+            } => {
+                input.entered.retain(|(entered, _)| entered != pointer);
+                input.entered.push((pointer.clone(), serial));
+                input.pointer_focus = Some(surface.clone());
+                input.events.push(InputEvent::Motion {
+                    surface,
+                    position: (surface_x, surface_y),
+                });
+            }
+            wl_pointer::Event::Leave { .. } => {
+                input.entered.retain(|(entered, _)| entered != pointer);
+                input.pointer_focus = None;
+            }
+            wl_pointer::Event::Motion {
+                surface_x,
+                surface_y,
+                ..
+            } => {
+                if let Some(surface) = input.pointer_focus.clone() {
+                    input.events.push(InputEvent::Motion {
+                        surface,
+                        position: (surface_x, surface_y),
+                    });
+                }
+            }
+            wl_pointer::Event::Button { button, state, .. } => {
+                input.events.push(InputEvent::Button {
+                    button,
+                    pressed: state == WEnum::Value(wl_pointer::ButtonState::Pressed),
+                });
+            }
+            wl_pointer::Event::Axis {
+                axis: WEnum::Value(wl_pointer::Axis::VerticalScroll),
+                value,
+                ..
+            } => input.events.push(InputEvent::Scroll(value)),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlTouch, Arc<Mutex<Input>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WlTouch,
+        event: wl_touch::Event,
+        input: &Arc<Mutex<Input>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let mut input = input.lock().unwrap();
+        match event {
+            // The first finger acts as the pointer, the others get ignored
+            wl_touch::Event::Down {
+                surface, id, x, y, ..
+            } if input.contact.is_none() => {
+                input.touch_id = Some(id);
+                input.contact_down(surface, (x, y));
+            }
+            wl_touch::Event::Motion { id, x, y, .. } if input.touch_id == Some(id) => {
+                input.contact_motion((x, y));
+            }
+            wl_touch::Event::Up { id, .. } if input.touch_id == Some(id) => {
+                input.touch_id = None;
+                input.contact_up(false);
+            }
+            // The compositor took the touch sequence over, for a gesture of its own
+            wl_touch::Event::Cancel if input.touch_id.is_some() => {
+                input.touch_id = None;
+                input.contact_up(true);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletSeatV2, Arc<Mutex<Input>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &ZwpTabletSeatV2,
+        event: zwp_tablet_seat_v2::Event,
+        input: &Arc<Mutex<Input>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        // New tools can't get the input of the seat right away, it only gets here
+        if let zwp_tablet_seat_v2::Event::ToolAdded { id } = event {
+            if let Some(tool_input) = id.data::<OnceLock<Arc<Mutex<Input>>>>() {
+                let _ = tool_input.set(input.clone());
+            }
+        }
+    }
+
+    event_created_child!(Delegate, ZwpTabletSeatV2, [
+        zwp_tablet_seat_v2::EVT_TABLET_ADDED_OPCODE => (ZwpTabletV2, ()),
+        zwp_tablet_seat_v2::EVT_TOOL_ADDED_OPCODE => (ZwpTabletToolV2, OnceLock::new()),
+        zwp_tablet_seat_v2::EVT_PAD_ADDED_OPCODE => (ZwpTabletPadV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletToolV2, OnceLock<Arc<Mutex<Input>>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        tool: &ZwpTabletToolV2,
+        event: zwp_tablet_tool_v2::Event,
+        input: &OnceLock<Arc<Mutex<Input>>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let Some(input) = input.get() else {
+            return;
+        };
+        let mut input = input.lock().unwrap();
+        match event {
+            zwp_tablet_tool_v2::Event::ProximityIn { surface, .. } => {
+                input.tool_focus = Some((surface, (0.0, 0.0)));
+            }
+            zwp_tablet_tool_v2::Event::ProximityOut => input.tool_focus = None,
+            zwp_tablet_tool_v2::Event::Motion { x, y } => {
+                let Some((surface, position)) = &mut input.tool_focus else {
+                    return;
+                };
+                *position = (x, y);
+                let surface = surface.clone();
+                match (&input.contact, input.touch_id) {
+                    (Some(_), None) => input.contact_motion((x, y)),
+                    // A finger is the pointer for now
+                    (Some(_), Some(_)) => {}
+                    // Hovering moves the pointer like a mouse does
+                    (None, _) => input.events.push(InputEvent::Motion {
+                        surface,
+                        position: (x, y),
+                    }),
+                }
+            }
+            zwp_tablet_tool_v2::Event::Down { .. } if input.contact.is_none() => {
+                if let Some((surface, position)) = input.tool_focus.clone() {
+                    input.contact_down(surface, position);
+                }
+            }
+            zwp_tablet_tool_v2::Event::Up if input.touch_id.is_none() => input.contact_up(false),
+            zwp_tablet_tool_v2::Event::Removed => tool.destroy(),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwpTabletPadV2, ()> for Delegate {
+    fn event(
+        _: &mut Self,
+        pad: &ZwpTabletPadV2,
+        event: zwp_tablet_pad_v2::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        // Pads have no use on the overlay, but their groups, rings and strips get created anyway
+        log::trace!("{:#?}", event);
+        if let zwp_tablet_pad_v2::Event::Removed = event {
+            pad.destroy();
+        }
+    }
+
+    event_created_child!(Delegate, ZwpTabletPadV2, [
+        zwp_tablet_pad_v2::EVT_GROUP_OPCODE => (ZwpTabletPadGroupV2, ()),
+    ]);
+}
+
+impl Dispatch<ZwpTabletPadGroupV2, ()> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &ZwpTabletPadGroupV2,
+        event: zwp_tablet_pad_group_v2::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+    }
+
+    event_created_child!(Delegate, ZwpTabletPadGroupV2, [
+        zwp_tablet_pad_group_v2::EVT_RING_OPCODE => (ZwpTabletPadRingV2, ()),
+        zwp_tablet_pad_group_v2::EVT_STRIP_OPCODE => (ZwpTabletPadStripV2, ()),
+    ]);
+}
+
+impl Dispatch<WlKeyboard, Arc<Mutex<Input>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WlKeyboard,
+        event: wl_keyboard::Event,
+        input: &Arc<Mutex<Input>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let mut input = input.lock().unwrap();
+        match event {
+            wl_keyboard::Event::Keymap {
+                format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+                fd,
+                size,
+            } => {
+                let mut keymap = String::new();
+                match File::from(fd).take(size as u64).read_to_string(&mut keymap) {
+                    Ok(_) => input.events.push(InputEvent::Keymap(
+                        keymap.trim_end_matches('\0').to_string(),
+                    )),
+                    Err(err) => log::warn!("Failed to read the keymap: {err}"),
+                }
+            }
+            wl_keyboard::Event::Key { key, state, .. } => {
+                input.events.push(InputEvent::Key {
+                    key,
+                    pressed: state == WEnum::Value(wl_keyboard::KeyState::Pressed),
+                });
+            }
+            wl_keyboard::Event::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+                ..
+            } => input.events.push(InputEvent::Modifiers {
+                depressed: mods_depressed,
+                latched: mods_latched,
+                locked: mods_locked,
+                group,
+            }),
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlDeviceV1,
+        event: zwlr_data_control_device_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        // We only ever offer data, what others offer is of no interest
+        log::trace!("{:#?}", event);
+    }
+
+    event_created_child!(Delegate, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlSourceV1, Arc<Mutex<ClipboardSource>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrDataControlSourceV1,
+        event: zwlr_data_control_source_v1::Event,
+        source: &Arc<Mutex<ClipboardSource>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:#?}", event);
+        let mut source = source.lock().unwrap();
+        match event {
+            zwlr_data_control_source_v1::Event::Send { fd, .. } => {
+                // Every mime type we offer is the same data
+                if let Err(err) = File::from(fd).write_all(&source.data) {
+                    log::warn!("Failed to send the clipboard: {err}");
+                }
+            }
+            zwlr_data_control_source_v1::Event::Cancelled => source.cancelled = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WpFractionalScaleManagerV1, GlobalData> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WpFractionalScaleManagerV1,
+        _: <WpFractionalScaleManagerV1 as wayland_client::Proxy>::Event,
+        _: &GlobalData,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+impl Dispatch<WpFractionalScaleV1, Arc<Mutex<Option<u32>>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WpFractionalScaleV1,
+        event: <WpFractionalScaleV1 as wayland_client::Proxy>::Event,
+        preferred_scale: &Arc<Mutex<Option<u32>>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:?}", event);
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event {
+            *preferred_scale.lock().unwrap() = Some(scale);
+        }
+    }
+}
+
+/// Clears the busy flag of an overlay buffer once the compositor is done reading it.
+impl Dispatch<WlBuffer, Arc<Mutex<bool>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WlBuffer,
+        event: <WlBuffer as wayland_client::Proxy>::Event,
+        busy: &Arc<Mutex<bool>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_buffer::Event::Release = event {
+            *busy.lock().unwrap() = false;
+        }
+    }
+}
+
+/// Clears the pending flag of a popup once the compositor wants its next frame.
+impl Dispatch<WlCallback, Arc<Mutex<bool>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &WlCallback,
+        event: <WlCallback as wayland_client::Proxy>::Event,
+        frame_pending: &Arc<Mutex<bool>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            *frame_pending.lock().unwrap() = false;
+        }
+    }
+}
+
 impl Dispatch<WlOutput, Arc<Mutex<Option<(i32, i32)>>>> for Delegate {
     fn event(
         _: &mut Self,
@@ -122,39 +596,88 @@ impl Dispatch<ZwlrScreencopyFrameV1, Arc<Mutex<u8>>> for Delegate {
         }
     }
 }
+impl Dispatch<ZwlrScreencopyFrameV1, Arc<Mutex<FrameState>>> for Delegate {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrScreencopyFrameV1,
+        event: <ZwlrScreencopyFrameV1 as wayland_client::Proxy>::Event,
+        state: &Arc<Mutex<FrameState>>,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        log::trace!("{:?}", event);
+        let mut state = state.lock().unwrap();
+        match event {
+            zwlr_screencopy_frame_v1::Event::Buffer {
+                format: WEnum::Value(format),
+                width,
+                height,
+                stride,
+            } => {
+                state.format = Some(FrameFormat {
+                    format,
+                    width,
+                    height,
+                    stride,
+                });
+            }
+            zwlr_screencopy_frame_v1::Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
+                let secs = ((tv_sec_hi as u64) << 32) | tv_sec_lo as u64;
+                state.ready = Some(Duration::new(secs, tv_nsec));
+            }
+            zwlr_screencopy_frame_v1::Event::Failed => state.failed = true,
+            _ => {}
+        }
+    }
+}
 
-impl Dispatch<ZxdgOutputV1, Arc<Mutex<Option<(i32, i32)>>>> for Delegate {
+impl Dispatch<ZxdgOutputV1, Arc<Mutex<LogicalGeometry>>> for Delegate {
     fn event(
         _: &mut Self,
         _: &ZxdgOutputV1,
         event: <ZxdgOutputV1 as wayland_client::Proxy>::Event,
-        data: &Arc<Mutex<Option<(i32, i32)>>>,
+        data: &Arc<Mutex<LogicalGeometry>>,
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        if let zxdg_output_v1::Event::LogicalSize { width, height } = event {
-            let mut logical_resolution = data.lock().unwrap();
-            *logical_resolution = Some((width, height))
+        let mut geometry = data.lock().unwrap();
+        match event {
+            zxdg_output_v1::Event::LogicalPosition { x, y } => geometry.position = Some((x, y)),
+            zxdg_output_v1::Event::LogicalSize { width, height } => {
+                geometry.size = Some((width, height))
+            }
+            zxdg_output_v1::Event::Name { name } => geometry.name = Some(name),
+            _ => {}
         }
     }
 }
-impl Dispatch<ZwlrLayerSurfaceV1, ()> for Delegate {
+impl Dispatch<ZwlrLayerSurfaceV1, Arc<Mutex<bool>>> for Delegate {
     fn event(
         _: &mut Self,
         layer_surface: &ZwlrLayerSurfaceV1,
         event: <ZwlrLayerSurfaceV1 as wayland_client::Proxy>::Event,
-        _: &(),
+        closed: &Arc<Mutex<bool>>,
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
-        println!("{:#?}", event);
-        if let zwlr_layer_surface_v1::Event::Configure { serial, .. } = event {
-            layer_surface.ack_configure(serial);
+        log::trace!("{:#?}", event);
+        match event {
+            zwlr_layer_surface_v1::Event::Configure { serial, .. } => {
+                layer_surface.ack_configure(serial);
+            }
+            // Sent when the output of the surface goes away
+            zwlr_layer_surface_v1::Event::Closed => *closed.lock().unwrap() = true,
+            _ => {}
         }
     }
 }
 
 pub(crate) struct WaylandVarsNew {
+    pub(crate) conn: Connection,
     pub(crate) event_queue: EventQueue<Delegate>,
     pub(crate) qh: QueueHandle<Delegate>,
     pub(crate) globals: GlobalList,
@@ -166,6 +689,7 @@ pub(crate) fn init() -> WaylandVarsNew {
     let qh = event_queue.handle();
 
     WaylandVarsNew {
+        conn,
         globals,
         event_queue,
         qh,
@@ -174,144 +698,317 @@ pub(crate) fn init() -> WaylandVarsNew {
 
 // ===
 
+/// Passed as `overlay_cursor` to every screencopy, captures and recordings show the cursor.
+pub(crate) const OVERLAY_CURSOR: i32 = 1;
+
 pub(crate) fn screenshot(vars: &mut WaylandVarsNew, file: File) -> BuffersStore<Screenshot> {
     let screensdata = get_screen_data(vars);
-
-    let (qh, screencopying_counter) = (&vars.qh, Arc::new(Mutex::new(0)));
+    let qh = &vars.qh;
 
     // Globals
     let shm: WlShm = vars.globals.bind(qh, 1..=1, ()).unwrap();
+    let screencopy_manager: ZwlrScreencopyManagerV1 = vars.globals.bind(qh, 1..=1, ()).unwrap();
+
+    // Request every output at once, so they all show the same moment
+    let mut frames = screensdata
+        .into_iter()
+        .map(|(screen, output)| {
+            let state = Arc::new(Mutex::new(FrameState::default()));
+            let frame =
+                screencopy_manager.capture_output(OVERLAY_CURSOR, &output, qh, state.clone());
+            (frame, state, screen, output)
+        })
+        .collect::<Vec<_>>();
+
+    // Wait for the compositor to tell us which buffer layout it wants for each of them
+    loop {
+        let described = frames.iter().all(|(_, state, _, _)| {
+            let state = state.lock().unwrap();
+            state.failed || state.format.is_some()
+        });
+        if described {
+            break;
+        }
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+    }
+    // Outputs unplugged in the meantime fail their frame
+    frames.retain(|(frame, state, screen, _)| {
+        let failed = state.lock().unwrap().failed;
+        if failed {
+            log::warn!("Screencopy of output {} failed, skipping it", screen.name);
+            frame.destroy();
+        }
+        !failed
+    });
+
     // Logic
-    let format_bytes = 4;
-    let pixel_count = screensdata
+    let formats = frames
         .iter()
-        .map(|(screen_data, _)| screen_data.resolution.0 * screen_data.resolution.1)
-        .sum::<i32>();
-    let shm_pool = shm.create_pool(file.as_fd(), pixel_count * format_bytes, qh, ());
-    file.set_len((pixel_count * format_bytes) as u64).unwrap();
-
-    let (mut screenshots, mut pixels_passed) = (vec![], 0usize);
-    for (screen, output) in screensdata {
-        let (width, height) = (screen.resolution.0, screen.resolution.1);
-        let (l_width, l_height) = (screen.logical_resolution.0, screen.logical_resolution.1);
-        let screen_byte_span = pixels_passed + (width * height * format_bytes) as usize;
+        .map(|(_, state, _, _)| state.lock().unwrap().format.unwrap())
+        .collect::<Vec<_>>();
+    let file_len = formats
+        .iter()
+        .map(|format| (format.stride * format.height) as usize)
+        .sum::<usize>();
+    file.set_len(file_len as u64).unwrap();
+    let shm_pool = shm.create_pool(file.as_fd(), file_len as i32, qh, ());
+
+    let (mut screenshots, mut bytes_passed) = (vec![], 0usize);
+    for ((frame, _, screen, output), format) in frames.iter().zip(formats) {
+        let screen_byte_span = bytes_passed + (format.stride * format.height) as usize;
+        log::debug!(
+            "Capturing {}x{} {:?}",
+            format.width,
+            format.height,
+            format.format
+        );
+        let wayland_data =
+            ScreenshotWayland::new(qh, format, output.clone(), &shm_pool, bytes_passed);
+        frame.copy(&wayland_data.buffer);
 
         screenshots.push(Screenshot {
-            offset: pixels_passed,
+            offset: bytes_passed,
             span: screen_byte_span,
-            wayland_data: ScreenshotWayland::new(qh, screen, output, &shm_pool, pixels_passed),
-            screen_data: ScreenData {
-                logical_resolution: (l_width, l_height),
-                resolution: (width, height),
-            },
+            format,
+            wayland_data,
+            screen_data: screen.clone(),
         });
 
-        pixels_passed += screen_byte_span;
+        bytes_passed = screen_byte_span;
     }
 
     // A wait until finished copying screenshot to buffer
     loop {
-        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
-        let val = screencopying_counter.lock().unwrap();
-        if *val == 0 {
+        let copied = frames.iter().all(|(_, state, _, _)| {
+            let state = state.lock().unwrap();
+            state.failed || state.ready.is_some()
+        });
+        if copied {
             break;
         };
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
     }
+    let mut frames = frames.into_iter();
+    screenshots.retain(|_| {
+        let (frame, state, screen, _) = frames.next().unwrap();
+        frame.destroy();
+        let failed = state.lock().unwrap().failed;
+        if failed {
+            log::warn!("Screencopy of output {} failed, skipping it", screen.name);
+        }
+        !failed
+    });
 
     BuffersStore {
         buffer_file: file,
-        file_len: pixels_passed,
+        file_len,
+        mapped: None,
         buffers_metadata: screenshots,
     }
 }
 
-fn get_screen_data(vars: &mut WaylandVarsNew) -> Vec<(ScreenData, WlOutput)> {
-    let (globals, event_queue, qh) = (&vars.globals, &mut vars.event_queue, &vars.qh);
+/// Copies the current content of `output` into `frame_buffer`, reallocating it when the
+/// compositor asks for a different layout. Returns the presentation time of the copy, or `None`
+/// when the compositor failed to copy the frame.
+pub(crate) fn capture_frame(
+    vars: &mut WaylandVarsNew,
+    screencopy_manager: &ZwlrScreencopyManagerV1,
+    shm: &WlShm,
+    output: &WlOutput,
+    frame_buffer: &mut Option<FrameBuffer>,
+) -> Option<Duration> {
+    let state = Arc::new(Mutex::new(FrameState::default()));
+    let frame = screencopy_manager.capture_output(OVERLAY_CURSOR, output, &vars.qh, state.clone());
+
+    // Wait for the compositor to tell us what kind of buffer it wants
+    let format = loop {
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+        let state = state.lock().unwrap();
+        if state.failed {
+            frame.destroy();
+            return None;
+        }
+        if let Some(format) = state.format {
+            break format;
+        }
+    };
+    if frame_buffer.as_ref().map(|buffer| buffer.format) != Some(format) {
+        *frame_buffer = Some(FrameBuffer::new(&vars.qh, shm, format));
+    }
+    frame.copy(&frame_buffer.as_ref().unwrap().buffer);
+
+    let ready = loop {
+        vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+        let state = state.lock().unwrap();
+        if state.failed {
+            break None;
+        }
+        if state.ready.is_some() {
+            break state.ready;
+        }
+    };
+    frame.destroy();
+    ready
+}
 
-    let output_manager: ZxdgOutputManagerV1 = globals.bind(qh, 1..=3, ()).unwrap();
+pub(crate) fn get_screen_data(vars: &mut WaylandVarsNew) -> Vec<(ScreenData, WlOutput)> {
+    let output_manager: ZxdgOutputManagerV1 = vars.globals.bind(&vars.qh, 1..=3, ()).unwrap();
 
     let mut screens_data = vec![];
-    for global in globals.contents().clone_list() {
+    for global in vars.globals.contents().clone_list() {
         if let "wl_output" = &global.interface[..] {
-            let resolution = Arc::new(Mutex::new(None));
-            let logical_resolution = Arc::new(Mutex::new(None));
+            screens_data.extend(bind_output(vars, &output_manager, &global));
+        }
+    }
 
-            let output: WlOutput =
-                globals
-                    .registry()
-                    .bind(global.name, global.version, qh, resolution.clone());
-            output_manager.get_xdg_output(&output, qh, logical_resolution.clone());
+    screens_data
+}
+
+/// Binds a `wl_output` global and waits for its description, `None` when it went away before
+/// the compositor described it.
+fn bind_output(
+    vars: &mut WaylandVarsNew,
+    output_manager: &ZxdgOutputManagerV1,
+    global: &Global,
+) -> Option<(ScreenData, WlOutput)> {
+    let (globals, event_queue, qh) = (&vars.globals, &mut vars.event_queue, &vars.qh);
+
+    let resolution = Arc::new(Mutex::new(None));
+    let logical_geometry = Arc::new(Mutex::new(LogicalGeometry::default()));
 
-            event_queue.blocking_dispatch(&mut Delegate).unwrap();
+    let output: WlOutput =
+        globals
+            .registry()
+            .bind(global.name, global.version, qh, resolution.clone());
+    output_manager.get_xdg_output(&output, qh, logical_geometry.clone());
 
-            screens_data.push((
-                ScreenData {
-                    resolution: resolution.lock().unwrap().expect("res not found"),
-                    logical_resolution: logical_resolution
-                        .lock()
-                        .unwrap()
-                        .expect("l_res not found"),
-                },
-                output,
-            ));
+    // A removed output never sends anything, so don't wait for events
+    event_queue.roundtrip(&mut Delegate).unwrap();
+
+    let mut logical_geometry = logical_geometry.lock().unwrap();
+    let resolution = *resolution.lock().unwrap();
+    let (Some(resolution), Some(logical_resolution), Some(logical_position)) =
+        (resolution, logical_geometry.size, logical_geometry.position)
+    else {
+        log::warn!("Output {} went away before it was described", global.name);
+        return None;
+    };
+    Some((
+        ScreenData {
+            name: global.name,
+            connector: logical_geometry.name.take(),
+            resolution,
+            logical_resolution,
+            logical_position,
+        },
+        output,
+    ))
+}
+
+/// Outputs plugged in or removed since `known` were listed, by their registry name.
+#[derive(Debug, Default)]
+pub(crate) struct OutputChanges {
+    pub(crate) added: Vec<(ScreenData, WlOutput)>,
+    pub(crate) removed: Vec<u32>,
+}
+
+/// Compares the outputs of the registry with `known`, binding the new ones. Events have to be
+/// dispatched before, the registry is kept up to date while dispatching.
+pub(crate) fn output_changes(vars: &mut WaylandVarsNew, known: &[u32]) -> OutputChanges {
+    let outputs = vars
+        .globals
+        .contents()
+        .clone_list()
+        .into_iter()
+        .filter(|global| global.interface == "wl_output")
+        .collect::<Vec<_>>();
+
+    let mut changes = OutputChanges {
+        removed: known
+            .iter()
+            .copied()
+            .filter(|name| outputs.iter().all(|global| global.name != *name))
+            .collect(),
+        ..Default::default()
+    };
+    let new_outputs = outputs
+        .iter()
+        .filter(|global| !known.contains(&global.name))
+        .collect::<Vec<_>>();
+    if !new_outputs.is_empty() {
+        let output_manager: ZxdgOutputManagerV1 = vars.globals.bind(&vars.qh, 1..=3, ()).unwrap();
+        for global in new_outputs {
+            changes
+                .added
+                .extend(bind_output(vars, &output_manager, global));
         }
     }
+    for (screen, _) in changes.added.iter() {
+        log::info!("Output {} plugged in", screen.name);
+    }
+    for name in changes.removed.iter() {
+        log::info!("Output {name} removed");
+    }
+    changes
+}
 
-    screens_data
+/// Whether the output with this registry name is still around.
+pub(crate) fn output_exists(vars: &WaylandVarsNew, name: u32) -> bool {
+    vars.globals
+        .contents()
+        .with_list(|globals| globals.iter().any(|global| global.name == name))
 }
+
 pub(crate) fn create_popup(
     vars: &mut WaylandVarsNew,
     screenshots_data: &BuffersStore<Screenshot>,
 ) -> BuffersStore<Popup> {
+    let outputs = screenshots_data
+        .buffers_metadata
+        .iter()
+        .map(|screen| {
+            let output = screen.wayland_data.output.clone();
+            (screen.screen_data.clone(), output, Some(screen.clone()))
+        })
+        .collect();
+    let mut screens = new_popups(vars, outputs);
+    let (buffer_file, file_len, mapped) = allocate_popup_buffers(vars, &mut screens);
+    BuffersStore {
+        buffer_file,
+        file_len,
+        mapped,
+        buffers_metadata: screens,
+    }
+}
+
+/// Creates a configured popup on every output, leaving out the ones that went away meanwhile.
+/// Their buffers still have to be allocated.
+fn new_popups(
+    vars: &mut WaylandVarsNew,
+    outputs: Vec<(ScreenData, WlOutput, Option<Screenshot>)>,
+) -> Vec<Popup> {
     let qh = &vars.qh;
 
-    let shm: WlShm = vars.globals.bind(qh, 1..=1, ()).unwrap();
     let compositor: WlCompositor = vars.globals.bind(qh, 1..=4, ()).unwrap();
     let layer_shell: ZwlrLayerShellV1 = vars.globals.bind(qh, 1..=1, ()).unwrap();
     let viewporter: WpViewporter = vars.globals.bind(qh, 1..=1, ()).unwrap();
-
-    let backing_memory = tempfile().unwrap();
-    backing_memory
-        .set_len(screenshots_data.file_len as u64)
-        .unwrap();
-
-    let shm_pool = shm.create_pool(
-        backing_memory.as_fd(),
-        screenshots_data.file_len as i32,
-        qh,
-        (),
-    );
+    // Without it the buffers stay at the physical resolution of the output
+    let fractional_scale = FractionalScale::bind(&vars.globals, qh).ok();
 
     // ===
-    let mut screens = vec![];
-    for screen in screenshots_data.buffers_metadata.iter() {
-        let (l_width, l_height) = (
-            screen.screen_data.logical_resolution.0,
-            screen.screen_data.logical_resolution.1,
-        );
-
-        let (width, height) = (
-            screen.screen_data.resolution.0,
-            screen.screen_data.resolution.1,
-        );
-        let buffer = shm_pool.create_buffer(
-            screen.offset as i32,
-            width,
-            height,
-            width * 4,
-            Format::Abgr8888,
-            qh,
-            (),
-        );
+    let mut surfaces = vec![];
+    for (screen, output, capture) in outputs {
+        let (l_width, l_height) = (screen.logical_resolution.0, screen.logical_resolution.1);
 
         let surface = compositor.create_surface(qh, ());
+        let closed = Arc::new(Mutex::new(false));
         let layer_surface = layer_shell.get_layer_surface(
             &surface,
-            Some(&screen.wayland_data.output),
+            Some(&output),
             Layer::Overlay,
             "ScreenshotUtil".to_string(),
             qh,
-            (),
+            closed.clone(),
         );
         let viewport = viewporter.get_viewport(&surface, qh, ());
         viewport.set_destination(l_width, l_height);
@@ -319,29 +1016,237 @@ pub(crate) fn create_popup(
         //region.add(0, 0, 0, 0);
         //surface.set_input_region(Some(&region));
 
+        let scale = Arc::new(Mutex::new(None));
+        if let Some(fractional_scale) = &fractional_scale {
+            fractional_scale.get_fractional_scale(&surface, qh, scale.clone());
+        }
+        surfaces.push((screen, capture, surface, layer_surface, scale, closed));
+    }
+
+    let mut screens = vec![];
+    for (screen, capture, surface, layer_surface, scale, closed) in surfaces {
         let popup = Popup {
-            screen_data: screen.clone(),
-            buffer,
+            buffers: vec![],
+            front: None,
+            buffer_size: screen.resolution,
+            screen_data: screen,
+            capture,
+            scale,
+            selection: None,
+            dirty: true,
+            frame_pending: Arc::new(Mutex::new(false)),
             surface,
             layer_surface,
+            closed,
         };
         popup.config_layer_surface(&mut vars.event_queue);
         vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
 
         screens.push(popup);
     }
+    // The preferred scale gets sent once the surfaces know their output
+    vars.event_queue.roundtrip(&mut Delegate).unwrap();
 
-    BuffersStore {
-        buffer_file: backing_memory,
-        file_len: screenshots_data.file_len,
-        buffers_metadata: screens,
+    screens.retain(|popup| !popup.is_closed());
+    screens
+}
+
+/// Registry names of every output right now.
+pub(crate) fn output_names(vars: &WaylandVarsNew) -> Vec<u32> {
+    vars.globals.contents().with_list(|globals| {
+        globals
+            .iter()
+            .filter(|global| global.interface == "wl_output")
+            .map(|global| global.name)
+            .collect()
+    })
+}
+
+/// Creates popups for the outputs plugged in since `known` were listed, which have no capture.
+/// Reallocates the overlay buffers when there are any, returns how many got added at the end of
+/// `popups`. Removed outputs close their popups, [`remove_closed_popups`] drops those.
+pub(crate) fn add_plugged_popups(
+    vars: &mut WaylandVarsNew,
+    popups: &mut BuffersStore<Popup>,
+    known: &mut Vec<u32>,
+) -> usize {
+    let changes = output_changes(vars, known);
+    known.retain(|name| !changes.removed.contains(name));
+    known.extend(changes.added.iter().map(|(screen, _)| screen.name));
+    if changes.added.is_empty() {
+        return 0;
+    }
+
+    let outputs = changes
+        .added
+        .into_iter()
+        .map(|(screen, output)| (screen, output, None))
+        .collect();
+    let added = new_popups(vars, outputs);
+    let count = added.len();
+    popups.buffers_metadata.extend(added);
+    (popups.buffer_file, popups.file_len, popups.mapped) =
+        allocate_popup_buffers(vars, &mut popups.buffers_metadata);
+    count
+}
+
+/// Size of the overlay buffer that maps 1:1 to the pixels of the output.
+fn popup_buffer_size(popup: &Popup) -> (i32, i32) {
+    let screen_data = &popup.screen_data;
+    match *popup.scale.lock().unwrap() {
+        // In 120ths, rounded half away from zero as the protocol asks
+        Some(scale) => {
+            let (l_width, l_height) = screen_data.logical_resolution;
+            let scaled = |length: i32| (length * scale as i32 + 60) / 120;
+            (scaled(l_width), scaled(l_height))
+        }
+        None => screen_data.resolution,
+    }
+}
+
+/// Overlay buffers of every popup, drawn in turn.
+const BUFFERS_PER_POPUP: usize = 2;
+
+/// Gives `popup` new overlay `buffers` of `size`, destroying the old ones.
+fn replace_popup_buffers(popup: &mut Popup, buffers: Vec<OverlayBuffer>, size: (i32, i32)) {
+    for old in mem::replace(&mut popup.buffers, buffers) {
+        old.buffer.destroy();
+    }
+    popup.front = None;
+    popup.buffer_size = size;
+    popup.dirty = true;
+}
+
+/// (Re)creates the overlay buffers at the size of the preferred scale of every popup, in a new
+/// file so the compositor can keep reading the old buffers until they get replaced. The file comes
+/// mapped for drawing, unless it is empty. Popups without any pixels get no buffers.
+fn allocate_popup_buffers(
+    vars: &mut WaylandVarsNew,
+    popups: &mut [Popup],
+) -> (File, usize, Option<MmapMut>) {
+    let qh = &vars.qh;
+    let shm: WlShm = vars.globals.bind(qh, 1..=1, ()).unwrap();
+
+    // The overlay always draws Abgr8888, whatever format the screenshots came in
+    let sizes = popups.iter().map(popup_buffer_size).collect::<Vec<_>>();
+    let file_len = sizes
+        .iter()
+        .map(|(width, height)| (width * height * 4) as usize * BUFFERS_PER_POPUP)
+        .sum::<usize>();
+    let backing_memory = tempfile().unwrap();
+    backing_memory.set_len(file_len as u64).unwrap();
+    // wl_shm disconnects us for an empty pool
+    if file_len == 0 {
+        for (popup, size) in popups.iter_mut().zip(sizes) {
+            replace_popup_buffers(popup, vec![], size);
+        }
+        return (backing_memory, file_len, None);
+    }
+    let shm_pool = shm.create_pool(backing_memory.as_fd(), file_len as i32, qh, ());
+
+    let mut bytes_passed = 0usize;
+    for (popup, (width, height)) in popups.iter_mut().zip(sizes) {
+        log::debug!("Overlay buffers {width}x{height}");
+        if width == 0 || height == 0 {
+            replace_popup_buffers(popup, vec![], (width, height));
+            continue;
+        }
+        let buffers = (0..BUFFERS_PER_POPUP)
+            .map(|_| {
+                let busy = Arc::new(Mutex::new(false));
+                let buffer = shm_pool.create_buffer(
+                    bytes_passed as i32,
+                    width,
+                    height,
+                    width * 4,
+                    Format::Abgr8888,
+                    qh,
+                    busy.clone(),
+                );
+                let offset = bytes_passed;
+                bytes_passed += (width * height * 4) as usize;
+                OverlayBuffer {
+                    buffer,
+                    offset,
+                    busy,
+                }
+            })
+            .collect();
+        replace_popup_buffers(popup, buffers, (width, height));
     }
+    // The buffers keep the memory mapped
+    shm_pool.destroy();
+    // SAFETY: only the compositor reads the file while it is mapped, it never resizes it
+    let mapped = unsafe { MmapMut::map_mut(&backing_memory) }.unwrap();
+    (backing_memory, file_len, Some(mapped))
+}
+
+/// Destroys the popups whose output got unplugged, returns whether there were any.
+pub(crate) fn remove_closed_popups(popups: &mut BuffersStore<Popup>) -> bool {
+    let count = popups.buffers_metadata.len();
+    popups.buffers_metadata.retain(|popup| {
+        if !popup.is_closed() {
+            return true;
+        }
+        log::warn!(
+            "Output {} went away, dropping its screenshot",
+            popup.screen_data.name
+        );
+        popup.layer_surface.destroy();
+        popup.surface.destroy();
+        for buffer in &popup.buffers {
+            buffer.buffer.destroy();
+        }
+        false
+    });
+    popups.buffers_metadata.len() != count
+}
+
+/// Reallocates the overlay buffers when the preferred scale of any popup changed, the popups
+/// have to be drawn and attached again when this returns `true`.
+pub(crate) fn rescale_popups(vars: &mut WaylandVarsNew, popups: &mut BuffersStore<Popup>) -> bool {
+    let changed = popups
+        .buffers_metadata
+        .iter()
+        .any(|popup| popup_buffer_size(popup) != popup.buffer_size);
+    if changed {
+        (popups.buffer_file, popups.file_len, popups.mapped) =
+            allocate_popup_buffers(vars, &mut popups.buffers_metadata);
+    }
+    changed
+}
+
+/// Listens to the pointers and keyboards of every seat, the events get queued in the returned
+/// [`Input`].
+pub(crate) fn bind_input(vars: &mut WaylandVarsNew) -> Arc<Mutex<Input>> {
+    let input = Arc::new(Mutex::new(Input::default()));
+    let tablet_manager: Option<ZwpTabletManagerV2> = vars.globals.bind(&vars.qh, 1..=1, ()).ok();
+    for global in vars.globals.contents().clone_list() {
+        if global.interface == "wl_seat" {
+            // The devices get created once the seat tells us its capabilities
+            let seat: WlSeat = vars.globals.registry().bind(
+                global.name,
+                global.version.min(7),
+                &vars.qh,
+                input.clone(),
+            );
+            if let Some(tablet_manager) = &tablet_manager {
+                tablet_manager.get_tablet_seat(&seat, &vars.qh, input.clone());
+            }
+        }
+    }
+    input
 }
 
 pub(crate) fn filter_unfocused_popups(popups: &mut BuffersStore<Popup>, vars: &mut WaylandVarsNew) {
     // render overlay
     popups.buffers_metadata.iter().for_each(|a| {
-        a.surface.attach(Some(&a.buffer), 0, 0);
+        // Nothing to show before the output has a size
+        let Some(buffer) = a.buffers.first() else {
+            return;
+        };
+        *buffer.busy.lock().unwrap() = true;
+        a.surface.attach(Some(&buffer.buffer), 0, 0);
         a.surface.commit();
     });
 
@@ -360,9 +1265,13 @@ pub(crate) fn filter_unfocused_popups(popups: &mut BuffersStore<Popup>, vars: &m
     }
     loop {
         vars.event_queue.blocking_dispatch(&mut Delegate).unwrap();
+        remove_closed_popups(popups);
         if (*hovering_over_surface.lock().unwrap()).is_some() {
             break;
         }
+        if popups.buffers_metadata.is_empty() {
+            return;
+        }
     }
     // Remove all the popups that dont have the cursor on them
     let hovering_over_surface = hovering_over_surface.lock().unwrap().clone().unwrap();
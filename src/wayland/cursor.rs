@@ -0,0 +1,138 @@
+//! The cursor shown over the overlay. Compositors with `wp_cursor_shape_v1` draw a named shape
+//! themselves, otherwise the image comes from the xcursor theme of the user
+//! (`$XCURSOR_THEME` and `$XCURSOR_SIZE`) and gets attached to a surface of our own.
+
+use wayland_client::{
+    protocol::{
+        wl_compositor::WlCompositor, wl_pointer::WlPointer, wl_shm::WlShm, wl_surface::WlSurface,
+    },
+    QueueHandle,
+};
+use wayland_cursor::CursorTheme;
+use wayland_protocols::wp::cursor_shape::v1::client::{
+    wp_cursor_shape_device_v1::{Shape, WpCursorShapeDeviceV1},
+    wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+};
+
+use super::{types::Delegate, WaylandVarsNew};
+
+/// Size of the themed cursor when `$XCURSOR_SIZE` isn't set.
+const DEFAULT_SIZE: u32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CursorIcon {
+    Crosshair,
+    Move,
+    /// Resizing left or right.
+    ResizeEw,
+    /// Resizing up or down.
+    ResizeNs,
+    /// Resizing at the top left or bottom right corner.
+    ResizeNwse,
+    /// Resizing at the top right or bottom left corner.
+    ResizeNesw,
+}
+
+impl CursorIcon {
+    fn shape(self) -> Shape {
+        match self {
+            CursorIcon::Crosshair => Shape::Crosshair,
+            CursorIcon::Move => Shape::Move,
+            CursorIcon::ResizeEw => Shape::EwResize,
+            CursorIcon::ResizeNs => Shape::NsResize,
+            CursorIcon::ResizeNwse => Shape::NwseResize,
+            CursorIcon::ResizeNesw => Shape::NeswResize,
+        }
+    }
+
+    /// Names of the icon in xcursor themes, newer themes use the CSS names and older ones the
+    /// X11 ones.
+    fn xcursor_names(self) -> &'static [&'static str] {
+        match self {
+            CursorIcon::Crosshair => &["crosshair", "cross", "tcross"],
+            CursorIcon::Move => &["move", "fleur", "all-scroll"],
+            CursorIcon::ResizeEw => &["ew-resize", "sb_h_double_arrow", "size_hor"],
+            CursorIcon::ResizeNs => &["ns-resize", "sb_v_double_arrow", "size_ver"],
+            CursorIcon::ResizeNwse => &["nwse-resize", "size_fdiag", "bottom_right_corner"],
+            CursorIcon::ResizeNesw => &["nesw-resize", "size_bdiag", "bottom_left_corner"],
+        }
+    }
+}
+
+/// Sets the cursor of the pointers over the overlay.
+pub(crate) struct Cursors {
+    shape_manager: Option<WpCursorShapeManagerV1>,
+    shape_devices: Vec<(WlPointer, WpCursorShapeDeviceV1)>,
+    /// The xcursor theme and the surface showing it, used without `shape_manager`.
+    theme: Option<(CursorTheme, WlSurface)>,
+}
+
+impl Cursors {
+    pub(crate) fn new(vars: &WaylandVarsNew) -> Cursors {
+        let shape_manager = vars.globals.bind(&vars.qh, 1..=1, ()).ok();
+        let theme = match shape_manager {
+            Some(_) => None,
+            None => load_theme(vars),
+        };
+        Cursors {
+            shape_manager,
+            shape_devices: vec![],
+            theme,
+        }
+    }
+
+    /// Shows `icon` as the cursor of `pointer`, which entered the overlay with `serial`.
+    pub(crate) fn set(
+        &mut self,
+        qh: &QueueHandle<Delegate>,
+        pointer: &WlPointer,
+        serial: u32,
+        icon: CursorIcon,
+    ) {
+        if let Some(manager) = &self.shape_manager {
+            let device = match self
+                .shape_devices
+                .iter()
+                .find(|(known, _)| known == pointer)
+            {
+                Some((_, device)) => device,
+                None => {
+                    let device = manager.get_pointer(pointer, qh, ());
+                    self.shape_devices.push((pointer.clone(), device));
+                    &self.shape_devices.last().unwrap().1
+                }
+            };
+            device.set_shape(serial, icon.shape());
+            return;
+        }
+
+        let Some((theme, surface)) = &mut self.theme else {
+            return;
+        };
+        let Some(name) = icon
+            .xcursor_names()
+            .iter()
+            .find(|name| theme.get_cursor(name).is_some())
+        else {
+            log::debug!("The cursor theme has no {icon:?} cursor");
+            return;
+        };
+        let image = &theme.get_cursor(name).unwrap()[0];
+        let (width, height) = image.dimensions();
+        let (hotspot_x, hotspot_y) = image.hotspot();
+        surface.attach(Some(image), 0, 0);
+        surface.damage_buffer(0, 0, width as i32, height as i32);
+        surface.commit();
+        pointer.set_cursor(serial, Some(surface), hotspot_x as i32, hotspot_y as i32);
+    }
+}
+
+/// Loads the xcursor theme of the user, `None` when the compositor lacks what it needs.
+fn load_theme(vars: &WaylandVarsNew) -> Option<(CursorTheme, WlSurface)> {
+    let shm: WlShm = vars.globals.bind(&vars.qh, 1..=1, ()).ok()?;
+    let compositor: WlCompositor = vars.globals.bind(&vars.qh, 1..=4, ()).ok()?;
+    let theme = CursorTheme::load(&vars.conn, shm, DEFAULT_SIZE)
+        .map_err(|err| log::warn!("Failed to load the cursor theme: {err}"))
+        .ok()?;
+    Some((theme, compositor.create_surface(&vars.qh, ())))
+}
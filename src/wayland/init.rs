@@ -1,6 +1,5 @@
 use std::{
     fs::File,
-    io::{Seek, SeekFrom},
     os::fd::AsFd,
     sync::{Arc, Mutex},
 };
@@ -107,7 +106,6 @@ struct WindowDataManager<'a> {
 #[derive(Debug)]
 struct PopupData {
     screen_data: ScreenData,
-    _offset: i32,
     buffer: WlBuffer,
     surface: WlSurface,
 }
@@ -120,33 +118,6 @@ impl WindowDataManager<'_> {
         })
     }
 
-    fn _draw(&mut self, monitor: usize) {
-        let monitor_data = &self.wayland_window_data[monitor];
-        let (buf_x, buf_y) = (
-            monitor_data.screen_data.resolution.0,
-            monitor_data.screen_data.resolution.1,
-        );
-        let tmp = &mut self.file;
-
-        use std::{cmp::min, io::Write};
-        let mut buf = std::io::BufWriter::new(tmp);
-
-        buf.seek(SeekFrom::Start(monitor_data._offset as u64))
-            .unwrap();
-
-        for y in 0..buf_y {
-            for x in 0..buf_x {
-                let a = 0xFF;
-                let r = min(((buf_x - x) * 0xFF) / buf_x, ((buf_y - y) * 0xFF) / buf_y);
-                let g = min((x * 0xFF) / buf_x, ((buf_y - y) * 0xFF) / buf_y);
-                let b = min(((buf_x - x) * 0xFF) / buf_x, (y * 0xFF) / buf_y);
-                buf.write_all(&[b as u8, g as u8, r as u8, a as u8])
-                    .unwrap();
-            }
-        }
-        buf.flush().unwrap();
-    }
-
     /// Returns a bool when the screen has been copied into the buffer.
     fn screencopy(&self, screencopy_manager: &ZwlrScreencopyManagerV1) -> Arc<Mutex<u8>> {
         let screencopying_counter = Arc::new(Mutex::new(0u8));
@@ -229,7 +200,6 @@ pub(crate) fn screen_shot_overlay(wayland: &mut WaylandVars, screens: Vec<Screen
                 (),
             ),
             surface,
-            _offset: pixels_passed,
             screen_data: screen,
         });
 
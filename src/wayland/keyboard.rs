@@ -0,0 +1,75 @@
+//! Turns the scancodes the seats send into keysyms and text with the layout of the user.
+
+use xkbcommon::xkb::{self, Keycode, Keysym};
+
+/// A key press after applying the keymap and the active modifiers.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyPress {
+    pub(crate) keysym: Keysym,
+    /// What the key types, empty for keys like the arrows.
+    pub(crate) text: String,
+    pub(crate) shift: bool,
+    pub(crate) ctrl: bool,
+    pub(crate) alt: bool,
+}
+
+pub(crate) struct Keyboard {
+    context: xkb::Context,
+    /// `None` until the compositor sent a keymap.
+    state: Option<xkb::State>,
+}
+
+impl Keyboard {
+    pub(crate) fn new() -> Keyboard {
+        Keyboard {
+            context: xkb::Context::new(xkb::CONTEXT_NO_FLAGS),
+            state: None,
+        }
+    }
+
+    pub(crate) fn set_keymap(&mut self, keymap: String) {
+        let keymap = xkb::Keymap::new_from_string(
+            &self.context,
+            keymap,
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        );
+        match keymap {
+            Some(keymap) => self.state = Some(xkb::State::new(&keymap)),
+            None => log::warn!("Failed to compile the keymap"),
+        }
+    }
+
+    pub(crate) fn update_modifiers(
+        &mut self,
+        depressed: u32,
+        latched: u32,
+        locked: u32,
+        group: u32,
+    ) {
+        if let Some(state) = &mut self.state {
+            state.update_mask(depressed, latched, locked, 0, 0, group);
+        }
+    }
+
+    /// Translates a pressed evdev scancode.
+    pub(crate) fn press(&self, key: u32) -> Option<KeyPress> {
+        let state = self.state.as_ref()?;
+        // xkb keycodes are offset by 8 from evdev scancodes
+        let keycode = Keycode::new(key + 8);
+        let is_active = |name| state.mod_name_is_active(name, xkb::STATE_MODS_EFFECTIVE);
+        Some(KeyPress {
+            keysym: state.key_get_one_sym(keycode),
+            text: state.key_get_utf8(keycode),
+            shift: is_active(xkb::MOD_NAME_SHIFT),
+            ctrl: is_active(xkb::MOD_NAME_CTRL),
+            alt: is_active(xkb::MOD_NAME_ALT),
+        })
+    }
+
+    /// The keysym of a released evdev scancode.
+    pub(crate) fn release(&self, key: u32) -> Option<Keysym> {
+        let state = self.state.as_ref()?;
+        Some(state.key_get_one_sym(Keycode::new(key + 8)))
+    }
+}
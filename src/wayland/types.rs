@@ -1,43 +1,117 @@
 use std::{
     fs::File,
+    os::{fd::AsFd, unix::fs::FileExt},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use wayland_client::{
     protocol::{
-        wl_buffer::WlBuffer, wl_output::WlOutput, wl_shm::Format, wl_shm_pool::WlShmPool,
+        wl_buffer::WlBuffer,
+        wl_keyboard::WlKeyboard,
+        wl_output::WlOutput,
+        wl_pointer::WlPointer,
+        wl_seat::WlSeat,
+        wl_shm::{Format, WlShm},
+        wl_shm_pool::WlShmPool,
         wl_surface::WlSurface,
+        wl_touch::WlTouch,
     },
     EventQueue, QueueHandle,
 };
-use wayland_protocols_wlr::{
-    layer_shell::v1::client::zwlr_layer_surface_v1::{
-        Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1,
-    },
-    screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1::{
+    Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1,
 };
 
-use crate::types::Screenshot;
+use crate::{
+    overlay::BTN_LEFT,
+    types::{Rect, Screenshot},
+};
 
 pub(crate) struct Delegate;
 
 #[derive(Debug, Clone)]
 pub(crate) struct ScreenData {
+    /// Registry name of the `wl_output` global, identifies the output across hotplugs.
+    pub(crate) name: u32,
+    /// Name like `DP-1` that stays the same across sessions, compositors without version 2 of
+    /// `zxdg_output_v1` don't send it.
+    pub(crate) connector: Option<String>,
     pub(crate) resolution: (i32, i32),
     pub(crate) logical_resolution: (i32, i32),
+    /// Top left corner of the output in the global logical coordinate space.
+    pub(crate) logical_position: (i32, i32),
+}
+
+/// What `zxdg_output_v1` told us about an output.
+#[derive(Debug, Default)]
+pub(crate) struct LogicalGeometry {
+    pub(crate) position: Option<(i32, i32)>,
+    pub(crate) size: Option<(i32, i32)>,
+    pub(crate) name: Option<String>,
 }
 
+/// One of the overlay buffers a popup draws into in turn.
 #[derive(Debug, Clone)]
-pub(crate) struct Popup {
-    pub(crate) screen_data: Screenshot,
+pub(crate) struct OverlayBuffer {
     pub(crate) buffer: WlBuffer,
+    /// Where its pixels start in the file of the [`BuffersStore`].
+    ///
+    /// [`BuffersStore`]: crate::types::BuffersStore
+    pub(crate) offset: usize,
+    /// Set from attaching the buffer until the compositor releases it, it must not be drawn
+    /// into meanwhile.
+    pub(crate) busy: Arc<Mutex<bool>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Popup {
+    pub(crate) screen_data: ScreenData,
+    /// The screenshot of the output, `None` when it got plugged in after the capture.
+    pub(crate) capture: Option<Screenshot>,
+    /// Two buffers of `buffer_size`, so one can be drawn while the compositor reads the other.
+    pub(crate) buffers: Vec<OverlayBuffer>,
+    /// Index of the buffer attached last.
+    pub(crate) front: Option<usize>,
+    /// Size of the buffers, the logical size at the preferred scale of the surface.
+    pub(crate) buffer_size: (i32, i32),
+    /// Preferred scale of the surface in 120ths, `None` until the compositor sent one.
+    pub(crate) scale: Arc<Mutex<Option<u32>>>,
+    /// Part of the output left undimmed, in pixels of the overlay buffer.
+    pub(crate) selection: Option<Rect>,
+    /// Whether the overlay changed since the popup was drawn last.
+    pub(crate) dirty: bool,
+    /// Set from committing a frame until the compositor is ready for the next one.
+    pub(crate) frame_pending: Arc<Mutex<bool>>,
     pub(crate) surface: WlSurface,
     pub(crate) layer_surface: ZwlrLayerSurfaceV1,
+    /// Set once the compositor closed the layer surface, because its output went away.
+    pub(crate) closed: Arc<Mutex<bool>>,
 }
 
 impl Popup {
+    pub(crate) fn is_closed(&self) -> bool {
+        *self.closed.lock().unwrap()
+    }
+
+    /// Bytes of one overlay buffer.
+    pub(crate) fn frame_len(&self) -> usize {
+        (self.buffer_size.0 * self.buffer_size.1 * 4) as usize
+    }
+
+    /// A buffer other than the one on screen that can be drawn now, `None` until the compositor
+    /// is ready for a new frame and released it.
+    pub(crate) fn back_buffer(&self) -> Option<usize> {
+        if *self.frame_pending.lock().unwrap() {
+            return None;
+        }
+        (0..self.buffers.len())
+            .filter(|&index| Some(index) != self.front)
+            .find(|&index| !*self.buffers[index].busy.lock().unwrap())
+    }
+
     pub(crate) fn config_layer_surface(&self, event_queue: &mut EventQueue<Delegate>) {
-        let (l_width, l_height) = self.screen_data.screen_data.logical_resolution;
+        let (l_width, l_height) = self.screen_data.logical_resolution;
 
         self.layer_surface.set_size(l_width as u32, l_height as u32);
         self.layer_surface.set_anchor(Anchor::Bottom);
@@ -47,11 +121,138 @@ impl Popup {
         self.layer_surface.set_exclusive_zone(-1);
         self.surface.commit();
 
-        println!("prep for config");
+        log::trace!("Configuring layer surface {:?}", self.layer_surface);
         event_queue.dispatch_pending(&mut Delegate).unwrap();
     }
 }
 
+/// How far a finger or stylus may move while tapping, in logical pixels.
+const TAP_DISTANCE: f64 = 8.0;
+/// How long a tap may take at most.
+const TAP_DURATION: Duration = Duration::from_millis(300);
+
+/// A finger or stylus touching an overlay surface, which acts like the left button held down.
+#[derive(Debug)]
+pub(crate) struct Contact {
+    surface: WlSurface,
+    /// Where it touched down, in logical coordinates of `surface`.
+    start: (f64, f64),
+    since: Instant,
+    /// Whether it moved too far to be a tap.
+    moved: bool,
+}
+
+/// Pointer, touch, tablet and keyboard events of every seat, queued until the overlay handles
+/// them.
+#[derive(Debug, Default)]
+pub(crate) struct Input {
+    pub(crate) events: Vec<InputEvent>,
+    /// Surface the pointer is over, motion events don't repeat it.
+    pub(crate) pointer_focus: Option<WlSurface>,
+    /// Pointers over an overlay surface, with the serial of the enter event setting their cursor
+    /// needs.
+    pub(crate) entered: Vec<(WlPointer, u32)>,
+    /// The finger or stylus acting as the pointer, only one at a time does.
+    pub(crate) contact: Option<Contact>,
+    /// Id of the touch point that is the `contact`.
+    pub(crate) touch_id: Option<i32>,
+    /// Surface a tablet tool is in proximity of, with its position.
+    pub(crate) tool_focus: Option<(WlSurface, (f64, f64))>,
+    pub(crate) seats: Vec<SeatDevices>,
+}
+
+impl Input {
+    /// Presses the left button at `position` on `surface`.
+    pub(crate) fn contact_down(&mut self, surface: WlSurface, position: (f64, f64)) {
+        self.events.push(InputEvent::Motion {
+            surface: surface.clone(),
+            position,
+        });
+        self.events.push(InputEvent::Button {
+            button: BTN_LEFT,
+            pressed: true,
+        });
+        self.contact = Some(Contact {
+            surface,
+            start: position,
+            since: Instant::now(),
+            moved: false,
+        });
+    }
+
+    pub(crate) fn contact_motion(&mut self, position: (f64, f64)) {
+        let Some(contact) = &mut self.contact else {
+            return;
+        };
+        let distance = (position.0 - contact.start.0).hypot(position.1 - contact.start.1);
+        contact.moved |= distance > TAP_DISTANCE;
+        let surface = contact.surface.clone();
+        self.events.push(InputEvent::Motion { surface, position });
+    }
+
+    /// Releases the left button, following it up with a tap when the contact was short and
+    /// stayed in place. A `cancelled` contact is never a tap.
+    pub(crate) fn contact_up(&mut self, cancelled: bool) {
+        let Some(contact) = self.contact.take() else {
+            return;
+        };
+        self.events.push(InputEvent::Button {
+            button: BTN_LEFT,
+            pressed: false,
+        });
+        if !cancelled && !contact.moved && contact.since.elapsed() <= TAP_DURATION {
+            self.events.push(InputEvent::Tap {
+                surface: contact.surface,
+                position: contact.start,
+            });
+        }
+    }
+}
+
+/// The devices we got from a seat, following its capabilities.
+#[derive(Debug)]
+pub(crate) struct SeatDevices {
+    pub(crate) seat: WlSeat,
+    pub(crate) pointer: Option<WlPointer>,
+    pub(crate) keyboard: Option<WlKeyboard>,
+    pub(crate) touch: Option<WlTouch>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum InputEvent {
+    /// The pointer moved over `surface`, in logical coordinates of the surface.
+    Motion {
+        surface: WlSurface,
+        position: (f64, f64),
+    },
+    /// A pointer button as a linux event code, such as `BTN_LEFT`.
+    Button { button: u32, pressed: bool },
+    /// A finger or stylus tapped `surface` at `position`, right after releasing `BTN_LEFT`.
+    Tap {
+        surface: WlSurface,
+        position: (f64, f64),
+    },
+    /// Vertical scroll distance, positive scrolls down.
+    Scroll(f64),
+    /// A new keymap in the xkb text format.
+    Keymap(String),
+    /// A key as an evdev scancode.
+    Key { key: u32, pressed: bool },
+    Modifiers {
+        depressed: u32,
+        latched: u32,
+        locked: u32,
+        group: u32,
+    },
+}
+
+/// The data we put on the clipboard, handed out until another client takes the selection over.
+#[derive(Debug)]
+pub(crate) struct ClipboardSource {
+    pub(crate) data: Vec<u8>,
+    pub(crate) cancelled: bool,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct ScreenshotWayland {
     pub(crate) output: WlOutput,
@@ -61,19 +262,17 @@ pub(crate) struct ScreenshotWayland {
 impl ScreenshotWayland {
     pub(crate) fn new(
         qh: &QueueHandle<Delegate>,
-        screen_data: ScreenData,
+        format: FrameFormat,
         output: WlOutput,
         shm_pool: &WlShmPool,
         offset: usize,
     ) -> ScreenshotWayland {
-        let (width, height) = (screen_data.resolution.0, screen_data.resolution.1);
-
         let buffer = shm_pool.create_buffer(
             offset as i32,
-            width,
-            height,
-            width * 4,
-            Format::Xrgb8888,
+            format.width as i32,
+            format.height as i32,
+            format.stride as i32,
+            format.format,
             qh,
             (),
         );
@@ -84,18 +283,71 @@ impl ScreenshotWayland {
     pub(crate) fn attach_buffer(&mut self, buffer: WlBuffer) {
         self.buffer = buffer;
     }
+}
 
-    pub(crate) fn screencopy(
-        &self,
-        screencopying_counter: Arc<Mutex<u8>>,
-        qh: &QueueHandle<Delegate>,
-        screencopy_manager: &ZwlrScreencopyManagerV1,
-    ) {
-        *screencopying_counter.lock().unwrap() += 1;
+/// The shm layout a screencopy frame asked for in its `buffer` event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct FrameFormat {
+    pub(crate) format: Format,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) stride: u32,
+}
+
+/// Everything the compositor told us about a single screencopy frame.
+#[derive(Debug, Default)]
+pub(crate) struct FrameState {
+    pub(crate) format: Option<FrameFormat>,
+    /// Presentation time of the copied content, from the `ready` event.
+    pub(crate) ready: Option<Duration>,
+    pub(crate) failed: bool,
+}
+
+/// Shm memory a screencopy frame gets copied into, reused while the output keeps its layout.
+#[derive(Debug)]
+pub(crate) struct FrameBuffer {
+    pub(crate) file: File,
+    pub(crate) pool: WlShmPool,
+    pub(crate) buffer: WlBuffer,
+    pub(crate) format: FrameFormat,
+}
+
+impl FrameBuffer {
+    pub(crate) fn new(qh: &QueueHandle<Delegate>, shm: &WlShm, format: FrameFormat) -> FrameBuffer {
+        let size = (format.stride * format.height) as i32;
+
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(size as u64).unwrap();
+        let pool = shm.create_pool(file.as_fd(), size, qh, ());
+        let buffer = pool.create_buffer(
+            0,
+            format.width as i32,
+            format.height as i32,
+            format.stride as i32,
+            format.format,
+            qh,
+            (),
+        );
+
+        FrameBuffer {
+            file,
+            pool,
+            buffer,
+            format,
+        }
+    }
+
+    /// Reads the whole buffer, rows are `format.stride` bytes apart.
+    pub(crate) fn read(&self, pixels: &mut Vec<u8>) {
+        pixels.resize((self.format.stride * self.format.height) as usize, 0);
+        self.file.read_exact_at(pixels, 0).unwrap();
+    }
+}
 
-        let a =
-            screencopy_manager.capture_output(0, &self.output, qh, screencopying_counter.clone());
-        a.copy(&self.buffer);
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        self.pool.destroy();
     }
 }
 
@@ -1,8 +1,18 @@
+//! The xdg toplevel the capture preview lives in, along with the seat handling it needs.
+
 use smithay_client_toolkit::compositor::CompositorHandler;
 use smithay_client_toolkit::globals::GlobalData;
 use smithay_client_toolkit::output::OutputHandler;
 use smithay_client_toolkit::registry::ProvidesRegistryState;
-use smithay_client_toolkit::shell::xdg::window::{WindowConfigure, WindowHandler};
+use smithay_client_toolkit::registry_handlers;
+use smithay_client_toolkit::seat::keyboard::{KeyEvent, KeyboardHandler, Keysym, Modifiers};
+use smithay_client_toolkit::seat::pointer::{
+    CursorIcon, PointerEvent, PointerEventKind, PointerHandler, ThemeSpec, ThemedPointer,
+};
+use smithay_client_toolkit::seat::{Capability, SeatHandler, SeatState};
+use smithay_client_toolkit::shell::xdg::window::{
+    WindowConfigure, WindowDecorations, WindowHandler,
+};
 use smithay_client_toolkit::shell::xdg::XdgShell;
 use smithay_client_toolkit::shell::WaylandSurface;
 use smithay_client_toolkit::shm::ShmHandler;
@@ -16,95 +26,324 @@ use smithay_client_toolkit::{
         Shm,
     },
 };
+use smithay_client_toolkit::{
+    delegate_compositor, delegate_keyboard, delegate_output, delegate_pointer, delegate_registry,
+    delegate_seat, delegate_shm, delegate_xdg_shell, delegate_xdg_window,
+};
+use wayland_client::globals::registry_queue_init;
+use wayland_client::protocol::wl_keyboard::WlKeyboard;
 use wayland_client::protocol::wl_output::{Transform, WlOutput};
+use wayland_client::protocol::wl_pointer::WlPointer;
+use wayland_client::protocol::wl_seat::WlSeat;
 use wayland_client::protocol::wl_shm::Format;
 use wayland_client::protocol::wl_surface::WlSurface;
 use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
 use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1;
-use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_v1::WpFractionalScaleV1;
-use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1;
-use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_v1::{
+    self, WpFractionalScaleV1,
+};
+use wayland_protocols::wp::viewporter::client::{
+    wp_viewport::WpViewport, wp_viewporter::WpViewporter,
+};
 
-use crate::wayland_screencopy::ScreenCopyManager;
+use crate::canvas::Canvas;
+use crate::config::Theme;
+use crate::overlay::Event;
+use crate::preview::{Outcome, Preview};
+use crate::wayland::keyboard::KeyPress;
+use crate::wayland_fractional_scale::FractionalScale;
 
 pub(crate) struct WindowState {
     pub(crate) registry_state: RegistryState,
     pub(crate) output_state: OutputState,
     pub(crate) compositor_state: CompositorState,
+    pub(crate) seat_state: SeatState,
     pub(crate) shm: Shm,
-    pub(crate) zwlr_screencopy_manager: ScreenCopyManager,
-    pub(crate) xdg_shell: XdgShell,
 
     pub(crate) pool: SlotPool,
-    pub(crate) windows: Vec<ScreenShotViewer>,
+    pub(crate) viewer: ScreenShotViewer,
+    pub(crate) theme: Theme,
+    /// Devices of every seat, dropped again when the seat loses them.
+    pub(crate) pointers: Vec<(WlSeat, ThemedPointer)>,
+    pub(crate) keyboards: Vec<(WlSeat, WlKeyboard)>,
+    pub(crate) modifiers: Modifiers,
 }
 
-#[derive(Debug)]
 pub(crate) struct ScreenShotViewer {
     pub(crate) window: Window,
-    pub(crate) screenshot_buffer: Buffer,
-    pub(crate) screen: usize,
+    /// Scales the buffer to the logical size of the window.
+    pub(crate) viewport: WpViewport,
+    pub(crate) fractional_scale: WpFractionalScaleV1,
+    /// Preferred scale of the window in 120ths.
+    pub(crate) scale: Option<u32>,
+    /// The buffer last attached, kept alive until the next one replaces it.
+    pub(crate) buffer: Option<Buffer>,
+    /// Logical size of the window.
     pub(crate) width: u32,
     pub(crate) height: u32,
-    pub(crate) format: Format,
+    pub(crate) preview: Preview,
     pub(crate) first_configure: bool,
     pub(crate) damaged: bool,
 }
 
+delegate_compositor!(WindowState);
+delegate_output!(WindowState);
+delegate_shm!(WindowState);
+delegate_seat!(WindowState);
+delegate_pointer!(WindowState);
+delegate_keyboard!(WindowState);
+delegate_xdg_shell!(WindowState);
+delegate_xdg_window!(WindowState);
+delegate_registry!(WindowState);
+
 impl WindowState {
     pub fn draw(&mut self, _conn: &Connection, qh: &QueueHandle<Self>) {
-        for viewer in &mut self.windows {
-            if viewer.first_configure || !viewer.damaged {
-                continue;
-            }
+        let viewer = &mut self.viewer;
+        if viewer.first_configure || !viewer.damaged {
+            return;
+        }
 
-            let window = &viewer.window;
+        // Render at the preferred scale, the viewport maps the buffer back to the logical size
+        let scale = viewer.scale.unwrap_or(120) as f64 / 120.0;
+        let buffer_size = (
+            ((viewer.width as f64 * scale).round() as u32).max(1),
+            ((viewer.height as f64 * scale).round() as u32).max(1),
+        );
+        let (buffer, pixels) = self
+            .pool
+            .create_buffer(
+                buffer_size.0 as i32,
+                buffer_size.1 as i32,
+                buffer_size.0 as i32 * 4,
+                Format::Abgr8888,
+            )
+            .expect("Failed to create the preview buffer");
+        viewer.preview.draw(
+            &mut Canvas::from_bytes(pixels, buffer_size.0, buffer_size.1),
+            (viewer.width, viewer.height),
+            &self.theme,
+        );
 
-            // Damage the entire window
-            window
-                .wl_surface()
-                .damage_buffer(0, 0, viewer.width as i32, viewer.height as i32);
-            viewer.damaged = false;
+        let window = &viewer.window;
+        window
+            .wl_surface()
+            .damage_buffer(0, 0, buffer_size.0 as i32, buffer_size.1 as i32);
+        viewer.damaged = false;
 
-            // Request our next frame
-            window.wl_surface().frame(qh, window.wl_surface().clone());
+        // Request our next frame
+        window.wl_surface().frame(qh, window.wl_surface().clone());
 
-            // Attach and commit to present.
-            window.wl_surface().set_buffer_scale(2);
-            viewer
-                .screenshot_buffer
-                .attach_to(window.wl_surface())
-                .unwrap();
-            window.wl_surface().commit();
+        viewer
+            .viewport
+            .set_destination(viewer.width as i32, viewer.height as i32);
+        buffer.attach_to(window.wl_surface()).unwrap();
+        viewer.buffer = Some(buffer);
+        window.wl_surface().commit();
+    }
+
+    /// Passes `event` to the preview and redraws when it asks for it.
+    fn handle(&mut self, conn: &Connection, qh: &QueueHandle<Self>, event: Event) {
+        let viewer = &mut self.viewer;
+        if viewer.preview.handle(event, (viewer.width, viewer.height)) {
+            viewer.damaged = true;
+            self.draw(conn, qh);
         }
     }
 }
 
 impl WindowHandler for WindowState {
-    fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, window: &Window) {
-        self.windows.retain(|v| v.window != *window);
+    fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _window: &Window) {
+        self.viewer.preview.outcome = Some(Outcome::Discard);
     }
 
     fn configure(
         &mut self,
         conn: &Connection,
         qh: &QueueHandle<Self>,
-        window: &Window,
+        _window: &Window,
         configure: WindowConfigure,
         _serial: u32,
     ) {
-        //println!("{:#?}", configure);
-        for view in &mut self.windows {
-            if view.window != *window {
-                continue;
+        let view = &mut self.viewer;
+        let (width, height) = view.preview.preferred_size();
+        view.width = configure.new_size.0.map_or(width, |v| v.get());
+        view.height = configure.new_size.1.map_or(height, |v| v.get());
+        view.first_configure = false;
+        view.damaged = true;
+        self.draw(conn, qh);
+    }
+}
+
+impl SeatHandler for WindowState {
+    fn seat_state(&mut self) -> &mut SeatState {
+        &mut self.seat_state
+    }
+
+    fn new_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
+
+    fn new_capability(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        seat: WlSeat,
+        capability: Capability,
+    ) {
+        match capability {
+            Capability::Pointer => {
+                let surface = self.compositor_state.create_surface(qh);
+                match self.seat_state.get_pointer_with_theme(
+                    qh,
+                    &seat,
+                    self.shm.wl_shm(),
+                    surface,
+                    ThemeSpec::default(),
+                ) {
+                    Ok(pointer) => self.pointers.push((seat, pointer)),
+                    Err(err) => log::warn!("Failed to get the pointer: {err}"),
+                }
             }
+            Capability::Keyboard => match self.seat_state.get_keyboard(qh, &seat, None) {
+                Ok(keyboard) => self.keyboards.push((seat, keyboard)),
+                Err(err) => log::warn!("Failed to get the keyboard: {err}"),
+            },
+            _ => {}
+        }
+    }
 
-            view.width = configure.new_size.0.map(|v| v.get()).unwrap_or(256);
-            view.height = configure.new_size.1.map(|v| v.get()).unwrap_or(256);
-            view.first_configure = false;
-            view.damaged = true;
+    fn remove_capability(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        seat: WlSeat,
+        capability: Capability,
+    ) {
+        match capability {
+            Capability::Pointer => self.pointers.retain(|(of, pointer)| {
+                let keep = *of != seat;
+                if !keep {
+                    pointer.pointer().release();
+                }
+                keep
+            }),
+            Capability::Keyboard => self.keyboards.retain(|(of, keyboard)| {
+                let keep = *of != seat;
+                if !keep {
+                    keyboard.release();
+                }
+                keep
+            }),
+            _ => {}
         }
-        self.draw(&conn, &qh);
+    }
+
+    fn remove_seat(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _seat: WlSeat) {}
+}
+
+impl PointerHandler for WindowState {
+    fn pointer_frame(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        pointer: &WlPointer,
+        events: &[PointerEvent],
+    ) {
+        for event in events {
+            if &event.surface != self.viewer.window.wl_surface() {
+                continue;
+            }
+            let event = match event.kind {
+                PointerEventKind::Enter { .. } => {
+                    let themed = self
+                        .pointers
+                        .iter()
+                        .find(|(_, themed)| themed.pointer() == pointer);
+                    if let Some((_, themed)) = themed {
+                        if let Err(err) = themed.set_cursor(conn, CursorIcon::Default) {
+                            log::debug!("Failed to set the cursor: {err}");
+                        }
+                    }
+                    Event::Motion(event.position)
+                }
+                PointerEventKind::Motion { .. } => Event::Motion(event.position),
+                PointerEventKind::Press { button, .. } => Event::Button {
+                    button,
+                    pressed: true,
+                },
+                PointerEventKind::Release { button, .. } => Event::Button {
+                    button,
+                    pressed: false,
+                },
+                PointerEventKind::Axis { vertical, .. } if vertical.absolute != 0.0 => {
+                    Event::Scroll(vertical.absolute)
+                }
+                _ => continue,
+            };
+            self.handle(conn, qh, event);
+        }
+    }
+}
+
+impl KeyboardHandler for WindowState {
+    fn enter(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _surface: &WlSurface,
+        _serial: u32,
+        _raw: &[u32],
+        _keysyms: &[Keysym],
+    ) {
+    }
+
+    fn leave(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _surface: &WlSurface,
+        _serial: u32,
+    ) {
+    }
+
+    fn press_key(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        event: KeyEvent,
+    ) {
+        let key = KeyPress {
+            keysym: event.keysym,
+            text: event.utf8.unwrap_or_default(),
+            shift: self.modifiers.shift,
+            ctrl: self.modifiers.ctrl,
+            alt: self.modifiers.alt,
+        };
+        self.handle(conn, qh, Event::Key(key));
+    }
+
+    fn release_key(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        event: KeyEvent,
+    ) {
+        self.handle(conn, qh, Event::KeyRelease(event.keysym));
+    }
+
+    fn update_modifiers(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _keyboard: &WlKeyboard,
+        _serial: u32,
+        modifiers: Modifiers,
+    ) {
+        self.modifiers = modifiers;
     }
 }
 
@@ -159,67 +398,55 @@ impl CompositorHandler for WindowState {
 
 impl ProvidesRegistryState for WindowState {
     fn registry(&mut self) -> &mut RegistryState {
-        todo!()
+        &mut self.registry_state
     }
 
-    fn runtime_add_global(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _name: u32,
-        _interface: &str,
-        _version: u32,
-    ) {
-        todo!()
-    }
-
-    fn runtime_remove_global(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _name: u32,
-        _interface: &str,
-    ) {
-        todo!()
-    }
+    registry_handlers!(OutputState, SeatState);
 }
 
-impl Dispatch<ZwlrScreencopyManagerV1, GlobalData> for WindowState {
+impl Dispatch<WpFractionalScaleV1, GlobalData> for WindowState {
     fn event(
-        _state: &mut Self,
-        _proxy: &ZwlrScreencopyManagerV1,
-        _event: <ZwlrScreencopyManagerV1 as Proxy>::Event,
+        state: &mut Self,
+        proxy: &WpFractionalScaleV1,
+        event: <WpFractionalScaleV1 as Proxy>::Event,
         _data: &GlobalData,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
     ) {
-        println!("ScreenCopy");
+        log::trace!("{:?}", event);
+        let wp_fractional_scale_v1::Event::PreferredScale { scale } = event else {
+            return;
+        };
+        let viewer = &mut state.viewer;
+        if viewer.fractional_scale == *proxy && viewer.scale != Some(scale) {
+            viewer.scale = Some(scale);
+            viewer.damaged = true;
+        }
+        state.draw(conn, qh);
     }
 }
 
-impl Dispatch<ZwlrScreencopyFrameV1, GlobalData> for WindowState {
+impl Dispatch<WpViewporter, GlobalData> for WindowState {
     fn event(
         _state: &mut Self,
-        _proxy: &ZwlrScreencopyFrameV1,
-        event: <ZwlrScreencopyFrameV1 as Proxy>::Event,
+        _proxy: &WpViewporter,
+        _event: <WpViewporter as Proxy>::Event,
         _data: &GlobalData,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        println!("{:?}", event);
     }
 }
 
-impl Dispatch<WpFractionalScaleV1, GlobalData> for WindowState {
+impl Dispatch<WpViewport, GlobalData> for WindowState {
     fn event(
         _state: &mut Self,
-        _proxy: &WpFractionalScaleV1,
-        event: <WpFractionalScaleV1 as Proxy>::Event,
+        _proxy: &WpViewport,
+        _event: <WpViewport as Proxy>::Event,
         _data: &GlobalData,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        println!("{:?}", event);
     }
 }
 
@@ -232,7 +459,67 @@ impl Dispatch<WpFractionalScaleManagerV1, GlobalData> for WindowState {
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
-        println!("Stufffaaa");
     }
 }
 
+/// Opens the preview window and runs it until the user picked an [`Outcome`], returning it with
+/// the possibly edited image.
+pub(crate) fn show_preview(preview: Preview, theme: Theme) -> (Outcome, image::DynamicImage) {
+    let conn = Connection::connect_to_env().unwrap();
+    let (globals, mut event_queue) = registry_queue_init(&conn).unwrap();
+    let qh: QueueHandle<WindowState> = event_queue.handle();
+
+    let shm = Shm::bind(&globals, &qh).expect("wl shm is not available.");
+    let pool = SlotPool::new(256 * 256 * 4, &shm).expect("Failed to create pool");
+    let xdg_shell = XdgShell::bind(&globals, &qh).expect("xdg shell is not available");
+    let compositor_state =
+        CompositorState::bind(&globals, &qh).expect("wl_compositor not available");
+    let fractional_scale =
+        FractionalScale::bind(&globals, &qh).expect("wp_fractional_scale_manager_v1 not available");
+    let viewporter: WpViewporter = globals
+        .bind(&qh, 1..=1, GlobalData)
+        .expect("wp_viewporter not available");
+
+    let surface = compositor_state.create_surface(&qh);
+    let viewport = viewporter.get_viewport(&surface, &qh, GlobalData);
+    let scale = fractional_scale.get_fractional_scale(&surface, &qh, GlobalData);
+    let window = xdg_shell.create_window(surface, WindowDecorations::RequestServer, &qh);
+    window.set_title("Capture preview");
+    window.set_app_id("screen_capturer");
+    window.set_min_size(Some((320, 160)));
+    // Nothing gets drawn before the first configure
+    window.commit();
+
+    let mut window_state = WindowState {
+        registry_state: RegistryState::new(&globals),
+        output_state: OutputState::new(&globals, &qh),
+        seat_state: SeatState::new(&globals, &qh),
+        compositor_state,
+        shm,
+        pool,
+        viewer: ScreenShotViewer {
+            window,
+            viewport,
+            fractional_scale: scale,
+            scale: None,
+            buffer: None,
+            width: 0,
+            height: 0,
+            preview,
+            first_configure: true,
+            damaged: false,
+        },
+        theme,
+        pointers: vec![],
+        keyboards: vec![],
+        modifiers: Modifiers::default(),
+    };
+
+    let outcome = loop {
+        event_queue.blocking_dispatch(&mut window_state).unwrap();
+        if let Some(outcome) = window_state.viewer.preview.outcome {
+            break outcome;
+        }
+    };
+    (outcome, window_state.viewer.preview.into_image())
+}
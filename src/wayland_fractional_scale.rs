@@ -1,8 +1,10 @@
 use smithay_client_toolkit::globals::GlobalData;
 use wayland_client::globals::{BindError, GlobalList};
 use wayland_client::{Dispatch, QueueHandle};
+use wayland_client::protocol::wl_surface::WlSurface;
 use wayland_protocols::wp::fractional_scale::v1::client::{
-    wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1
+    wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+    wp_fractional_scale_v1::WpFractionalScaleV1,
 };
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
 
@@ -25,4 +27,19 @@ impl FractionalScale {
     pub(crate) fn wp_fractional_scale_manager_v1(&self) -> &WpFractionalScaleManagerV1 {
         &self.wp_fractional_scale_manager_v1
     }
+
+    /// Starts sending the preferred scale of `surface` to the `WpFractionalScaleV1` handler.
+    pub(crate) fn get_fractional_scale<State, U>(
+        &self,
+        surface: &WlSurface,
+        qh: &QueueHandle<State>,
+        udata: U,
+    ) -> WpFractionalScaleV1
+        where
+            State: Dispatch<WpFractionalScaleV1, U> + 'static,
+            U: Send + Sync + 'static,
+    {
+        self.wp_fractional_scale_manager_v1
+            .get_fractional_scale(surface, qh, udata)
+    }
 }